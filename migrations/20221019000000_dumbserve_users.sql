@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS dumbserve_users (
+	name VARCHAR(100) NOT NULL PRIMARY KEY,
+	email VARCHAR(100) UNIQUE DEFAULT NULL,
+	password TEXT NOT NULL,
+	disabled BOOLEAN NOT NULL DEFAULT FALSE,
+	created BIGINT NOT NULL
+);
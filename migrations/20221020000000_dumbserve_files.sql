@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS dumbserve_files (
+	owner VARCHAR(100) NOT NULL,
+	path TEXT NOT NULL,
+	size BIGINT NOT NULL,
+	digest VARCHAR(64) NOT NULL,
+	content_type TEXT NOT NULL,
+	modified BIGINT NOT NULL,
+	uploaded BIGINT NOT NULL,
+	uploader VARCHAR(100) NOT NULL,
+	PRIMARY KEY (owner, path)
+);
+
+CREATE INDEX IF NOT EXISTS dumbserve_files_uploaded ON dumbserve_files (uploaded);
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS dumbserve_releases (
+	owner VARCHAR(100) NOT NULL,
+	project VARCHAR(100) NOT NULL,
+	version VARCHAR(100) NOT NULL,
+	channel VARCHAR(20) NOT NULL,
+	published BIGINT NOT NULL,
+	publisher VARCHAR(100) NOT NULL,
+	PRIMARY KEY (owner, project, version)
+);
@@ -95,16 +95,26 @@ delete_dir() {
 		}"
 }
 
+# semver versions are published as releases, other refs(like master) are
+# uploaded to $1/ and replace its previous contents
 upload_dist() {
-	delete_dir $1
-
 	pushd $TMP_DIR
-	for file in $TARBALL $TARBALL.asc $TARBALL.sha256
-	do
-		curl -v \
-			-F upload=@$file  \
-			"$DUMBSERVE_HOST/api/v1/files/upload?path=$1/"
-	done
+	if [[ $1 =~ ^v?[0-9]+\.[0-9]+\.[0-9]+([-+].*)?$ ]]
+	then
+		curl -v --fail \
+			-F upload=@$TARBALL \
+			-F upload=@$TARBALL.asc \
+			-F upload=@$TARBALL.sha256 \
+			"$DUMBSERVE_HOST/api/v1/releases/publish?project=$NAME&version=$1"
+	else
+		delete_dir $1
+		for file in $TARBALL $TARBALL.asc $TARBALL.sha256
+		do
+			curl -v \
+				-F upload=@$file  \
+				"$DUMBSERVE_HOST/api/v1/files/upload?path=$1/"
+		done
+	fi
 	popd
 }
 
@@ -0,0 +1,274 @@
+/*
+ * Copyright (C) 2022  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Self-service account management
+use actix_web::HttpMessage;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use actix_web_httpauth::middleware::HttpAuthentication;
+use argon2_creds::Config;
+use serde::{Deserialize, Serialize};
+
+use super::admin::{create_user, NewUser};
+use super::httpauth;
+use super::SignedInUser;
+use super::API_V1_ROUTES;
+use crate::errors::*;
+use crate::AppCtx;
+
+pub mod routes {
+    use super::*;
+    #[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+    pub struct Account {
+        pub register: &'static str,
+        pub update_password: &'static str,
+        pub delete: &'static str,
+    }
+    impl Account {
+        pub const fn new() -> Self {
+            Self {
+                register: "/api/v1/account/register",
+                update_password: "/api/v1/account/password",
+                delete: "/api/v1/account/delete",
+            }
+        }
+    }
+}
+
+pub fn services(cfg: &mut web::ServiceConfig) {
+    cfg.service(register);
+    cfg.service(update_password);
+    cfg.service(delete_account);
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Register {
+    pub username: String,
+    pub password: String,
+    pub confirm_password: String,
+    pub email: Option<String>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct ChangePassword {
+    pub password: String,
+    pub new_password: String,
+    pub confirm_new_password: String,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Password {
+    pub password: String,
+}
+
+/// verify password of user account stored in the database
+async fn check_password(ctx: &AppCtx, username: &str, password: &str) -> ServiceResult<()> {
+    let user = ctx.db.get_user(username).await?;
+    if Config::verify(&user.password, password)? {
+        Ok(())
+    } else {
+        Err(ServiceError::WrongPassword)
+    }
+}
+
+#[actix_web_codegen_const_routes::post(path = "API_V1_ROUTES.account.register")]
+async fn register(ctx: AppCtx, payload: web::Json<Register>) -> ServiceResult<impl Responder> {
+    if !ctx.settings.allow_registration {
+        return Err(ServiceError::ClosedForRegistration);
+    }
+
+    if payload.password != payload.confirm_password {
+        return Err(ServiceError::PasswordsDontMatch);
+    }
+
+    let payload = payload.into_inner();
+    let new_user = NewUser {
+        username: payload.username,
+        password: payload.password,
+        email: payload.email,
+    };
+    create_user(&ctx, &new_user).await?;
+    Ok(HttpResponse::Ok())
+}
+
+#[actix_web_codegen_const_routes::post(
+    path = "API_V1_ROUTES.account.update_password",
+    wrap = "HttpAuthentication::with_fn(httpauth)"
+)]
+async fn update_password(
+    req: HttpRequest,
+    ctx: AppCtx,
+    payload: web::Json<ChangePassword>,
+) -> ServiceResult<impl Responder> {
+    let user = req.extensions().get::<SignedInUser>().unwrap().clone();
+
+    if payload.new_password != payload.confirm_new_password {
+        return Err(ServiceError::PasswordsDontMatch);
+    }
+
+    check_password(&ctx, &user.0, &payload.password).await?;
+    let hash = ctx.creds.password(&payload.new_password)?;
+    ctx.db.update_password(&user.0, &hash).await?;
+    Ok(HttpResponse::Ok())
+}
+
+#[actix_web_codegen_const_routes::delete(
+    path = "API_V1_ROUTES.account.delete",
+    wrap = "HttpAuthentication::with_fn(httpauth)"
+)]
+async fn delete_account(
+    req: HttpRequest,
+    ctx: AppCtx,
+    payload: web::Json<Password>,
+) -> ServiceResult<impl Responder> {
+    let user = req.extensions().get::<SignedInUser>().unwrap().clone();
+    check_password(&ctx, &user.0, &payload.password).await?;
+    ctx.delete_user(&user.0).await?;
+    Ok(HttpResponse::Ok())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        test, App,
+    };
+
+    use super::*;
+    use crate::tests::basic_auth;
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn account_works() {
+        const USERNAME: &str = "account_works";
+        const PASSWORD: &str = "23k4j;123k4j1;l23kj4";
+        const NEW_PASSWORD: &str = "l23kj4;23k4j;123k4j1";
+
+        let mut settings = Settings::new().unwrap();
+        settings.allow_registration = true;
+        let ctx = AppCtx::new(crate::ctx::Ctx::new(&settings).await);
+        let _ = ctx.delete_user(USERNAME).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(ctx.clone())
+                .configure(crate::routes::services),
+        )
+        .await;
+
+        let mut payload = Register {
+            username: USERNAME.into(),
+            password: PASSWORD.into(),
+            confirm_password: NEW_PASSWORD.into(),
+            email: None,
+        };
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .set_json(&payload)
+                .uri(API_V1_ROUTES.account.register)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        payload.confirm_password = PASSWORD.into();
+        // usernames name directories in files.path
+        for username in [".precompressed", "x/y"] {
+            let resp = test::call_service(
+                &app,
+                test::TestRequest::post()
+                    .set_json(&Register {
+                        username: username.into(),
+                        ..payload.clone()
+                    })
+                    .uri(API_V1_ROUTES.account.register)
+                    .to_request(),
+            )
+            .await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{username}");
+            assert!(!ctx.db.username_exists(username).await.unwrap());
+        }
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .set_json(&payload)
+                .uri(API_V1_ROUTES.account.register)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(ctx.authenticate(USERNAME, PASSWORD).await);
+        let identity = ctx.session_identity(USERNAME).await.unwrap();
+        assert_eq!(ctx.session_user(&identity).await.unwrap(), USERNAME);
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .set_json(&payload)
+                .uri(API_V1_ROUTES.account.register)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let mut change = ChangePassword {
+            password: NEW_PASSWORD.into(),
+            new_password: NEW_PASSWORD.into(),
+            confirm_new_password: NEW_PASSWORD.into(),
+        };
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .append_header((header::AUTHORIZATION, basic_auth(USERNAME, PASSWORD)))
+                .set_json(&change)
+                .uri(API_V1_ROUTES.account.update_password)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        change.password = PASSWORD.into();
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .append_header((header::AUTHORIZATION, basic_auth(USERNAME, PASSWORD)))
+                .set_json(&change)
+                .uri(API_V1_ROUTES.account.update_password)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(ctx.authenticate(USERNAME, NEW_PASSWORD).await);
+        // password change ends existing sessions
+        assert!(ctx.session_user(&identity).await.is_none());
+        let identity = ctx.session_identity(USERNAME).await.unwrap();
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::delete()
+                .append_header((header::AUTHORIZATION, basic_auth(USERNAME, NEW_PASSWORD)))
+                .set_json(&Password {
+                    password: NEW_PASSWORD.into(),
+                })
+                .uri(API_V1_ROUTES.account.delete)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!ctx.authenticate(USERNAME, NEW_PASSWORD).await);
+        assert!(ctx.session_user(&identity).await.is_none());
+    }
+}
@@ -0,0 +1,369 @@
+/*
+ * Copyright (C) 2022  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! User management. Only administrators (users configured in settings) can access these
+//! endpoints.
+use actix_web::HttpMessage;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use actix_web_httpauth::middleware::HttpAuthentication;
+use serde::{Deserialize, Serialize};
+
+use super::httpauth;
+use super::SignedInUser;
+use super::API_V1_ROUTES;
+use crate::db::AddUser;
+use crate::errors::*;
+use crate::AppCtx;
+
+pub mod routes {
+    use super::*;
+    #[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+    pub struct Admin {
+        pub add_user: &'static str,
+        pub list_users: &'static str,
+        pub disable_user: &'static str,
+        pub delete_user: &'static str,
+        pub reset_password: &'static str,
+    }
+    impl Admin {
+        pub const fn new() -> Self {
+            Self {
+                add_user: "/api/v1/admin/users/add",
+                list_users: "/api/v1/admin/users",
+                disable_user: "/api/v1/admin/users/disable",
+                delete_user: "/api/v1/admin/users/delete",
+                reset_password: "/api/v1/admin/users/password",
+            }
+        }
+    }
+}
+
+pub fn services(cfg: &mut web::ServiceConfig) {
+    cfg.service(add_user);
+    cfg.service(list_users);
+    cfg.service(disable_user);
+    cfg.service(delete_user);
+    cfg.service(reset_password);
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct NewUser {
+    pub username: String,
+    pub password: String,
+    pub email: Option<String>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Username {
+    pub username: String,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct DisableUser {
+    pub username: String,
+    pub disabled: bool,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct ResetPassword {
+    pub username: String,
+    pub password: String,
+}
+
+fn check_admin(req: &HttpRequest, ctx: &AppCtx) -> ServiceResult<()> {
+    let ext = req.extensions();
+    let user = ext.get::<SignedInUser>().unwrap();
+    if ctx.is_admin(&user.0) {
+        Ok(())
+    } else {
+        Err(ServiceError::Forbidden)
+    }
+}
+
+/// top-level paths used by the API and the web interface. User trees are served from
+/// `/<username>`, so these can't be usernames.
+pub const RESERVED_USERNAMES: [&str; 2] = ["api", "web"];
+
+/// whether `username` can name a user's tree in `files.path`: no separators, `..` or dot
+/// directories like `.staging` and `.precompressed`
+fn is_path_safe(username: &str) -> bool {
+    !username.is_empty()
+        && !username.starts_with('.')
+        && username
+            .bytes()
+            .all(|b| matches!(b, b'a'..=b'z' | b'0'..=b'9' | b'_' | b'-'))
+}
+
+/// validate and create a new user account. Shared with self-service registration.
+pub async fn create_user(ctx: &AppCtx, payload: &NewUser) -> ServiceResult<String> {
+    let username = ctx.creds.username(&payload.username)?;
+    if !is_path_safe(&username) {
+        return Err(ServiceError::InvalidUsername);
+    }
+    if RESERVED_USERNAMES.contains(&username.as_str())
+        || ctx.is_admin(&username)
+        || ctx.db.username_exists(&username).await?
+    {
+        return Err(ServiceError::UsernameTaken);
+    }
+
+    if let Some(email) = &payload.email {
+        ctx.creds.email(email)?;
+        if ctx.db.email_exists(email).await? {
+            return Err(ServiceError::EmailTaken);
+        }
+    }
+
+    let hash = ctx.creds.password(&payload.password)?;
+    let user = AddUser {
+        name: &username,
+        email: payload.email.as_deref(),
+        password: &hash,
+    };
+    ctx.db.add_user(&user).await?;
+    Ok(username)
+}
+
+#[actix_web_codegen_const_routes::post(
+    path = "API_V1_ROUTES.admin.add_user",
+    wrap = "HttpAuthentication::with_fn(httpauth)"
+)]
+async fn add_user(
+    req: HttpRequest,
+    ctx: AppCtx,
+    payload: web::Json<NewUser>,
+) -> ServiceResult<impl Responder> {
+    check_admin(&req, &ctx)?;
+    create_user(&ctx, &payload).await?;
+    Ok(HttpResponse::Ok())
+}
+
+#[actix_web_codegen_const_routes::get(
+    path = "API_V1_ROUTES.admin.list_users",
+    wrap = "HttpAuthentication::with_fn(httpauth)"
+)]
+async fn list_users(req: HttpRequest, ctx: AppCtx) -> ServiceResult<impl Responder> {
+    check_admin(&req, &ctx)?;
+    let users = ctx.db.list_users().await?;
+    Ok(HttpResponse::Ok().json(users))
+}
+
+#[actix_web_codegen_const_routes::post(
+    path = "API_V1_ROUTES.admin.disable_user",
+    wrap = "HttpAuthentication::with_fn(httpauth)"
+)]
+async fn disable_user(
+    req: HttpRequest,
+    ctx: AppCtx,
+    payload: web::Json<DisableUser>,
+) -> ServiceResult<impl Responder> {
+    check_admin(&req, &ctx)?;
+    ctx.db
+        .set_user_disabled(&payload.username, payload.disabled)
+        .await?;
+    Ok(HttpResponse::Ok())
+}
+
+#[actix_web_codegen_const_routes::delete(
+    path = "API_V1_ROUTES.admin.delete_user",
+    wrap = "HttpAuthentication::with_fn(httpauth)"
+)]
+async fn delete_user(
+    req: HttpRequest,
+    ctx: AppCtx,
+    payload: web::Json<Username>,
+) -> ServiceResult<impl Responder> {
+    check_admin(&req, &ctx)?;
+    ctx.delete_user(&payload.username).await?;
+    Ok(HttpResponse::Ok())
+}
+
+#[actix_web_codegen_const_routes::post(
+    path = "API_V1_ROUTES.admin.reset_password",
+    wrap = "HttpAuthentication::with_fn(httpauth)"
+)]
+async fn reset_password(
+    req: HttpRequest,
+    ctx: AppCtx,
+    payload: web::Json<ResetPassword>,
+) -> ServiceResult<impl Responder> {
+    check_admin(&req, &ctx)?;
+    let hash = ctx.creds.password(&payload.password)?;
+    ctx.db.update_password(&payload.username, &hash).await?;
+    Ok(HttpResponse::Ok())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        test, App,
+    };
+
+    use super::*;
+    use crate::db::User;
+    use crate::tests::basic_auth;
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn user_management_works() {
+        const USERNAME: &str = "user_management_works";
+        const PASSWORD: &str = "23k4j;123k4j1;l23kj4";
+        const NEW_PASSWORD: &str = "l23kj4;23k4j;123k4j1";
+
+        let settings = Settings::new().unwrap();
+        let creds = settings.files.creds.first().unwrap().clone();
+        let admin = basic_auth(&creds.username, &creds.password);
+
+        let ctx = AppCtx::new(crate::ctx::Ctx::new(&settings).await);
+        let _ = ctx.db.delete_user(USERNAME).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(ctx.clone())
+                .configure(crate::routes::services),
+        )
+        .await;
+
+        let new_user = NewUser {
+            username: USERNAME.into(),
+            password: PASSWORD.into(),
+            email: None,
+        };
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .append_header((header::AUTHORIZATION, admin.clone()))
+                .set_json(&new_user)
+                .uri(API_V1_ROUTES.admin.add_user)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // duplicate username
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .append_header((header::AUTHORIZATION, admin.clone()))
+                .set_json(&new_user)
+                .uri(API_V1_ROUTES.admin.add_user)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // usernames name directories in files.path
+        for username in ["..", "a/b", ".staging", ".precompressed"] {
+            let resp = test::call_service(
+                &app,
+                test::TestRequest::post()
+                    .append_header((header::AUTHORIZATION, admin.clone()))
+                    .set_json(&NewUser {
+                        username: username.into(),
+                        ..new_user.clone()
+                    })
+                    .uri(API_V1_ROUTES.admin.add_user)
+                    .to_request(),
+            )
+            .await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{username}");
+            assert!(!ctx.db.username_exists(username).await.unwrap());
+        }
+
+        // non-admins can't manage users
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .append_header((header::AUTHORIZATION, basic_auth(USERNAME, PASSWORD)))
+                .uri(API_V1_ROUTES.admin.list_users)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .append_header((header::AUTHORIZATION, admin.clone()))
+                .uri(API_V1_ROUTES.admin.list_users)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let users: Vec<User> = test::read_body_json(resp).await;
+        assert!(users.iter().any(|u| u.name == USERNAME && !u.disabled));
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .append_header((header::AUTHORIZATION, admin.clone()))
+                .set_json(&DisableUser {
+                    username: USERNAME.into(),
+                    disabled: true,
+                })
+                .uri(API_V1_ROUTES.admin.disable_user)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!ctx.authenticate(USERNAME, PASSWORD).await);
+
+        ctx.db.set_user_disabled(USERNAME, false).await.unwrap();
+        assert!(ctx.authenticate(USERNAME, PASSWORD).await);
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .append_header((header::AUTHORIZATION, admin.clone()))
+                .set_json(&ResetPassword {
+                    username: USERNAME.into(),
+                    password: NEW_PASSWORD.into(),
+                })
+                .uri(API_V1_ROUTES.admin.reset_password)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!ctx.authenticate(USERNAME, PASSWORD).await);
+        assert!(ctx.authenticate(USERNAME, NEW_PASSWORD).await);
+
+        let payload = Username {
+            username: USERNAME.into(),
+        };
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::delete()
+                .append_header((header::AUTHORIZATION, admin.clone()))
+                .set_json(&payload)
+                .uri(API_V1_ROUTES.admin.delete_user)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!ctx.authenticate(USERNAME, NEW_PASSWORD).await);
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::delete()
+                .append_header((header::AUTHORIZATION, admin))
+                .set_json(&payload)
+                .uri(API_V1_ROUTES.admin.delete_user)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -14,18 +14,31 @@
  * You should have received a copy of the GNU Affero General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
+use std::io;
+use std::path::{Path, PathBuf};
+
 use actix_multipart::Multipart;
 use actix_web::HttpMessage;
 use actix_web::{web, Error, HttpRequest, HttpResponse, Responder};
 use actix_web_httpauth::middleware::HttpAuthentication;
 use futures_util::TryStreamExt as _;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
 
 use super::httpauth;
 use super::SignedInUser;
 use super::API_V1_ROUTES;
+use crate::compression::remove_sidecars;
+use crate::db::{FileFilter, FileInfo, SortBy};
+use crate::errors::*;
+use crate::extract::{extract, Format, Usage};
+use crate::index::{digest, index_file, walk};
+use crate::manifest::SIGNATURE_EXTENSIONS;
+use crate::pages::files::file_url;
+use crate::settings::clean_path;
 use crate::AppCtx;
 
 pub mod routes {
@@ -33,14 +46,20 @@ pub mod routes {
     #[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
     pub struct Files {
         pub delete_dir: &'static str,
+        pub delete_file: &'static str,
+        pub mkdir: &'static str,
         pub upload_file: &'static str,
+        pub search: &'static str,
         pub index: &'static str,
     }
     impl Files {
         pub const fn new() -> Self {
             Self {
                 delete_dir: "/api/v1/files/delete",
+                delete_file: "/api/v1/files/delete-file",
+                mkdir: "/api/v1/files/mkdir",
                 upload_file: "/api/v1/files/upload",
+                search: "/api/v1/files/search",
                 index: "/api/v1/files/",
             }
         }
@@ -49,33 +68,45 @@ pub mod routes {
 
 pub fn services(cfg: &mut web::ServiceConfig) {
     cfg.service(delete_dir);
+    cfg.service(delete_file);
+    cfg.service(mkdir);
     cfg.service(upload_file);
+    cfg.service(search);
     cfg.service(index);
 }
 
 #[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
-struct Dir {
-    path: String,
+pub struct Dir {
+    pub path: String,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Upload {
+    pub path: String,
+    /// extract uploaded archives into `path` instead of saving them
+    #[serde(default)]
+    pub extract: bool,
 }
 
 #[actix_web_codegen_const_routes::delete(
     path = "API_V1_ROUTES.files.delete_dir",
-    wrap = "HttpAuthentication::basic(httpauth)"
+    wrap = "HttpAuthentication::with_fn(httpauth)"
 )]
 async fn delete_dir(
     req: HttpRequest,
     ctx: AppCtx,
     payload: web::Json<Dir>,
 ) -> Result<impl Responder, Error> {
-    let path = {
-        let ext = req.extensions();
-        let user = ext.get::<SignedInUser>().unwrap().clone();
-        ctx.settings.files.get_path(&user.0, &payload.path)
-    };
+    let user = req.extensions().get::<SignedInUser>().unwrap().clone();
+    let path = ctx.settings.files.get_path(&user.0, &payload.path);
 
     if path.exists() {
         if path.is_dir() {
-            fs::remove_dir_all(path).await?;
+            fs::remove_dir_all(&path).await?;
+            remove_sidecars(&ctx.settings.files, &path).await;
+            ctx.db
+                .delete_dir(&user.0, &clean_path(&payload.path))
+                .await?;
             Ok(HttpResponse::Ok().into())
         } else {
             Ok(HttpResponse::BadRequest().body("Path is not dir".to_string()))
@@ -85,62 +116,455 @@ async fn delete_dir(
     }
 }
 
+#[actix_web_codegen_const_routes::delete(
+    path = "API_V1_ROUTES.files.delete_file",
+    wrap = "HttpAuthentication::with_fn(httpauth)"
+)]
+async fn delete_file(
+    req: HttpRequest,
+    ctx: AppCtx,
+    payload: web::Json<Dir>,
+) -> Result<impl Responder, Error> {
+    let user = req.extensions().get::<SignedInUser>().unwrap().clone();
+    let path = ctx.settings.files.get_path(&user.0, &payload.path);
+
+    if path.is_file() {
+        fs::remove_file(&path).await?;
+        remove_sidecars(&ctx.settings.files, &path).await;
+        ctx.db
+            .delete_file(&user.0, &clean_path(&payload.path))
+            .await?;
+        Ok(HttpResponse::Ok().into())
+    } else if path.exists() {
+        Ok(HttpResponse::BadRequest().body("Path is not file".to_string()))
+    } else {
+        Ok(HttpResponse::NotFound().body("file not found".to_string()))
+    }
+}
+
+#[actix_web_codegen_const_routes::post(
+    path = "API_V1_ROUTES.files.mkdir",
+    wrap = "HttpAuthentication::with_fn(httpauth)"
+)]
+async fn mkdir(
+    req: HttpRequest,
+    ctx: AppCtx,
+    payload: web::Json<Dir>,
+) -> Result<impl Responder, Error> {
+    let path = {
+        let ext = req.extensions();
+        let user = ext.get::<SignedInUser>().unwrap().clone();
+        ctx.settings.files.get_path(&user.0, &payload.path)
+    };
+
+    if path.is_file() {
+        Ok(HttpResponse::BadRequest().body("Path is file".to_string()))
+    } else {
+        fs::create_dir_all(path).await?;
+        Ok(HttpResponse::Ok().into())
+    }
+}
+
 #[actix_web_codegen_const_routes::post(
     path = "API_V1_ROUTES.files.upload_file",
-    wrap = "HttpAuthentication::basic(httpauth)"
+    wrap = "HttpAuthentication::with_fn(httpauth)"
 )]
 async fn upload_file(
     ctx: AppCtx,
     mut payload: Multipart,
     req: HttpRequest,
-    query: web::Query<Dir>,
+    query: web::Query<Upload>,
 ) -> Result<HttpResponse, Error> {
-    let path = {
-        let ext = req.extensions();
-        let user = ext.get::<SignedInUser>().unwrap().clone();
-        ctx.settings.files.get_path(&user.0, &query.path)
-    };
-    if !path.exists() {
-        fs::create_dir_all(&path).await?;
+    let user = req.extensions().get::<SignedInUser>().unwrap().clone();
+    let dir = clean_path(&query.path);
+    if query.extract {
+        let files = extract_files(&ctx, &user.0, &dir, &mut payload).await?;
+        return Ok(HttpResponse::Ok().json(files));
+    }
+    save_files(&ctx, &user.0, &dir, &mut payload).await?;
+    Ok(HttpResponse::Ok().into())
+}
+
+/// write files in multipart `payload` to `dir` in `owner`'s tree and index them. Returns
+/// paths of saved files.
+pub async fn save_files(
+    ctx: &AppCtx,
+    owner: &str,
+    dir: &str,
+    payload: &mut Multipart,
+) -> Result<Vec<String>, Error> {
+    let staged = Staged::receive(ctx, payload).await?;
+    staged.verify(ctx, owner, dir).await?;
+    Ok(staged.commit(ctx, owner, dir).await?)
+}
+
+/// extract archives in multipart `payload` to `dir` in `owner`'s tree and index the extracted
+/// files. Signatures uploaded alongside are checked against the archives. Returns paths of
+/// extracted files and links.
+pub async fn extract_files(
+    ctx: &AppCtx,
+    owner: &str,
+    dir: &str,
+    payload: &mut Multipart,
+) -> Result<Vec<String>, Error> {
+    let staged = Staged::receive(ctx, payload).await?;
+    staged.verify(ctx, owner, dir).await?;
+    let extracted = staged.extract(ctx).await?;
+    Ok(extracted.commit(ctx, owner, dir).await?)
+}
+
+/// uploaded files waiting in the staging directory. Nothing is visible in users' trees until
+/// [Staged::commit] is called, the staging directory is removed when dropped.
+pub struct Staged {
+    path: PathBuf,
+    /// file names and their SHA-256 digests
+    files: Vec<(String, String)>,
+    /// symbolic links extracted from archives
+    links: Vec<String>,
+}
+
+impl Staged {
+    async fn new(ctx: &AppCtx) -> ServiceResult<Self> {
+        let staged = Self {
+            path: ctx
+                .settings
+                .files
+                .get_staging_path()
+                .join(Uuid::new_v4().to_string()),
+            files: Vec::new(),
+            links: Vec::new(),
+        };
+        fs::create_dir_all(&staged.path).await?;
+        Ok(staged)
     }
 
-    // iterate over multipart stream
-    while let Some(mut field) = payload.try_next().await? {
-        // A multipart/form-data stream has to contain `content_disposition`
-        let content_disposition = field.content_disposition();
+    /// receive files in multipart `payload`
+    pub async fn receive(ctx: &AppCtx, payload: &mut Multipart) -> Result<Self, Error> {
+        let mut staged = Self::new(ctx).await?;
+
+        // iterate over multipart stream
+        while let Some(mut field) = payload.try_next().await? {
+            // A multipart/form-data stream has to contain `content_disposition`
+            let content_disposition = field.content_disposition();
+
+            let filename = match content_disposition.get_filename() {
+                Some(filename) => sanitize_filename::sanitize(filename),
+                None => return Err(ServiceError::FilenameNotPresent.into()),
+            };
 
-        let filename = content_disposition.get_filename();
+            let mut f = fs::File::create(staged.path.join(&filename)).await?;
+            let mut hasher = Sha256::new();
 
-        if filename.is_none() {
-            return Ok(HttpResponse::BadRequest().body("Filename is not present".to_string()));
+            // Field in turn is stream of *Bytes* object
+            while let Some(chunk) = field.try_next().await? {
+                hasher.update(&chunk);
+                f.write_all(&chunk).await?
+            }
+            f.flush().await?;
+
+            let digest = hex::encode(hasher.finalize());
+            staged.files.retain(|(name, _)| name != &filename);
+            staged.files.push((filename, digest));
         }
-        let filename = filename.unwrap();
-        let filepath = path.join(filename);
+        Ok(staged)
+    }
 
-        let mut f = fs::File::create(filepath).await?;
+    /// check signatures when `dir` in `owner`'s tree requires them
+    pub async fn verify(&self, ctx: &AppCtx, owner: &str, dir: &str) -> ServiceResult<()> {
+        let names: Vec<String> = self.files.iter().map(|(name, _)| name.clone()).collect();
+        ctx.keyring.verify(owner, dir, &self.path, &names).await
+    }
 
-        // Field in turn is stream of *Bytes* object
-        while let Some(chunk) = field.try_next().await? {
-            f.write_all(&chunk).await?
+    /// extract received archives, see [crate::extract]. Every file except signatures must be
+    /// an archive.
+    pub async fn extract(&self, ctx: &AppCtx) -> ServiceResult<Self> {
+        let mut archives = Vec::with_capacity(self.files.len());
+        for (filename, _) in self.files.iter() {
+            if SIGNATURE_EXTENSIONS
+                .iter()
+                .any(|ext| filename.ends_with(ext))
+            {
+                continue;
+            }
+            let format = Format::from_name(filename).ok_or(ServiceError::UnsupportedArchive)?;
+            archives.push((self.path.join(filename), format));
         }
+
+        let mut extracted = Self::new(ctx).await?;
+        let mut usage = Usage::default();
+        for (archive, format) in archives {
+            let dest = extracted.path.clone();
+            let limits = ctx.settings.files.extract.clone();
+            let (entries, used) = web::block(move || {
+                extract(&archive, format, &dest, &limits, &mut usage).map(|e| (e, usage))
+            })
+            .await
+            .map_err(|_| ServiceError::InternalServerError)??;
+            usage = used;
+
+            for entry in entries {
+                extracted.files.retain(|(name, _)| name != &entry.path);
+                extracted.links.retain(|name| name != &entry.path);
+                if entry.is_symlink {
+                    extracted.links.push(entry.path);
+                } else {
+                    let digest = digest(&extracted.path.join(&entry.path)).await?;
+                    extracted.files.push((entry.path, digest));
+                }
+            }
+        }
+        Ok(extracted)
     }
 
-    Ok(HttpResponse::Ok().into())
+    /// replace `dir` in `owner`'s tree with the received files, see [replace_dir]
+    pub async fn replace(self, ctx: &AppCtx, owner: &str, dir: &str) -> ServiceResult<Vec<String>> {
+        replace_dir(ctx, owner, dir, &self.path).await
+    }
+
+    /// move received files to `dir`, which must be on the same filesystem
+    pub async fn move_to(self, dir: &Path) -> ServiceResult<()> {
+        for (filename, _) in self.files.iter() {
+            fs::rename(self.path.join(filename), dir.join(filename)).await?;
+        }
+        Ok(())
+    }
+
+    /// move files to `dir` in `owner`'s tree and index them. Returns paths of saved files.
+    pub async fn commit(self, ctx: &AppCtx, owner: &str, dir: &str) -> ServiceResult<Vec<String>> {
+        let path = ctx.settings.files.get_path(owner, dir);
+        if !path.exists() {
+            fs::create_dir_all(&path).await?;
+        }
+
+        let file_path = |filename: &str| {
+            if dir.is_empty() {
+                filename.to_owned()
+            } else {
+                format!("{dir}/{filename}")
+            }
+        };
+
+        let mut files = Vec::with_capacity(self.files.len() + self.links.len());
+        for (filename, digest) in self.files.iter() {
+            let target = path.join(filename);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            fs::rename(self.path.join(filename), target).await?;
+            let file = file_path(filename);
+            index_file(ctx, owner, &file, owner, digest.clone()).await?;
+            files.push(file);
+        }
+        // links aren't indexed, like during reconciliation
+        for link in self.links.iter() {
+            let target = path.join(link);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            fs::rename(self.path.join(link), target).await?;
+            let file = file_path(link);
+            ctx.db.delete_file(owner, &file).await?;
+            files.push(file);
+        }
+        Ok(files)
+    }
+}
+
+impl Drop for Staged {
+    fn drop(&mut self) {
+        match std::fs::remove_dir_all(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                log::warn!("Unable to remove staging directory {:?}: {e}", self.path)
+            }
+            _ => (),
+        }
+    }
+}
+
+/// replace `dir` in `owner`'s tree with directory `source` and index the new contents.
+/// Readers see either the old or the new contents, never a mix of both. Old contents are left
+/// in `source`. Returns paths of files in the new directory.
+pub async fn replace_dir(
+    ctx: &AppCtx,
+    owner: &str,
+    dir: &str,
+    source: &Path,
+) -> ServiceResult<Vec<String>> {
+    if dir.is_empty() {
+        return Err(ServiceError::InvalidPath);
+    }
+
+    let target = ctx.settings.files.get_path(owner, dir);
+    if target.exists() {
+        exchange(source, &target)?;
+    } else {
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::rename(source, &target).await?;
+    }
+
+    remove_sidecars(&ctx.settings.files, &target).await;
+    ctx.db.delete_dir(owner, dir).await?;
+    let mut files = Vec::new();
+    for (name, _) in walk(&target).await {
+        let digest = digest(&target.join(&name)).await?;
+        let file = format!("{dir}/{name}");
+        index_file(ctx, owner, &file, owner, digest).await?;
+        files.push(file);
+    }
+    Ok(files)
+}
+
+/// atomically swap two paths with `renameat2(RENAME_EXCHANGE)`. Falls back to renames with a
+/// short window where `b` is missing when the filesystem doesn't support it.
+#[cfg(target_os = "linux")]
+fn exchange(a: &Path, b: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let a_c = CString::new(a.as_os_str().as_bytes())?;
+    let b_c = CString::new(b.as_os_str().as_bytes())?;
+    // SAFETY: both paths are valid NUL terminated strings that outlive the call
+    let res = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            a_c.as_ptr(),
+            libc::AT_FDCWD,
+            b_c.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+    if res == 0 {
+        return Ok(());
+    }
+    match io::Error::last_os_error() {
+        e if matches!(e.raw_os_error(), Some(libc::EINVAL) | Some(libc::ENOSYS)) => {
+            exchange_with_renames(a, b)
+        }
+        e => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn exchange(a: &Path, b: &Path) -> io::Result<()> {
+    exchange_with_renames(a, b)
+}
+
+fn exchange_with_renames(a: &Path, b: &Path) -> io::Result<()> {
+    let tmp = a.with_extension("exchange");
+    std::fs::rename(b, &tmp)?;
+    std::fs::rename(a, b)?;
+    std::fs::rename(&tmp, a)
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Search {
+    /// case-insensitive substring of path
+    pub q: Option<String>,
+    /// glob pattern. Matched against `<owner>/<path>` when it contains `/`, otherwise against
+    /// file name.
+    pub glob: Option<String>,
+    pub owner: Option<String>,
+    /// content type prefix
+    pub content_type: Option<String>,
+    pub min_size: Option<i64>,
+    pub max_size: Option<i64>,
+    /// UNIX timestamp
+    pub modified_after: Option<i64>,
+    /// UNIX timestamp
+    pub modified_before: Option<i64>,
+    pub sort: Option<SortBy>,
+    #[serde(default)]
+    pub desc: bool,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct SearchResult {
+    #[serde(flatten)]
+    pub file: FileInfo,
+    /// public URL of file
+    pub url: String,
+}
+
+const SEARCH_LIMIT: usize = 100;
+const SEARCH_MAX_LIMIT: usize = 1000;
+
+/// search the file index. Stored trees are public, results are scoped to what the public file
+/// service serves: hidden files and files in hidden directories are left out.
+#[actix_web_codegen_const_routes::get(path = "API_V1_ROUTES.files.search")]
+async fn search(ctx: AppCtx, query: web::Query<Search>) -> ServiceResult<impl Responder> {
+    let query = query.into_inner();
+    let glob = match &query.glob {
+        Some(glob) => Some(glob::Pattern::new(glob).map_err(|_| ServiceError::InvalidGlob)?),
+        None => None,
+    };
+    let match_path = query
+        .glob
+        .as_ref()
+        .map(|g| g.contains('/'))
+        .unwrap_or(false);
+    let options = glob::MatchOptions {
+        case_sensitive: true,
+        require_literal_separator: true,
+        require_literal_leading_dot: false,
+    };
+    let matches = |f: &FileInfo| match &glob {
+        Some(glob) if match_path => glob.matches_with(&format!("{}/{}", f.owner, f.path), options),
+        Some(glob) => glob.matches_with(f.path.rsplit('/').next().unwrap(), options),
+        None => true,
+    };
+
+    let filter = FileFilter {
+        owner: query.owner,
+        contains: query.q,
+        content_type: query.content_type,
+        min_size: query.min_size,
+        max_size: query.max_size,
+        modified_after: query.modified_after,
+        modified_before: query.modified_before,
+        sort: query.sort,
+        descending: query.desc,
+        exclude_hidden: true,
+        ..Default::default()
+    };
+    let limit = query.limit.unwrap_or(SEARCH_LIMIT).min(SEARCH_MAX_LIMIT);
+    let files = ctx
+        .db
+        .search_files(&filter, matches, query.offset.unwrap_or_default(), limit)
+        .await?;
+
+    let results: Vec<SearchResult> = files
+        .into_iter()
+        .map(|file| SearchResult {
+            url: file_url(ctx.settings.server.prefix(), &file.owner, &file.path),
+            file,
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(results))
 }
+
 #[actix_web_codegen_const_routes::get(
     path = "API_V1_ROUTES.files.index",
-    wrap = "HttpAuthentication::basic(httpauth)"
+    wrap = "HttpAuthentication::with_fn(httpauth)"
 )]
-async fn index() -> HttpResponse {
-    let html = r#"<html>
+async fn index(ctx: AppCtx) -> HttpResponse {
+    let html = format!(
+        r#"<html>
         <head><title>Upload Test</title></head>
         <body>
-            <form target="/" method="post" enctype="multipart/form-data">
+            <form action="{}?path=" method="post" enctype="multipart/form-data">
                 <input type="file" multiple name="file"/>
                 <button type="submit">Submit</button>
             </form>
         </body>
-    </html>"#;
+    </html>"#,
+        ctx.settings
+            .server
+            .url_path(API_V1_ROUTES.files.upload_file)
+    );
 
     HttpResponse::Ok().body(html)
 }
@@ -186,6 +610,85 @@ pub mod tests {
         assert_eq!(index_resp.status(), StatusCode::OK);
     }
 
+    #[actix_rt::test]
+    async fn upload_file_works() {
+        const TEST_DIR_NAME: &str = "test-upload_file_works";
+        const TEST_FILE_NAME: &str = "foo.txt";
+        const BOUNDARY: &str = "dumbserveboundary";
+
+        let settings = Settings::new().unwrap();
+        let creds = settings.files.creds.first().unwrap().clone();
+        let auth = crate::tests::basic_auth(&creds.username, &creds.password);
+        let ctx = AppCtx::new(crate::ctx::Ctx::new(&settings).await);
+        let app = test::init_service(
+            App::new()
+                .app_data(ctx.clone())
+                .configure(crate::routes::services),
+        )
+        .await;
+
+        let body = format!(
+            "--{BOUNDARY}\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"{TEST_FILE_NAME}\"\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            foo\r\n\
+            --{BOUNDARY}--\r\n"
+        );
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .append_header((header::AUTHORIZATION, auth.clone()))
+                .append_header((
+                    header::CONTENT_TYPE,
+                    format!("multipart/form-data; boundary={BOUNDARY}"),
+                ))
+                .set_payload(body)
+                .uri(&format!(
+                    "{}?path={TEST_DIR_NAME}",
+                    API_V1_ROUTES.files.upload_file
+                ))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let path = format!("{TEST_DIR_NAME}/{TEST_FILE_NAME}");
+        let file = settings.files.get_path(&creds.username, &path);
+        assert_eq!(tokio::fs::read(&file).await.unwrap(), b"foo");
+
+        let indexed = ctx
+            .db
+            .get_file(&creds.username, &path)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(indexed.size, 3);
+        assert_eq!(
+            indexed.digest,
+            "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae"
+        );
+        assert_eq!(indexed.uploader, creds.username);
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::delete()
+                .append_header((header::AUTHORIZATION, auth))
+                .set_json(&Dir {
+                    path: TEST_DIR_NAME.into(),
+                })
+                .uri(API_V1_ROUTES.files.delete_dir)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(ctx
+            .db
+            .get_file(&creds.username, &path)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
     #[actix_rt::test]
     async fn delete_dir_works() {
         //        const USERNAME: &str = "index_works";
@@ -261,4 +764,244 @@ pub mod tests {
 
         assert!(!test_dir.exists());
     }
+
+    #[actix_rt::test]
+    async fn search_works() {
+        const OWNER: &str = "test-search_works";
+
+        let settings = Settings::new().unwrap();
+        let ctx = AppCtx::new(crate::ctx::Ctx::new(&settings).await);
+        ctx.db.delete_dir(OWNER, "").await.unwrap();
+        for (path, size) in [
+            ("docs/readme.md", 10),
+            ("docs/guide.md", 200),
+            ("bin/app", 3000),
+            // hidden files aren't served, so they aren't found either
+            (".env", 5),
+            ("docs/.drafts/notes.md", 20),
+        ] {
+            let file = FileInfo {
+                owner: OWNER.into(),
+                path: path.into(),
+                size,
+                digest: String::default(),
+                content_type: crate::index::content_type(path),
+                modified: size,
+                uploaded: size,
+                uploader: OWNER.into(),
+            };
+            ctx.db.upsert_file(&file).await.unwrap();
+        }
+
+        let app = test::init_service(
+            App::new()
+                .app_data(ctx.clone())
+                .configure(crate::routes::services),
+        )
+        .await;
+
+        let request = |query: String| {
+            test::TestRequest::get()
+                .uri(&format!(
+                    "{}?owner={OWNER}&{query}",
+                    API_V1_ROUTES.files.search
+                ))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, request("q=DOCS&sort=size&desc=true".into())).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let results: Vec<SearchResult> = test::read_body_json(resp).await;
+        let paths: Vec<&str> = results.iter().map(|r| r.file.path.as_str()).collect();
+        assert_eq!(paths, ["docs/guide.md", "docs/readme.md"]);
+        assert_eq!(results[0].url, format!("/{OWNER}/docs/guide.md"));
+
+        let resp = test::call_service(&app, request("glob=*.md&max_size=100".into())).await;
+        let results: Vec<SearchResult> = test::read_body_json(resp).await;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].file.path, "docs/readme.md");
+
+        let resp = test::call_service(&app, request(format!("glob={OWNER}/bin/*"))).await;
+        let results: Vec<SearchResult> = test::read_body_json(resp).await;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].file.path, "bin/app");
+
+        let resp = test::call_service(&app, request("limit=1&offset=1".into())).await;
+        let results: Vec<SearchResult> = test::read_body_json(resp).await;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].file.path, "docs/guide.md");
+
+        let resp = test::call_service(&app, request("glob=[".into())).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let children = ctx.db.list_children(OWNER, "docs").await.unwrap();
+        let paths: Vec<&str> = children.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, ["docs/guide.md", "docs/readme.md"]);
+        let children = ctx.db.list_children(OWNER, "").await.unwrap();
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].path, ".env");
+
+        ctx.db.delete_dir(OWNER, "").await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn signed_upload_works() {
+        use crate::signatures::tests::{MINISIGN_KEY, MINISIGN_SIGNATURE};
+        use crate::tests::multipart;
+
+        const TEST_DIR_NAME: &str = "test-signed_upload_works";
+
+        let mut settings = Settings::new().unwrap();
+        let creds = settings.files.creds.first().unwrap().clone();
+        settings
+            .files
+            .trusted_keys
+            .push(crate::settings::TrustedKey {
+                owner: creds.username.clone(),
+                path: Some(TEST_DIR_NAME.into()),
+                key: MINISIGN_KEY.into(),
+            });
+        let auth = crate::tests::basic_auth(&creds.username, &creds.password);
+        let ctx = AppCtx::new(crate::ctx::Ctx::new(&settings).await);
+        let app = test::init_service(
+            App::new()
+                .app_data(ctx.clone())
+                .configure(crate::routes::services),
+        )
+        .await;
+
+        let upload = |files: &[(&str, &str)]| {
+            let (content_type, body) = multipart(files);
+            test::TestRequest::post()
+                .append_header((header::AUTHORIZATION, auth.clone()))
+                .append_header((header::CONTENT_TYPE, content_type))
+                .set_payload(body)
+                .uri(&format!(
+                    "{}?path={TEST_DIR_NAME}/1.0.0",
+                    API_V1_ROUTES.files.upload_file
+                ))
+                .to_request()
+        };
+        let file = settings
+            .files
+            .get_path(&creds.username, &format!("{TEST_DIR_NAME}/1.0.0/data"));
+
+        let resp = test::call_service(&app, upload(&[("data", "foo")])).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(!file.exists());
+
+        let signature = MINISIGN_SIGNATURE.replace("file:data", "file:tampered");
+        let resp = test::call_service(
+            &app,
+            upload(&[("data", "foo"), ("data.minisig", &signature)]),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(!file.exists());
+
+        // checksums and signatures are only exempt next to their signed artifact
+        let resp = test::call_service(&app, upload(&[("payload.sha256", "foo")])).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = test::call_service(
+            &app,
+            upload(&[("data.sha256", "foo"), ("data.minisig", MINISIGN_SIGNATURE)]),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(!file.exists());
+
+        let resp = test::call_service(
+            &app,
+            upload(&[
+                ("data", "foo"),
+                ("data.minisig", MINISIGN_SIGNATURE),
+                ("data.sha256", "checksum"),
+            ]),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(file.exists());
+
+        let dir = settings.files.get_path(&creds.username, TEST_DIR_NAME);
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+        ctx.db
+            .delete_dir(&creds.username, TEST_DIR_NAME)
+            .await
+            .unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn extract_upload_works() {
+        use crate::extract::tests::{append, tar_archive};
+        use crate::tests::multipart;
+
+        const TEST_DIR_NAME: &str = "test-extract_upload_works";
+
+        let settings = Settings::new().unwrap();
+        let creds = settings.files.creds.first().unwrap().clone();
+        let auth = crate::tests::basic_auth(&creds.username, &creds.password);
+        let ctx = AppCtx::new(crate::ctx::Ctx::new(&settings).await);
+        let app = test::init_service(
+            App::new()
+                .app_data(ctx.clone())
+                .configure(crate::routes::services),
+        )
+        .await;
+
+        let upload = |filename: &str, archive: Vec<u8>| {
+            // plain tar archives of text files are valid UTF-8
+            let archive = String::from_utf8(archive).unwrap();
+            let (content_type, body) = multipart(&[(filename, &archive)]);
+            test::TestRequest::post()
+                .append_header((header::AUTHORIZATION, auth.clone()))
+                .append_header((header::CONTENT_TYPE, content_type))
+                .set_payload(body)
+                .uri(&format!(
+                    "{}?path={TEST_DIR_NAME}&extract=true",
+                    API_V1_ROUTES.files.upload_file
+                ))
+                .to_request()
+        };
+
+        let archive = tar_archive(|b| append(b, "foo", b"foo"));
+        let resp = test::call_service(&app, upload("site.txt", archive)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let archive = tar_archive(|b| append(b, "../escaped", b"foo"));
+        let resp = test::call_service(&app, upload("site.tar", archive)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(!settings.files.get_path(&creds.username, "escaped").exists());
+
+        let archive = tar_archive(|b| {
+            append(b, "index.html", b"foo");
+            append(b, "css/main.css", b"bar");
+        });
+        let resp = test::call_service(&app, upload("site.tar", archive)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let files: Vec<String> = test::read_body_json(resp).await;
+        assert_eq!(
+            files,
+            vec![
+                format!("{TEST_DIR_NAME}/index.html"),
+                format!("{TEST_DIR_NAME}/css/main.css")
+            ]
+        );
+        let file = settings
+            .files
+            .get_path(&creds.username, &format!("{TEST_DIR_NAME}/css/main.css"));
+        assert_eq!(tokio::fs::read(&file).await.unwrap(), b"bar");
+        assert!(ctx
+            .db
+            .get_file(&creds.username, &files[1])
+            .await
+            .unwrap()
+            .is_some());
+
+        let dir = settings.files.get_path(&creds.username, TEST_DIR_NAME);
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+        ctx.db
+            .delete_dir(&creds.username, TEST_DIR_NAME)
+            .await
+            .unwrap();
+    }
 }
@@ -19,6 +19,7 @@ use actix_web::{web, HttpResponse, Responder};
 use derive_builder::Builder;
 use serde::{Deserialize, Serialize};
 
+use crate::errors::*;
 use crate::AppCtx;
 use crate::{GIT_COMMIT_HASH, VERSION};
 
@@ -33,6 +34,7 @@ pub mod routes {
     pub struct Meta {
         pub build_details: &'static str,
         pub health: &'static str,
+        pub signing_key: &'static str,
     }
 
     impl Meta {
@@ -40,6 +42,7 @@ pub mod routes {
             Self {
                 build_details: "/api/v1/meta/build",
                 health: "/api/v1/meta/health",
+                signing_key: "/api/v1/meta/signing-key",
             }
         }
     }
@@ -64,23 +67,34 @@ pub struct Health {
 
 /// checks all components of the system
 #[actix_web_codegen_const_routes::get(path = "crate::API_V1_ROUTES.meta.health")]
-async fn health() -> impl Responder {
-    //   let mut resp_builder = HealthBuilder::default();
+async fn health(ctx: AppCtx) -> impl Responder {
+    let mut resp_builder = HealthBuilder::default();
 
-    //   resp_builder.db(data.db.ping().await);
+    resp_builder.db(ctx.db.ping().await);
 
-    HttpResponse::Ok() //.json(resp_builder.build().unwrap())
+    HttpResponse::Ok().json(resp_builder.build().unwrap())
+}
+
+/// minisign public key releases are signed with
+#[actix_web_codegen_const_routes::get(path = "crate::API_V1_ROUTES.meta.signing_key")]
+async fn signing_key(ctx: AppCtx) -> ServiceResult<impl Responder> {
+    let signer = ctx.signer.as_ref().ok_or(ServiceError::SigningDisabled)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .body(signer.public_key()))
 }
 
 pub fn services(cfg: &mut web::ServiceConfig) {
     cfg.service(build_details);
     cfg.service(health);
+    cfg.service(signing_key);
 }
 
 #[cfg(test)]
 pub mod tests {
     use actix_web::{http::StatusCode, test, App};
 
+    use super::Health;
     use crate::api::v1::services;
     use crate::*;
 
@@ -100,34 +114,22 @@ pub mod tests {
         assert_eq!(resp.status(), StatusCode::OK);
     }
 
-    //    #[actix_rt::test]
-    //    async fn health_works_pg() {
-    //        let data = crate::tests::pg::get_data().await;
-    //        health_works(data).await;
-    //    }
-    //
-    //    #[actix_rt::test]
-    //    async fn health_works_maria() {
-    //        let data = crate::tests::maria::get_data().await;
-    //        health_works(data).await;
-    //    }
-    //
-    //    pub async fn health_works(data: ArcCtx) {
-    //        println!("{}", API_V1_ROUTES.meta.health);
-    //        let data = &data;
-    //        let app = get_app!(data).await;
-    //
-    //        let resp = test::call_service(
-    //            &app,
-    //            test::TestRequest::get()
-    //                .uri(API_V1_ROUTES.meta.health)
-    //                .to_request(),
-    //        )
-    //        .await;
-    //        assert_eq!(resp.status(), StatusCode::OK);
-    //
-    //        let health_resp: Health = test::read_body_json(resp).await;
-    //        assert!(health_resp.db);
-    //        assert_eq!(health_resp.redis, Some(true));
-    //    }
+    #[actix_rt::test]
+    async fn health_works() {
+        let settings = Settings::new().unwrap();
+        let ctx = AppCtx::new(crate::ctx::Ctx::new(&settings).await);
+        let app = test::init_service(App::new().app_data(ctx.clone()).configure(services)).await;
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(API_V1_ROUTES.meta.health)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let health_resp: Health = test::read_body_json(resp).await;
+        assert!(health_resp.db);
+    }
 }
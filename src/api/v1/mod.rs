@@ -14,62 +14,114 @@
  * You should have received a copy of the GNU Affero General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
+use actix_identity::RequestIdentity;
 use actix_web::dev::ServiceRequest;
 use actix_web::web;
 use actix_web::Error;
 use actix_web::HttpMessage;
-use actix_web_httpauth::extractors::basic::BasicAuth;
+use actix_web_httpauth::extractors::basic::{self, BasicAuth};
+use actix_web_httpauth::extractors::AuthenticationError;
 
+pub mod account;
+pub mod admin;
 pub mod files;
 pub mod meta;
+pub mod publish;
+pub mod releases;
 
 use crate::errors::*;
+use crate::settings::ListenerScope;
+use crate::tls::ClientCertificate;
 use crate::AppCtx;
-use crate::SETTINGS;
 
 pub const API_V1_ROUTES: routes::Routes = routes::Routes::new();
 
 #[derive(Debug, Clone, Eq, PartialEq)]
-pub struct SignedInUser(String);
+pub struct SignedInUser(pub String);
 
+/// authenticates requests with HTTP Basic credentials or, when those are absent, a verified TLS
+/// client certificate mapped to a user or the browser session cookie issued by the login page
 pub async fn httpauth(
     req: ServiceRequest,
-    credentials: BasicAuth,
+    credentials: Option<BasicAuth>,
 ) -> Result<ServiceRequest, (Error, ServiceRequest)> {
-    let _ctx: &AppCtx = req.app_data().unwrap();
-    let username = credentials.user_id();
-    let password = credentials.password().unwrap();
-    if SETTINGS.files.authenticate(username, password) {
-        {
-            let mut ext = req.extensions_mut();
-            ext.insert(SignedInUser(username.to_string()));
-        }
-        Ok(req)
-    } else {
+    let ctx: &AppCtx = req.app_data().unwrap();
+    if crate::listeners::scope(req.request()) == ListenerScope::Public {
         let e = Error::from(ServiceError::Unauthorized);
-        Err((e, req))
+        return Err((e, req));
+    }
+    let username = match credentials {
+        Some(credentials) => {
+            let username = credentials.user_id();
+            let password = credentials.password().unwrap_or_default();
+            if !ctx.authenticate(username, password).await {
+                let e = Error::from(ServiceError::Unauthorized);
+                return Err((e, req));
+            }
+            username.to_string()
+        }
+        None => {
+            let cert_user = req
+                .request()
+                .conn_data::<ClientCertificate>()
+                .and_then(|cert| ctx.client_user(cert));
+            let username = match (cert_user, req.get_identity()) {
+                (Some(username), _) if ctx.is_active(&username).await => Some(username),
+                (None, Some(identity)) => ctx.session_user(&identity).await,
+                _ => None,
+            };
+            match username {
+                Some(username) => username,
+                None => {
+                    let e = Error::from(AuthenticationError::from(basic::Config::default()));
+                    return Err((e, req));
+                }
+            }
+        }
+    };
+
+    {
+        let mut ext = req.extensions_mut();
+        ext.insert(SignedInUser(username));
     }
+    Ok(req)
 }
 
 pub fn services(cfg: &mut web::ServiceConfig) {
+    account::services(cfg);
+    admin::services(cfg);
     files::services(cfg);
     meta::services(cfg);
+    publish::services(cfg);
+    releases::services(cfg);
 }
 
 pub mod routes {
+    use crate::api::v1::account::routes::Account;
+    use crate::api::v1::admin::routes::Admin;
     use crate::api::v1::files::routes::Files;
     use crate::api::v1::meta::routes::Meta;
+    use crate::api::v1::publish::routes::Publish;
+    use crate::api::v1::releases::routes::Releases;
 
     pub struct Routes {
+        pub account: Account,
+        pub admin: Admin,
         pub files: Files,
         pub meta: Meta,
+        pub publish: Publish,
+        pub releases: Releases,
     }
 
     impl Routes {
         pub const fn new() -> Self {
             Self {
+                account: Account::new(),
+                admin: Admin::new(),
                 files: Files::new(),
                 meta: Meta::new(),
+                publish: Publish::new(),
+                releases: Releases::new(),
             }
         }
     }
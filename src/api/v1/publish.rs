@@ -0,0 +1,399 @@
+/*
+ * Copyright (C) 2022  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Publish sessions: files uploaded to a session are staged until the session is committed,
+//! which atomically replaces the target directory with them.
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use actix_multipart::Multipart;
+use actix_web::HttpMessage;
+use actix_web::{web, Error, HttpRequest, HttpResponse, Responder};
+use actix_web_httpauth::middleware::HttpAuthentication;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use uuid::Uuid;
+
+use super::files::{replace_dir, Dir, Staged};
+use super::httpauth;
+use super::SignedInUser;
+use super::API_V1_ROUTES;
+use crate::db::now_unix;
+use crate::errors::*;
+use crate::index::walk;
+use crate::settings::clean_path;
+use crate::signing::sign_dir;
+use crate::{AppCtx, Ctx};
+
+/// sessions and staged uploads older than this are removed, see [spawn_cleaner]
+pub const STAGING_TTL: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// how often stale sessions and staged uploads are looked for
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const SESSION_FILE: &str = "session.json";
+
+pub mod routes {
+    use super::*;
+    #[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+    pub struct Publish {
+        pub open: &'static str,
+        pub upload: &'static str,
+        pub commit: &'static str,
+        pub abort: &'static str,
+    }
+    impl Publish {
+        pub const fn new() -> Self {
+            Self {
+                open: "/api/v1/publish/open",
+                upload: "/api/v1/publish/upload",
+                commit: "/api/v1/publish/commit",
+                abort: "/api/v1/publish/abort",
+            }
+        }
+    }
+}
+
+pub fn services(cfg: &mut web::ServiceConfig) {
+    cfg.service(open);
+    cfg.service(upload);
+    cfg.service(commit);
+    cfg.service(abort);
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct SessionId {
+    pub id: String,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+struct Session {
+    owner: String,
+    /// directory replaced on commit
+    path: String,
+    created: i64,
+}
+
+fn session_path(ctx: &Ctx, id: &str) -> ServiceResult<PathBuf> {
+    let id = Uuid::parse_str(id).map_err(|_| ServiceError::SessionNotFound)?;
+    Ok(ctx.settings.files.get_staging_path().join(id.to_string()))
+}
+
+/// load session `id` of `owner`, returns session and its directory
+async fn load(ctx: &Ctx, owner: &str, id: &str) -> ServiceResult<(Session, PathBuf)> {
+    let path = session_path(ctx, id)?;
+    let session = fs::read(path.join(SESSION_FILE))
+        .await
+        .ok()
+        .and_then(|s| serde_json::from_slice::<Session>(&s).ok())
+        .filter(|s| s.owner == owner)
+        .ok_or(ServiceError::SessionNotFound)?;
+    Ok((session, path))
+}
+
+/// remove sessions and staged uploads older than [STAGING_TTL]
+pub async fn remove_stale(ctx: &Ctx) -> ServiceResult<()> {
+    let staging = ctx.settings.files.get_staging_path();
+    if !staging.exists() {
+        return Ok(());
+    }
+    let mut read_dir = fs::read_dir(&staging).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let modified = entry.metadata().await?.modified()?;
+        let age = SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default();
+        if age > STAGING_TTL {
+            log::info!("Removing stale staging directory {:?}", entry.path());
+            fs::remove_dir_all(entry.path()).await?;
+        }
+    }
+    Ok(())
+}
+
+/// [remove_stale] at startup and every [CLEANUP_INTERVAL] after it
+pub fn spawn_cleaner(ctx: AppCtx) {
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(CLEANUP_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = remove_stale(&ctx).await {
+                log::error!("Unable to remove stale staging directories: {e}");
+            }
+        }
+    });
+}
+
+/// open session to replace `path`
+#[actix_web_codegen_const_routes::post(
+    path = "API_V1_ROUTES.publish.open",
+    wrap = "HttpAuthentication::with_fn(httpauth)"
+)]
+async fn open(
+    req: HttpRequest,
+    ctx: AppCtx,
+    payload: web::Json<Dir>,
+) -> ServiceResult<impl Responder> {
+    let user = req.extensions().get::<SignedInUser>().unwrap().clone();
+    let path = clean_path(&payload.path);
+    if path.is_empty() {
+        return Err(ServiceError::InvalidPath);
+    }
+
+    let id = Uuid::new_v4().to_string();
+    let session_path = session_path(&ctx, &id)?;
+    fs::create_dir_all(session_path.join("files")).await?;
+    let session = Session {
+        owner: user.0,
+        path,
+        created: now_unix(),
+    };
+    fs::write(
+        session_path.join(SESSION_FILE),
+        serde_json::to_vec(&session).unwrap(),
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(SessionId { id }))
+}
+
+/// stage files in session, files with the same name are overwritten
+#[actix_web_codegen_const_routes::post(
+    path = "API_V1_ROUTES.publish.upload",
+    wrap = "HttpAuthentication::with_fn(httpauth)"
+)]
+async fn upload(
+    req: HttpRequest,
+    ctx: AppCtx,
+    query: web::Query<SessionId>,
+    mut payload: Multipart,
+) -> Result<impl Responder, Error> {
+    let user = req.extensions().get::<SignedInUser>().unwrap().clone();
+    let (_, path) = load(&ctx, &user.0, &query.id).await?;
+    let staged = Staged::receive(&ctx, &mut payload).await?;
+    staged.move_to(&path.join("files")).await?;
+    Ok(HttpResponse::Ok())
+}
+
+/// replace session's directory with staged files. Signatures are checked when the directory
+/// requires them, and with server-side signing, the server's are swapped in with the files.
+#[actix_web_codegen_const_routes::post(
+    path = "API_V1_ROUTES.publish.commit",
+    wrap = "HttpAuthentication::with_fn(httpauth)"
+)]
+async fn commit(
+    req: HttpRequest,
+    ctx: AppCtx,
+    payload: web::Json<SessionId>,
+) -> ServiceResult<impl Responder> {
+    let user = req.extensions().get::<SignedInUser>().unwrap().clone();
+    let (session, path) = load(&ctx, &user.0, &payload.id).await?;
+    let files_path = path.join("files");
+
+    let names: Vec<String> = walk(&files_path)
+        .await
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    ctx.keyring
+        .verify(&session.owner, &session.path, &files_path, &names)
+        .await?;
+    if let Some(signer) = &ctx.signer {
+        sign_dir(signer, &files_path, &names).await?;
+    }
+
+    let files = replace_dir(&ctx, &session.owner, &session.path, &files_path).await?;
+    fs::remove_dir_all(&path).await?;
+    Ok(HttpResponse::Ok().json(files))
+}
+
+#[actix_web_codegen_const_routes::post(
+    path = "API_V1_ROUTES.publish.abort",
+    wrap = "HttpAuthentication::with_fn(httpauth)"
+)]
+async fn abort(
+    req: HttpRequest,
+    ctx: AppCtx,
+    payload: web::Json<SessionId>,
+) -> ServiceResult<impl Responder> {
+    let user = req.extensions().get::<SignedInUser>().unwrap().clone();
+    let (_, path) = load(&ctx, &user.0, &payload.id).await?;
+    fs::remove_dir_all(&path).await?;
+    Ok(HttpResponse::Ok())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        test, App,
+    };
+
+    use super::*;
+    use crate::tests::{basic_auth, multipart};
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn publish_sessions_work() {
+        const TEST_DIR_NAME: &str = "test-publish_sessions_work";
+
+        let settings = Settings::new().unwrap();
+        let creds = settings.files.creds.first().unwrap().clone();
+        let auth = basic_auth(&creds.username, &creds.password);
+        let ctx = AppCtx::new(crate::ctx::Ctx::new(&settings).await);
+        let app = test::init_service(
+            App::new()
+                .app_data(ctx.clone())
+                .configure(crate::routes::services),
+        )
+        .await;
+
+        let dir = settings.files.get_path(&creds.username, TEST_DIR_NAME);
+        let _ = fs::remove_dir_all(&dir).await;
+        fs::create_dir_all(&dir).await.unwrap();
+        fs::write(dir.join("old.txt"), b"old").await.unwrap();
+
+        let open_session = || {
+            test::TestRequest::post()
+                .append_header((header::AUTHORIZATION, auth.clone()))
+                .set_json(&Dir {
+                    path: TEST_DIR_NAME.into(),
+                })
+                .uri(API_V1_ROUTES.publish.open)
+                .to_request()
+        };
+        let upload_to = |id: &str, files: &[(&str, &str)]| {
+            let (content_type, body) = multipart(files);
+            test::TestRequest::post()
+                .append_header((header::AUTHORIZATION, auth.clone()))
+                .append_header((header::CONTENT_TYPE, content_type))
+                .set_payload(body)
+                .uri(&format!("{}?id={id}", API_V1_ROUTES.publish.upload))
+                .to_request()
+        };
+        let finish = |route: &str, id: &str| {
+            test::TestRequest::post()
+                .append_header((header::AUTHORIZATION, auth.clone()))
+                .set_json(&SessionId { id: id.into() })
+                .uri(route)
+                .to_request()
+        };
+
+        // aborted sessions don't touch the directory
+        let resp = test::call_service(&app, open_session()).await;
+        let session: SessionId = test::read_body_json(resp).await;
+        let resp = test::call_service(&app, upload_to(&session.id, &[("new.txt", "new")])).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&app, finish(API_V1_ROUTES.publish.abort, &session.id)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(dir.join("old.txt").exists());
+        assert!(!dir.join("new.txt").exists());
+        let resp =
+            test::call_service(&app, finish(API_V1_ROUTES.publish.commit, &session.id)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // sidecars generated for the old contents go with them
+        let sidecar = settings
+            .files
+            .get_precompressed_path()
+            .join(&creds.username)
+            .join(TEST_DIR_NAME)
+            .join("old.txt.gz");
+        fs::create_dir_all(sidecar.parent().unwrap()).await.unwrap();
+        fs::write(&sidecar, b"old").await.unwrap();
+
+        let resp = test::call_service(&app, open_session()).await;
+        let session: SessionId = test::read_body_json(resp).await;
+        for files in [[("a.tar.gz", "a")], [("a.tar.gz.asc", "b")]] {
+            let resp = test::call_service(&app, upload_to(&session.id, &files)).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert!(!dir.join(files[0].0).exists());
+        }
+        let resp =
+            test::call_service(&app, finish(API_V1_ROUTES.publish.commit, &session.id)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let files: Vec<String> = test::read_body_json(resp).await;
+        assert_eq!(files.len(), 2);
+
+        assert!(!dir.join("old.txt").exists());
+        assert!(!sidecar.exists());
+        assert_eq!(fs::read(dir.join("a.tar.gz")).await.unwrap(), b"a");
+        let old = format!("{TEST_DIR_NAME}/old.txt");
+        assert!(ctx
+            .db
+            .get_file(&creds.username, &old)
+            .await
+            .unwrap()
+            .is_none());
+        let new = format!("{TEST_DIR_NAME}/a.tar.gz.asc");
+        assert!(ctx
+            .db
+            .get_file(&creds.username, &new)
+            .await
+            .unwrap()
+            .is_some());
+        assert!(!session_path(&ctx, &session.id).unwrap().exists());
+
+        // session ids must be UUIDs
+        let resp = test::call_service(&app, finish(API_V1_ROUTES.publish.abort, "../foo")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // server signatures are published with the files
+        let mut settings = settings.clone();
+        settings.signing = Some(crate::settings::Signing {
+            key_path: "/tmp/dumbserve-test-publish-signing.key".into(),
+        });
+        let ctx = AppCtx::new(crate::ctx::Ctx::new(&settings).await);
+        let app = test::init_service(
+            App::new()
+                .app_data(ctx.clone())
+                .configure(crate::routes::services),
+        )
+        .await;
+        let resp = test::call_service(&app, open_session()).await;
+        let session: SessionId = test::read_body_json(resp).await;
+        let resp = test::call_service(&app, upload_to(&session.id, &[("app.tar.gz", "app")])).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp =
+            test::call_service(&app, finish(API_V1_ROUTES.publish.commit, &session.id)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let mut files: Vec<String> = test::read_body_json(resp).await;
+        files.sort();
+        let expected: Vec<String> = [
+            "SHA256SUMS",
+            "SHA256SUMS.minisig",
+            "app.tar.gz",
+            "app.tar.gz.minisig",
+        ]
+        .iter()
+        .map(|name| format!("{TEST_DIR_NAME}/{name}"))
+        .collect();
+        assert_eq!(files, expected);
+        let key = crate::signatures::PublicKey::parse(&ctx.signer.as_ref().unwrap().public_key())
+            .unwrap();
+        let signature = fs::read(dir.join("app.tar.gz.minisig")).await.unwrap();
+        assert!(key.verify(
+            "app.tar.gz.minisig",
+            &signature,
+            &mut std::io::Cursor::new(b"app")
+        ));
+
+        fs::remove_dir_all(&dir).await.unwrap();
+        ctx.db
+            .delete_dir(&creds.username, TEST_DIR_NAME)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,642 @@
+/*
+ * Copyright (C) 2022  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Versioned releases of projects. Artifacts of a release live at
+//! `<owner>/<project>/<version>/` and `/<owner>/<project>/latest/<artifact>` redirects to the
+//! newest release of a channel.
+use actix_multipart::Multipart;
+use actix_web::http::header;
+use actix_web::HttpMessage;
+use actix_web::{web, Error, HttpRequest, HttpResponse, Responder};
+use actix_web_httpauth::middleware::HttpAuthentication;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use super::files::Staged;
+use super::httpauth;
+use super::SignedInUser;
+use super::API_V1_ROUTES;
+use crate::compression::remove_sidecars;
+use crate::db::{now_unix, Channel, Release};
+use crate::errors::*;
+use crate::manifest::*;
+use crate::pages::files::file_url;
+use crate::settings::clean_path;
+use crate::signing::sign_release;
+use crate::AppCtx;
+
+pub mod routes {
+    use super::*;
+    #[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+    pub struct Releases {
+        pub publish: &'static str,
+        pub list: &'static str,
+        pub delete: &'static str,
+        pub manifest: &'static str,
+        pub update_check: &'static str,
+        /// served outside of the API prefix, shadows directories named `latest` in projects
+        pub latest: &'static str,
+    }
+    impl Releases {
+        pub const fn new() -> Self {
+            Self {
+                publish: "/api/v1/releases/publish",
+                list: "/api/v1/releases",
+                delete: "/api/v1/releases/delete",
+                manifest: "/api/v1/releases/manifest",
+                update_check: "/api/v1/releases/update-check",
+                latest: "/{owner}/{project}/latest/{artifact}",
+            }
+        }
+    }
+}
+
+pub fn services(cfg: &mut web::ServiceConfig) {
+    cfg.service(publish);
+    cfg.service(list);
+    cfg.service(delete);
+    cfg.service(manifest);
+    cfg.service(update_check);
+    cfg.service(latest);
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Publish {
+    pub project: String,
+    pub version: String,
+    /// derived from version when absent, see [default_channel]
+    pub channel: Option<Channel>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Project {
+    pub owner: String,
+    pub project: String,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct ReleaseVersion {
+    pub project: String,
+    pub version: String,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct UpdateCheckQuery {
+    pub owner: String,
+    pub project: String,
+    /// version the client is running
+    pub current: String,
+    /// `<os>-<arch>` or Rust target triple
+    pub target: String,
+    /// stable by default
+    pub channel: Option<Channel>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct UpdateCheck {
+    pub update_available: bool,
+    /// newest version with an artifact for the requested target
+    pub version: Option<String>,
+    pub artifact: Option<Artifact>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct LatestQuery {
+    pub channel: Option<Channel>,
+}
+
+/// parse semver version. A leading `v`, as in git tags, is accepted.
+pub fn parse_version(version: &str) -> ServiceResult<Version> {
+    let version = version.strip_prefix('v').unwrap_or(version);
+    Version::parse(version).map_err(|_| ServiceError::InvalidVersion)
+}
+
+/// releases without pre-release identifiers are stable, `nightly` pre-releases are nightly and
+/// every other pre-release is beta
+pub fn default_channel(version: &Version) -> Channel {
+    if version.pre.is_empty() {
+        Channel::Stable
+    } else if version.pre.as_str().starts_with("nightly") {
+        Channel::Nightly
+    } else {
+        Channel::Beta
+    }
+}
+
+/// newest release available on `channel`. Channels also receive releases of more stable
+/// channels, so the beta channel resolves to a stable release when it is newer than the
+/// latest beta.
+pub fn latest_release(releases: &[Release], channel: Channel) -> Option<&Release> {
+    releases
+        .iter()
+        .filter(|r| r.channel <= channel)
+        .filter_map(|r| Version::parse(&r.version).ok().map(|v| (v, r)))
+        .max_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, r)| r)
+}
+
+/// projects are top-level directories in owner's tree
+fn check_project(project: &str) -> ServiceResult<()> {
+    if project.is_empty() || project.contains('/') || clean_path(project) != project {
+        Err(ServiceError::InvalidProjectName)
+    } else {
+        Ok(())
+    }
+}
+
+/// upload artifacts of a release. Republishing a version atomically replaces all of its
+/// artifacts.
+#[actix_web_codegen_const_routes::post(
+    path = "API_V1_ROUTES.releases.publish",
+    wrap = "HttpAuthentication::with_fn(httpauth)"
+)]
+async fn publish(
+    req: HttpRequest,
+    ctx: AppCtx,
+    query: web::Query<Publish>,
+    mut payload: Multipart,
+) -> Result<impl Responder, Error> {
+    let user = req.extensions().get::<SignedInUser>().unwrap().clone();
+    check_project(&query.project)?;
+    let version = parse_version(&query.version)?;
+    let channel = query.channel.unwrap_or_else(|| default_channel(&version));
+
+    let dir = format!("{}/{version}", query.project);
+    let staged = Staged::receive(&ctx, &mut payload).await?;
+    staged.verify(&ctx, &user.0, &dir).await?;
+
+    staged.replace(&ctx, &user.0, &dir).await?;
+    sign_release(&ctx, &user.0, &dir).await?;
+
+    let release = Release {
+        owner: user.0.clone(),
+        project: query.project.clone(),
+        version: version.to_string(),
+        channel,
+        published: now_unix(),
+        publisher: user.0,
+    };
+    ctx.db.upsert_release(&release).await?;
+    Ok(HttpResponse::Ok().json(release))
+}
+
+/// releases of a project, newest first
+#[actix_web_codegen_const_routes::get(path = "API_V1_ROUTES.releases.list")]
+async fn list(ctx: AppCtx, query: web::Query<Project>) -> ServiceResult<impl Responder> {
+    let mut releases = ctx.db.list_releases(&query.owner, &query.project).await?;
+    sort_releases(&mut releases);
+    Ok(HttpResponse::Ok().json(releases))
+}
+
+/// releases of a project with their artifacts, newest first
+#[actix_web_codegen_const_routes::get(path = "API_V1_ROUTES.releases.manifest")]
+async fn manifest(ctx: AppCtx, query: web::Query<Project>) -> ServiceResult<impl Responder> {
+    let query = query.into_inner();
+    let mut releases = ctx.db.list_releases(&query.owner, &query.project).await?;
+    sort_releases(&mut releases);
+    let mut manifests = Vec::with_capacity(releases.len());
+    for release in releases.iter() {
+        manifests.push(release_manifest(&ctx, release).await?);
+    }
+    Ok(HttpResponse::Ok().json(Manifest {
+        owner: query.owner,
+        project: query.project,
+        releases: manifests,
+    }))
+}
+
+#[actix_web_codegen_const_routes::get(path = "API_V1_ROUTES.releases.update_check")]
+async fn update_check(
+    ctx: AppCtx,
+    query: web::Query<UpdateCheckQuery>,
+) -> ServiceResult<impl Responder> {
+    let current = parse_version(&query.current)?;
+    let (os, arch) = parse_target(&query.target).ok_or(ServiceError::InvalidTarget)?;
+    let channel = query.channel.unwrap_or(Channel::Stable);
+
+    let mut releases = ctx.db.list_releases(&query.owner, &query.project).await?;
+    releases.retain(|r| r.channel <= channel);
+    sort_releases(&mut releases);
+    let mut manifests = Vec::with_capacity(releases.len());
+    for release in releases.iter() {
+        manifests.push(release_manifest(&ctx, release).await?);
+    }
+
+    let check = match find_update(&manifests, &current, &os, &arch) {
+        Some((release, artifact)) => UpdateCheck {
+            update_available: true,
+            version: Some(release.version.clone()),
+            artifact: Some(artifact.clone()),
+        },
+        None => UpdateCheck {
+            update_available: false,
+            version: None,
+            artifact: None,
+        },
+    };
+    Ok(HttpResponse::Ok().json(check))
+}
+
+#[actix_web_codegen_const_routes::delete(
+    path = "API_V1_ROUTES.releases.delete",
+    wrap = "HttpAuthentication::with_fn(httpauth)"
+)]
+async fn delete(
+    req: HttpRequest,
+    ctx: AppCtx,
+    payload: web::Json<ReleaseVersion>,
+) -> Result<impl Responder, Error> {
+    let user = req.extensions().get::<SignedInUser>().unwrap().clone();
+    check_project(&payload.project)?;
+    let version = parse_version(&payload.version)?.to_string();
+    ctx.db
+        .delete_release(&user.0, &payload.project, &version)
+        .await?;
+
+    let dir = format!("{}/{version}", payload.project);
+    let path = ctx.settings.files.get_path(&user.0, &dir);
+    if path.exists() {
+        fs::remove_dir_all(&path).await?;
+    }
+    remove_sidecars(&ctx.settings.files, &path).await;
+    ctx.db.delete_dir(&user.0, &dir).await?;
+    Ok(HttpResponse::Ok())
+}
+
+/// redirect to artifact of the newest release on the requested channel, stable by default
+#[actix_web_codegen_const_routes::get(path = "API_V1_ROUTES.releases.latest")]
+async fn latest(
+    ctx: AppCtx,
+    path: web::Path<(String, String, String)>,
+    query: web::Query<LatestQuery>,
+) -> ServiceResult<impl Responder> {
+    let (owner, project, artifact) = path.into_inner();
+    let releases = ctx.db.list_releases(&owner, &project).await?;
+    let channel = query.channel.unwrap_or(Channel::Stable);
+    let release = latest_release(&releases, channel).ok_or(ServiceError::ReleaseNotFound)?;
+
+    let file = format!("{project}/{}/{artifact}", release.version);
+    if ctx.db.get_file(&owner, &file).await?.is_none() {
+        return Err(ServiceError::ArtifactNotFound);
+    }
+    Ok(HttpResponse::Found()
+        .insert_header((
+            header::LOCATION,
+            file_url(ctx.settings.server.prefix(), &owner, &file),
+        ))
+        .insert_header(header::CacheControl(vec![header::CacheDirective::NoCache]))
+        .finish())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        test, App,
+    };
+
+    use super::*;
+    use crate::tests::{basic_auth, multipart};
+    use crate::*;
+
+    fn release(version: &str, channel: Channel) -> Release {
+        Release {
+            owner: "owner".into(),
+            project: "project".into(),
+            version: version.into(),
+            channel,
+            published: 0,
+            publisher: "owner".into(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn versions_work() {
+        let version = parse_version("v1.2.3").unwrap();
+        assert_eq!(version.to_string(), "1.2.3");
+        assert_eq!(default_channel(&version), Channel::Stable);
+        assert_eq!(
+            default_channel(&parse_version("1.3.0-rc.1").unwrap()),
+            Channel::Beta
+        );
+        assert_eq!(
+            default_channel(&parse_version("1.3.0-nightly.20221021").unwrap()),
+            Channel::Nightly
+        );
+        assert!(parse_version("latest").is_err());
+
+        let releases = [
+            release("1.10.0", Channel::Stable),
+            release("1.9.0", Channel::Stable),
+            release("1.11.0-beta.2", Channel::Beta),
+            release("1.11.0-beta.10", Channel::Beta),
+            release("1.12.0-nightly.1", Channel::Nightly),
+        ];
+        let resolve = |channel| latest_release(&releases, channel).unwrap().version.as_str();
+        assert_eq!(resolve(Channel::Stable), "1.10.0");
+        assert_eq!(resolve(Channel::Beta), "1.11.0-beta.10");
+        assert_eq!(resolve(Channel::Nightly), "1.12.0-nightly.1");
+        assert!(latest_release(&releases[2..3], Channel::Stable).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn releases_work() {
+        const PROJECT: &str = "test-releases_work";
+        const ARTIFACT: &str = "app.tar.gz";
+
+        let settings = Settings::new().unwrap();
+        let creds = settings.files.creds.first().unwrap().clone();
+        let owner = creds.username.clone();
+        let auth = basic_auth(&creds.username, &creds.password);
+        let ctx = AppCtx::new(crate::ctx::Ctx::new(&settings).await);
+        for r in ctx.db.list_releases(&owner, PROJECT).await.unwrap() {
+            ctx.db
+                .delete_release(&owner, PROJECT, &r.version)
+                .await
+                .unwrap();
+        }
+        let _ = fs::remove_dir_all(settings.files.get_path(&owner, PROJECT)).await;
+
+        let app = test::init_service(
+            App::new()
+                .app_data(ctx.clone())
+                .configure(crate::routes::services),
+        )
+        .await;
+
+        let (content_type, body) = multipart(&[(ARTIFACT, "foo"), ("app.tar.gz.sha256", "bar")]);
+        let publish_req = |version: &str| {
+            test::TestRequest::post()
+                .append_header((header::AUTHORIZATION, auth.clone()))
+                .append_header((header::CONTENT_TYPE, content_type.clone()))
+                .set_payload(body.clone())
+                .uri(&format!(
+                    "{}?project={PROJECT}&version={version}",
+                    API_V1_ROUTES.releases.publish
+                ))
+                .to_request()
+        };
+
+        for version in ["1.0.0", "v1.1.0-beta.1", "0.9.0", "1.0.0"] {
+            let resp = test::call_service(&app, publish_req(version)).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let resp = test::call_service(&app, publish_req("not-semver")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let path = format!("{PROJECT}/1.1.0-beta.1/{ARTIFACT}");
+        assert!(settings.files.get_path(&owner, &path).exists());
+        assert!(ctx.db.get_file(&owner, &path).await.unwrap().is_some());
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&format!(
+                    "{}?owner={owner}&project={PROJECT}",
+                    API_V1_ROUTES.releases.list
+                ))
+                .to_request(),
+        )
+        .await;
+        let releases: Vec<Release> = test::read_body_json(resp).await;
+        let versions: Vec<&str> = releases.iter().map(|r| r.version.as_str()).collect();
+        assert_eq!(versions, ["1.1.0-beta.1", "1.0.0", "0.9.0"]);
+        assert_eq!(releases[0].channel, Channel::Beta);
+
+        let latest_req = |query: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/{owner}/{PROJECT}/latest/{ARTIFACT}{query}"))
+                .to_request()
+        };
+        let resp = test::call_service(&app, latest_req("")).await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        assert_eq!(
+            resp.headers().get(header::LOCATION).unwrap(),
+            &format!("/{owner}/{PROJECT}/1.0.0/{ARTIFACT}")
+        );
+        let resp = test::call_service(&app, latest_req("?channel=beta")).await;
+        assert_eq!(
+            resp.headers().get(header::LOCATION).unwrap(),
+            &format!("/{owner}/{PROJECT}/1.1.0-beta.1/{ARTIFACT}")
+        );
+
+        // sidecars are deleted with their release
+        let sidecar = settings
+            .files
+            .get_precompressed_path()
+            .join(&owner)
+            .join(format!("{PROJECT}/1.0.0/{ARTIFACT}.gz"));
+        fs::create_dir_all(sidecar.parent().unwrap()).await.unwrap();
+        fs::write(&sidecar, b"sidecar").await.unwrap();
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::delete()
+                .append_header((header::AUTHORIZATION, auth.clone()))
+                .set_json(&ReleaseVersion {
+                    project: PROJECT.into(),
+                    version: "1.0.0".into(),
+                })
+                .uri(API_V1_ROUTES.releases.delete)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!settings
+            .files
+            .get_path(&owner, &format!("{PROJECT}/1.0.0"))
+            .exists());
+        assert!(!sidecar.exists());
+
+        let resp = test::call_service(&app, latest_req("")).await;
+        assert_eq!(
+            resp.headers().get(header::LOCATION).unwrap(),
+            &format!("/{owner}/{PROJECT}/0.9.0/{ARTIFACT}")
+        );
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&format!("/{owner}/{PROJECT}/latest/missing"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let (content_type, body) = multipart(&[
+            ("app-2.0.0-linux-amd64.tar.gz", "foo"),
+            ("app-2.0.0-linux-amd64.tar.gz.asc", "bar"),
+        ]);
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .append_header((header::AUTHORIZATION, auth.clone()))
+                .append_header((header::CONTENT_TYPE, content_type))
+                .set_payload(body)
+                .uri(&format!(
+                    "{}?project={PROJECT}&version=2.0.0",
+                    API_V1_ROUTES.releases.publish
+                ))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&format!(
+                    "{}?owner={owner}&project={PROJECT}",
+                    API_V1_ROUTES.releases.manifest
+                ))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let project_manifest: Manifest = test::read_body_json(resp).await;
+        assert_eq!(project_manifest.releases.len(), 3);
+        assert_eq!(project_manifest.releases[0].version, "2.0.0");
+        assert_eq!(project_manifest.releases[0].artifacts.len(), 1);
+
+        let check_req = |current: &str, target: &str| {
+            test::TestRequest::get()
+                .uri(&format!(
+                    "{}?owner={owner}&project={PROJECT}&current={current}&target={target}",
+                    API_V1_ROUTES.releases.update_check
+                ))
+                .to_request()
+        };
+        let resp = test::call_service(&app, check_req("0.9.0", "x86_64-unknown-linux-gnu")).await;
+        let check: UpdateCheck = test::read_body_json(resp).await;
+        assert!(check.update_available);
+        assert_eq!(check.version.as_deref(), Some("2.0.0"));
+        assert_eq!(check.artifact.unwrap().signatures.len(), 1);
+        let resp = test::call_service(&app, check_req("2.0.0", "linux-amd64")).await;
+        let check: UpdateCheck = test::read_body_json(resp).await;
+        assert!(!check.update_available);
+        let resp = test::call_service(&app, check_req("0.9.0", "amd64")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        for r in ctx.db.list_releases(&owner, PROJECT).await.unwrap() {
+            ctx.db
+                .delete_release(&owner, PROJECT, &r.version)
+                .await
+                .unwrap();
+        }
+        ctx.db.delete_dir(&owner, PROJECT).await.unwrap();
+        fs::remove_dir_all(settings.files.get_path(&owner, PROJECT))
+            .await
+            .unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn signed_releases_work() {
+        use crate::signatures::PublicKey;
+        use crate::signing::CHECKSUMS;
+
+        const PROJECT: &str = "test-signed_releases_work";
+
+        let mut settings = Settings::new().unwrap();
+        settings.signing = Some(crate::settings::Signing {
+            key_path: "/tmp/dumbserve-test-signing.key".into(),
+        });
+        let creds = settings.files.creds.first().unwrap().clone();
+        let owner = creds.username.clone();
+        let auth = basic_auth(&creds.username, &creds.password);
+        let ctx = AppCtx::new(crate::ctx::Ctx::new(&settings).await);
+        let app = test::init_service(
+            App::new()
+                .app_data(ctx.clone())
+                .configure(crate::routes::services),
+        )
+        .await;
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(API_V1_ROUTES.meta.signing_key)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let key = test::read_body(resp).await;
+        let key = PublicKey::parse(std::str::from_utf8(&key).unwrap()).unwrap();
+
+        let (content_type, body) = multipart(&[("app.tar.gz", "foo")]);
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .append_header((header::AUTHORIZATION, auth))
+                .append_header((header::CONTENT_TYPE, content_type))
+                .set_payload(body)
+                .uri(&format!(
+                    "{}?project={PROJECT}&version=1.0.0",
+                    API_V1_ROUTES.releases.publish
+                ))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let read = |name: &str| {
+            std::fs::read(
+                settings
+                    .files
+                    .get_path(&owner, &format!("{PROJECT}/1.0.0/{name}")),
+            )
+            .unwrap()
+        };
+        let signature = read("app.tar.gz.minisig");
+        assert!(key.verify(
+            "app.tar.gz.minisig",
+            &signature,
+            &mut std::io::Cursor::new(b"foo")
+        ));
+        let checksums = read(CHECKSUMS);
+        assert_eq!(
+            std::str::from_utf8(&checksums).unwrap(),
+            "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae  app.tar.gz\n"
+        );
+        let signature = read(&format!("{CHECKSUMS}.minisig"));
+        assert!(key.verify(
+            "SHA256SUMS.minisig",
+            &signature,
+            &mut std::io::Cursor::new(&checksums)
+        ));
+
+        let release = release_manifest(
+            &ctx,
+            &ctx.db.list_releases(&owner, PROJECT).await.unwrap()[0],
+        )
+        .await
+        .unwrap();
+        let artifact = release
+            .artifacts
+            .iter()
+            .find(|a| a.name == "app.tar.gz")
+            .unwrap();
+        assert_eq!(artifact.signatures.len(), 1);
+
+        ctx.db
+            .delete_release(&owner, PROJECT, "1.0.0")
+            .await
+            .unwrap();
+        ctx.db.delete_dir(&owner, PROJECT).await.unwrap();
+        fs::remove_dir_all(settings.files.get_path(&owner, PROJECT))
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,356 @@
+/*
+ * Copyright (C) 2022  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Streaming `.tar.gz` and `.zip` archives of directories. Archives are written on a blocking
+//! thread straight into the response body, nothing is staged on disk.
+use std::fs::{File, Metadata};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use actix_web::web::Bytes;
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::{Compression, Crc};
+use futures_util::Stream;
+use sqlx::types::time::OffsetDateTime;
+use tokio::sync::{mpsc, OwnedSemaphorePermit};
+
+use crate::index::modified;
+
+/// size of chunks sent to the client
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    TarGz,
+    Zip,
+}
+
+impl Format {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::TarGz => "tar.gz",
+            Self::Zip => "zip",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::TarGz => "application/gzip",
+            Self::Zip => "application/zip",
+        }
+    }
+
+    /// largest total file size the format can hold. Plain zip offsets are 32 bits wide.
+    pub fn max_size(&self) -> u64 {
+        match self {
+            Self::TarGz => u64::MAX,
+            Self::Zip => u32::MAX as u64,
+        }
+    }
+
+    /// largest number of files the format can hold
+    pub fn max_files(&self) -> usize {
+        match self {
+            Self::TarGz => usize::MAX,
+            Self::Zip => u16::MAX as usize,
+        }
+    }
+}
+
+/// stream archive of `files`, relative paths in `root` and their metadata as returned by
+/// [crate::index::walk]. The blocking thread writing the archive waits for slow clients, it
+/// holds `permit` until the archive is complete or the client is gone.
+pub fn stream(
+    root: PathBuf,
+    files: Vec<(String, Metadata)>,
+    format: Format,
+    permit: OwnedSemaphorePermit,
+) -> impl Stream<Item = io::Result<Bytes>> {
+    let (tx, rx) = mpsc::channel(4);
+    tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        let mut writer = ChannelWriter {
+            tx: tx.clone(),
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        };
+        let res = match format {
+            Format::TarGz => write_tar_gz(&root, &files, &mut writer),
+            Format::Zip => write_zip(&root, &files, &mut writer),
+        }
+        .and_then(|_| writer.flush());
+        if let Err(e) = res {
+            // the response has started, aborting it is the only way to signal errors
+            log::warn!("Unable to stream archive of {:?}: {e}", root);
+            let _ = tx.blocking_send(Err(e));
+        }
+    });
+    futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    })
+}
+
+/// sends written data to the response stream in chunks. Fails once the client is gone.
+struct ChannelWriter {
+    tx: mpsc::Sender<io::Result<Bytes>>,
+    buf: Vec<u8>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE));
+        self.tx
+            .blocking_send(Ok(chunk.into()))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "client disconnected"))
+    }
+}
+
+/// reads exactly `remaining` bytes, files changed after they were listed would otherwise
+/// produce corrupt archives
+struct Exact<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: Read> Exact<R> {
+    fn new(inner: R, size: u64) -> Self {
+        Self {
+            inner,
+            remaining: size,
+        }
+    }
+}
+
+impl<R: Read> Read for Exact<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+        let len = buf.len().min(self.remaining as usize);
+        let n = self.inner.read(&mut buf[..len])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+fn write_tar_gz(root: &Path, files: &[(String, Metadata)], writer: impl Write) -> io::Result<()> {
+    let mut builder = tar::Builder::new(GzEncoder::new(writer, Compression::default()));
+    for (name, metadata) in files.iter() {
+        let file = File::open(root.join(name))?;
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata(metadata);
+        builder.append_data(&mut header, name, Exact::new(file, metadata.len()))?;
+    }
+    builder.into_inner()?.finish()?;
+    Ok(())
+}
+
+/// counts bytes written, for zip offsets
+struct Counter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for Counter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(data)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct ZipEntry<'a> {
+    name: &'a str,
+    crc: u32,
+    compressed_size: u32,
+    size: u32,
+    offset: u32,
+    time: u16,
+    date: u16,
+}
+
+/// `size` as 32 bit zip field
+fn zip_u32(size: u64) -> io::Result<u32> {
+    u32::try_from(size).map_err(|_| io::Error::other("zip exceeds 4 GiB"))
+}
+
+/// MS-DOS time and date of UNIX timestamp `unix`
+fn dos_time(unix: i64) -> (u16, u16) {
+    let t = OffsetDateTime::from_unix_timestamp(unix);
+    if t.year() < 1980 {
+        return (0, 0x21);
+    }
+    let time = (t.hour() as u16) << 11 | (t.minute() as u16) << 5 | ((t.second() as u16) / 2);
+    let date = ((t.year() - 1980) as u16) << 9 | (t.month() as u16) << 5 | (t.day() as u16);
+    (time, date)
+}
+
+/// zip with deflated entries. Sizes and checksums follow each entry in data descriptors, so
+/// the output never has to be seeked.
+fn write_zip(root: &Path, files: &[(String, Metadata)], writer: impl Write) -> io::Result<()> {
+    // data descriptors and UTF-8 names
+    const FLAGS: u16 = 1 << 3 | 1 << 11;
+    const DEFLATE: u16 = 8;
+    const VERSION: u16 = 20;
+    const UNIX: u16 = 3 << 8;
+
+    let mut w = Counter {
+        inner: writer,
+        count: 0,
+    };
+    let mut entries = Vec::with_capacity(files.len());
+    let mut buf = vec![0; CHUNK_SIZE];
+    for (name, metadata) in files.iter() {
+        let (time, date) = dos_time(modified(metadata));
+        let offset = zip_u32(w.count)?;
+        w.write_all(&0x04034b50u32.to_le_bytes())?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&FLAGS.to_le_bytes())?;
+        w.write_all(&DEFLATE.to_le_bytes())?;
+        w.write_all(&time.to_le_bytes())?;
+        w.write_all(&date.to_le_bytes())?;
+        // checksum and sizes are in the data descriptor
+        w.write_all(&[0; 12])?;
+        w.write_all(&(name.len() as u16).to_le_bytes())?;
+        w.write_all(&0u16.to_le_bytes())?;
+        w.write_all(name.as_bytes())?;
+
+        let start = w.count;
+        let mut crc = Crc::new();
+        let mut file = Exact::new(File::open(root.join(name))?, metadata.len());
+        let mut encoder = DeflateEncoder::new(&mut w, Compression::default());
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            crc.update(&buf[..n]);
+            encoder.write_all(&buf[..n])?;
+        }
+        encoder.finish()?;
+
+        let entry = ZipEntry {
+            name,
+            crc: crc.sum(),
+            compressed_size: zip_u32(w.count - start)?,
+            size: zip_u32(metadata.len())?,
+            offset,
+            time,
+            date,
+        };
+        w.write_all(&0x08074b50u32.to_le_bytes())?;
+        w.write_all(&entry.crc.to_le_bytes())?;
+        w.write_all(&entry.compressed_size.to_le_bytes())?;
+        w.write_all(&entry.size.to_le_bytes())?;
+        entries.push(entry);
+    }
+
+    let directory_offset = zip_u32(w.count)?;
+    for entry in entries.iter() {
+        w.write_all(&0x02014b50u32.to_le_bytes())?;
+        w.write_all(&(UNIX | VERSION).to_le_bytes())?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&FLAGS.to_le_bytes())?;
+        w.write_all(&DEFLATE.to_le_bytes())?;
+        w.write_all(&entry.time.to_le_bytes())?;
+        w.write_all(&entry.date.to_le_bytes())?;
+        w.write_all(&entry.crc.to_le_bytes())?;
+        w.write_all(&entry.compressed_size.to_le_bytes())?;
+        w.write_all(&entry.size.to_le_bytes())?;
+        w.write_all(&(entry.name.len() as u16).to_le_bytes())?;
+        // extra field and comment lengths, disk number, internal attributes
+        w.write_all(&[0; 8])?;
+        // regular file, rw-r--r--
+        w.write_all(&(0o100644u32 << 16).to_le_bytes())?;
+        w.write_all(&entry.offset.to_le_bytes())?;
+        w.write_all(entry.name.as_bytes())?;
+    }
+    let directory_size = zip_u32(w.count)? - directory_offset;
+
+    w.write_all(&0x06054b50u32.to_le_bytes())?;
+    // disk numbers
+    w.write_all(&[0; 4])?;
+    w.write_all(&(entries.len() as u16).to_le_bytes())?;
+    w.write_all(&(entries.len() as u16).to_le_bytes())?;
+    w.write_all(&directory_size.to_le_bytes())?;
+    w.write_all(&directory_offset.to_le_bytes())?;
+    // comment length
+    w.write_all(&0u16.to_le_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn archives_work() {
+        let root = std::env::temp_dir().join("dumbserve-archives_work");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("css")).unwrap();
+        std::fs::write(root.join("index.html"), "foo").unwrap();
+        std::fs::write(root.join("css/main.css"), "bar".repeat(1000)).unwrap();
+        let files = crate::index::walk(&root).await;
+
+        let mut zip = Vec::new();
+        write_zip(&root, &files, &mut zip).unwrap();
+        let mut zip = zip::ZipArchive::new(Cursor::new(zip)).unwrap();
+        assert_eq!(zip.len(), 2);
+        let mut css = String::new();
+        zip.by_name("css/main.css")
+            .unwrap()
+            .read_to_string(&mut css)
+            .unwrap();
+        assert_eq!(css, "bar".repeat(1000));
+
+        let mut tar_gz = Vec::new();
+        write_tar_gz(&root, &files, &mut tar_gz).unwrap();
+        let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(&tar_gz[..]));
+        let mut names: Vec<String> = tar
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_str().unwrap().to_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["css/main.css", "index.html"]);
+
+        // files changed after listing are caught
+        std::fs::write(root.join("index.html"), "f").unwrap();
+        assert!(write_zip(&root, &files, &mut Vec::new()).is_err());
+        assert!(write_tar_gz(&root, &files, &mut Vec::new()).is_err());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
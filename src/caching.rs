@@ -0,0 +1,159 @@
+/*
+ * Copyright (C) 2022  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Caching of public files: `Cache-Control` from rules in `files.cache` and strong ETags
+//! derived from digests in the file index
+use actix_web::http::header::{ContentEncoding, EntityTag, IfNoneMatch};
+use actix_web::{HttpMessage, HttpRequest};
+use glob::{MatchOptions, Pattern};
+
+use crate::settings::CacheRule;
+use crate::CACHE_AGE;
+
+const OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// compiled `files.cache` rules
+pub struct CachePolicy {
+    rules: Vec<(Option<Pattern>, CacheRule)>,
+}
+
+impl CachePolicy {
+    pub fn new(rules: &[CacheRule]) -> Result<Self, String> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let glob = match &rule.glob {
+                    Some(glob) => Some(
+                        Pattern::new(glob)
+                            .map_err(|e| format!("Invalid cache glob {glob}: {e}"))?,
+                    ),
+                    None => None,
+                };
+                Ok((glob, rule.clone()))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self { rules })
+    }
+
+    /// `Cache-Control` value of file `path`, relative to `files.path`, of `content_type`
+    pub fn cache_control(&self, path: &str, content_type: &str) -> Option<String> {
+        let (_, rule) = self.rules.iter().find(|(glob, rule)| {
+            let path_matches = match glob {
+                Some(glob) => glob.matches_with(path, OPTIONS),
+                None => true,
+            };
+            let type_matches = match &rule.content_type {
+                Some(prefix) => content_type.starts_with(prefix.as_str()),
+                None => true,
+            };
+            path_matches && type_matches
+        })?;
+
+        let mut value = format!("public, max-age={}", rule.max_age.unwrap_or(CACHE_AGE));
+        if rule.no_cache {
+            value.push_str(", no-cache");
+        }
+        if rule.immutable {
+            value.push_str(", immutable");
+        }
+        Some(value)
+    }
+}
+
+/// strong ETag of a file with SHA-256 `digest`, served with `encoding`
+pub fn etag(digest: &str, encoding: Option<ContentEncoding>) -> EntityTag {
+    match encoding {
+        Some(encoding) => EntityTag::new_strong(format!("{digest}-{}", encoding.as_str())),
+        None => EntityTag::new_strong(digest.to_owned()),
+    }
+}
+
+/// `If-None-Match` of `req` matches `etag`, the client's copy is fresh
+pub fn is_fresh(req: &HttpRequest, etag: &EntityTag) -> bool {
+    match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(items)) => items.iter().any(|item| item.weak_eq(etag)),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use actix_web::http::header;
+    use actix_web::test;
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn caching_works() {
+        let rule = |glob: Option<&str>, content_type: Option<&str>| CacheRule {
+            glob: glob.map(Into::into),
+            content_type: content_type.map(Into::into),
+            max_age: None,
+            immutable: false,
+            no_cache: false,
+        };
+        let policy = CachePolicy::new(&[
+            CacheRule {
+                max_age: Some(31536000),
+                immutable: true,
+                ..rule(Some("*/*/[0-9]*/*"), None)
+            },
+            CacheRule {
+                no_cache: true,
+                ..rule(None, Some("text/html"))
+            },
+            rule(Some("*/docs/**"), None),
+        ])
+        .unwrap();
+        assert_eq!(
+            policy
+                .cache_control("owner/app/1.0.0/app.tar.gz", "application/gzip")
+                .unwrap(),
+            "public, max-age=31536000, immutable"
+        );
+        assert_eq!(
+            policy.cache_control("owner/app/latest/index.html", "text/html"),
+            Some("public, max-age=604800, no-cache".into())
+        );
+        assert_eq!(
+            policy.cache_control("owner/docs/guide/intro.txt", "text/plain"),
+            Some("public, max-age=604800".into())
+        );
+        assert_eq!(policy.cache_control("owner/notes.txt", "text/plain"), None);
+        assert!(CachePolicy::new(&[rule(Some("[a"), None)]).is_err());
+
+        let etag = etag("digest", Some(ContentEncoding::Brotli));
+        assert_eq!(etag.to_string(), "\"digest-br\"");
+        let req = |value: &str| {
+            test::TestRequest::get()
+                .insert_header((header::IF_NONE_MATCH, value))
+                .to_http_request()
+        };
+        assert!(is_fresh(&req("\"digest-br\""), &etag));
+        assert!(is_fresh(&req("W/\"other\", \"digest-br\""), &etag));
+        assert!(is_fresh(&req("*"), &etag));
+        assert!(!is_fresh(&req("\"digest\""), &etag));
+        assert!(!is_fresh(
+            &test::TestRequest::get().to_http_request(),
+            &etag
+        ));
+    }
+}
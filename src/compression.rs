@@ -0,0 +1,275 @@
+/*
+ * Copyright (C) 2022  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Precompressed sidecars. `<file>.br`, `<file>.zst` and `<file>.gz` are served instead of
+//! `<file>` to clients accepting the encoding. Sidecars are looked up next to the file and in
+//! the precompressed directory, where they are generated in the background for compressible
+//! uploads when `files.precompress` is enabled. Sidecars older than the file are ignored.
+use std::fs::{self, File};
+use std::io::{self, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use actix_web::http::header::ContentEncoding;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use tokio::sync::Semaphore;
+
+use crate::settings::Files;
+
+/// encodings in order of preference and extensions of their sidecars
+pub const ENCODINGS: [(ContentEncoding, &str); 3] = [
+    (ContentEncoding::Brotli, "br"),
+    (ContentEncoding::Zstd, "zst"),
+    (ContentEncoding::Gzip, "gz"),
+];
+
+/// smaller files aren't worth precompressing
+pub const MIN_SIZE: u64 = 1024;
+
+/// sidecars generated concurrently, later uploads wait for a permit
+pub const MAX_JOBS: usize = 2;
+
+/// content types that are compressed already, or opaque binaries, and gain nothing from
+/// dynamic compression
+const COMPRESSED_TYPES: [&str; 18] = [
+    "application/octet-stream",
+    "application/gzip",
+    "application/x-gzip",
+    "application/zip",
+    "application/zstd",
+    "application/x-xz",
+    "application/x-bzip2",
+    "application/x-7z-compressed",
+    "application/vnd.rar",
+    "application/x-rar-compressed",
+    "application/java-archive",
+    "application/vnd.debian.binary-package",
+    "application/x-rpm",
+    "application/x-apple-diskimage",
+    "application/vnd.android.package-archive",
+    "application/pdf",
+    "font/woff",
+    "font/woff2",
+];
+
+/// `content_type` is worth compressing
+pub fn is_compressible(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    if essence == "image/svg+xml" {
+        return true;
+    }
+    let media = essence.split('/').next().unwrap_or_default();
+    !(COMPRESSED_TYPES.contains(&essence) || matches!(media, "image" | "audio" | "video"))
+}
+
+/// `accept_encoding`, an `Accept-Encoding` header value, accepts `coding`
+fn accepts(accept_encoding: &str, coding: &str) -> bool {
+    let mut wildcard = false;
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let q = params
+            .find_map(|p| p.trim().strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if name.eq_ignore_ascii_case(coding) {
+            return q > 0.0;
+        }
+        if name == "*" {
+            wildcard = q > 0.0;
+        }
+    }
+    wildcard
+}
+
+fn with_extension(path: &Path, ext: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(ext);
+    path.into()
+}
+
+/// base path of generated sidecars of `path`, which is in `files.path`
+fn cache_path(files: &Files, path: &Path) -> Option<PathBuf> {
+    let rel = path.strip_prefix(&files.path).ok()?;
+    Some(files.get_precompressed_path().join(rel))
+}
+
+/// best sidecar of `path` for a client sending `accept_encoding`
+pub fn find_sidecar(
+    files: &Files,
+    path: &Path,
+    accept_encoding: &str,
+) -> Option<(PathBuf, ContentEncoding)> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    let cached = cache_path(files, path);
+    for (encoding, ext) in ENCODINGS.iter() {
+        if !accepts(accept_encoding, encoding.as_str()) {
+            continue;
+        }
+        let candidates = [Some(path), cached.as_deref()];
+        for base in candidates.iter().flatten() {
+            let sidecar = with_extension(base, ext);
+            let fresh = match fs::metadata(&sidecar) {
+                Ok(m) if m.is_file() => matches!(m.modified(), Ok(m) if m >= modified),
+                _ => false,
+            };
+            if fresh {
+                return Some((sidecar, *encoding));
+            }
+        }
+    }
+    None
+}
+
+/// write compressed output of `write` to `target` if it is smaller than `size`
+fn write_sidecar(
+    target: &Path,
+    size: u64,
+    write: impl FnOnce(&mut File) -> io::Result<()>,
+) -> io::Result<()> {
+    let tmp = with_extension(target, "tmp");
+    let mut f = File::create(&tmp)?;
+    write(&mut f)?;
+    f.flush()?;
+    if f.metadata()?.len() < size {
+        fs::rename(&tmp, target)
+    } else {
+        fs::remove_file(&tmp)
+    }
+}
+
+/// generate sidecars of `path`, which is in `files.path`. Blocking.
+pub fn precompress(files: &Files, path: &Path) -> io::Result<()> {
+    let base = cache_path(files, path)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path outside files"))?;
+    if let Some(parent) = base.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let size = fs::metadata(path)?.len();
+    let open = || File::open(path).map(BufReader::new);
+
+    write_sidecar(&with_extension(&base, "gz"), size, |f| {
+        let mut encoder = GzEncoder::new(f, Compression::best());
+        io::copy(&mut open()?, &mut encoder)?;
+        encoder.finish().map(|_| ())
+    })?;
+    write_sidecar(&with_extension(&base, "zst"), size, |f| {
+        zstd::stream::copy_encode(open()?, f, 19)
+    })?;
+    write_sidecar(&with_extension(&base, "br"), size, |f| {
+        let mut encoder = brotli::CompressorWriter::new(f, 4096, 11, 22);
+        io::copy(&mut open()?, &mut encoder)?;
+        encoder.flush()
+    })
+}
+
+/// generate sidecars of `path` in the background when enabled and worthwhile. At most
+/// [MAX_JOBS] run at once, holding permits of `jobs`
+pub fn spawn_precompress(
+    files: &Files,
+    jobs: &Arc<Semaphore>,
+    path: PathBuf,
+    content_type: &str,
+    size: u64,
+) {
+    if !files.precompress || size < MIN_SIZE || !is_compressible(content_type) {
+        return;
+    }
+    let files = files.clone();
+    let jobs = jobs.clone();
+    tokio::spawn(async move {
+        let permit = match jobs.acquire_owned().await {
+            Ok(permit) => permit,
+            Err(_) => return,
+        };
+        let _ = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            if let Err(e) = precompress(&files, &path) {
+                log::warn!("Unable to precompress {:?}: {e}", path);
+            }
+        })
+        .await;
+    });
+}
+
+/// remove generated sidecars of `path`, a file or directory in `files.path`
+pub async fn remove_sidecars(files: &Files, path: &Path) {
+    let base = match cache_path(files, path) {
+        Some(base) => base,
+        None => return,
+    };
+    let _ = tokio::fs::remove_dir_all(&base).await;
+    for (_, ext) in ENCODINGS.iter() {
+        let _ = tokio::fs::remove_file(with_extension(&base, ext)).await;
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::settings::Settings;
+
+    #[actix_rt::test]
+    async fn sidecars_work() {
+        assert!(is_compressible("text/html; charset=utf-8"));
+        assert!(is_compressible("image/svg+xml"));
+        assert!(!is_compressible("application/octet-stream"));
+        assert!(!is_compressible("application/gzip"));
+        assert!(!is_compressible("image/png"));
+
+        assert!(accepts("gzip, deflate, br", "br"));
+        assert!(accepts("*", "zstd"));
+        assert!(!accepts("gzip;q=0, *", "gzip"));
+        assert!(!accepts("identity", "gzip"));
+
+        let settings = Settings::new().unwrap();
+        let path = settings
+            .files
+            .get_path("test-sidecars_work", "docs/index.html");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "foo".repeat(1000)).unwrap();
+        assert_eq!(find_sidecar(&settings.files, &path, "gzip, br"), None);
+
+        precompress(&settings.files, &path).unwrap();
+        let (sidecar, encoding) = find_sidecar(&settings.files, &path, "gzip, br").unwrap();
+        assert_eq!(encoding, ContentEncoding::Brotli);
+        assert!(sidecar.starts_with(settings.files.get_precompressed_path()));
+        let (sidecar, encoding) = find_sidecar(&settings.files, &path, "gzip").unwrap();
+        assert_eq!(encoding, ContentEncoding::Gzip);
+        let mut contents = String::new();
+        flate2::read::GzDecoder::new(File::open(sidecar).unwrap())
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "foo".repeat(1000));
+
+        // sidecars next to the file are preferred
+        let adjacent = with_extension(&path, "gz");
+        fs::write(&adjacent, "adjacent").unwrap();
+        assert_eq!(
+            find_sidecar(&settings.files, &path, "gzip"),
+            Some((adjacent, ContentEncoding::Gzip))
+        );
+
+        let dir = settings.files.get_path("test-sidecars_work", "");
+        remove_sidecars(&settings.files, &dir).await;
+        assert!(!cache_path(&settings.files, &dir).unwrap().exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -18,17 +18,43 @@ use std::sync::Arc;
 use std::thread;
 
 use argon2_creds::{Config, ConfigBuilder, PasswordPolicy};
+use sha2::{Digest, Sha256};
+use tera::Tera;
+use tokio::sync::Semaphore;
 
-//use crate::errors::ServiceResult;
+use crate::caching::CachePolicy;
+use crate::db::Db;
+use crate::errors::ServiceResult;
+use crate::headers::HeaderRules;
+use crate::proxy::TrustedProxies;
 use crate::settings::Settings;
+use crate::signatures::Keyring;
+use crate::signing::Signer;
+use crate::tls::ClientCertificate;
 /// App data
 pub struct Ctx {
-    //    /// database ops defined by db crates
-    //    pub db: BoxDB,
+    /// database ops
+    pub db: Db,
     /// credential management configuration
     pub creds: Config,
     /// app settings
     pub settings: Settings,
+    /// keys uploads are verified against
+    pub keyring: Keyring,
+    /// server-side signing key
+    pub signer: Option<Signer>,
+    /// templates of public pages, with overrides from settings
+    pub templates: Tera,
+    /// `Cache-Control` rules of public files
+    pub cache_policy: CachePolicy,
+    /// response header rules
+    pub header_rules: HeaderRules,
+    /// reverse proxies whose forwarding headers are honored
+    pub trusted_proxies: TrustedProxies,
+    /// permits of directory downloads, see [crate::archive::stream]
+    pub archive_streams: Arc<Semaphore>,
+    /// permits of sidecar generation, see [crate::compression::spawn_precompress]
+    pub precompress_jobs: Arc<Semaphore>,
     pub source_code: String,
 }
 
@@ -56,10 +82,7 @@ impl Ctx {
             log::info!("Initialized credential manager");
         });
 
-        //let db = match s.database.database_type {
-        //    crate::settings::DBType::Maria => db::maria::get_data(Some(s.clone())).await,
-        //    crate::settings::DBType::Postgres => db::pg::get_data(Some(s.clone())).await,
-        //};
+        let db = Db::new(s).await;
 
         #[cfg(not(debug_assertions))]
         init.join().unwrap();
@@ -75,15 +98,124 @@ impl Ctx {
             base.into()
         };
 
+        let keyring = Keyring::new(&s.files.trusted_keys).unwrap_or_else(|e| panic!("{e}"));
+        let signer = s.signing.as_ref().map(|signing| {
+            Signer::load_or_generate(
+                std::path::Path::new(&signing.key_path),
+                std::path::Path::new(&s.files.path),
+            )
+            .expect("Unable to load signing key")
+        });
+
+        let templates = crate::pages::load_templates(s).unwrap_or_else(|e| panic!("{e}"));
+        let cache_policy = CachePolicy::new(&s.files.cache).unwrap_or_else(|e| panic!("{e}"));
+        let header_rules = HeaderRules::new(&s.server.headers).unwrap_or_else(|e| panic!("{e}"));
+        let trusted_proxies =
+            TrustedProxies::new(&s.server.trusted_proxies).unwrap_or_else(|e| panic!("{e}"));
+
         let data = Ctx {
             creds,
-            //   db,
+            db,
             settings: s.clone(),
+            keyring,
+            signer,
+            templates,
+            cache_policy,
+            header_rules,
+            trusted_proxies,
+            archive_streams: Arc::new(Semaphore::new(s.files.download.max_streams)),
+            precompress_jobs: Arc::new(Semaphore::new(crate::compression::MAX_JOBS)),
             source_code,
         };
 
         Arc::new(data)
     }
+
+    /// check credentials against static credentials from settings and user accounts stored
+    /// in the database. Disabled accounts can't sign in.
+    pub async fn authenticate(&self, username: &str, password: &str) -> bool {
+        if self.settings.files.authenticate(username, password) {
+            return true;
+        }
+
+        match self.db.get_user(username).await {
+            Ok(user) if !user.disabled => Config::verify(&user.password, password).unwrap_or(false),
+            _ => false,
+        }
+    }
+
+    /// user verified client certificate `cert` is mapped to by `server.tls.clients`
+    pub fn client_user(&self, cert: &ClientCertificate) -> Option<String> {
+        let tls = self.settings.server.tls.as_ref()?;
+        cert.username(&tls.clients).map(Into::into)
+    }
+
+    /// check if user exists and is allowed to sign in
+    pub async fn is_active(&self, username: &str) -> bool {
+        self.session_generation(username).await.is_some()
+    }
+
+    /// generation of `username`'s browser sessions, derived from their password (hash). Changing
+    /// the password, deleting or disabling the account ends existing sessions, accounts
+    /// registered again under the same name don't inherit them. `None` when the user can't sign
+    /// in.
+    async fn session_generation(&self, username: &str) -> Option<String> {
+        let password = match self
+            .settings
+            .files
+            .creds
+            .iter()
+            .find(|c| c.username == username)
+        {
+            Some(creds) => creds.password.clone(),
+            None => match self.db.get_user(username).await {
+                Ok(user) if !user.disabled => user.password,
+                _ => return None,
+            },
+        };
+        let mut hasher = Sha256::new();
+        hasher.update(self.settings.server.cookie_secret.as_bytes());
+        hasher.update(password.as_bytes());
+        Some(hex::encode(&hasher.finalize()[..16]))
+    }
+
+    /// identity remembered in the session cookie of `username`
+    pub async fn session_identity(&self, username: &str) -> Option<String> {
+        let generation = self.session_generation(username).await?;
+        Some(format!("{username}:{generation}"))
+    }
+
+    /// user signed in with session cookie `identity`, if the session is still valid
+    pub async fn session_user(&self, identity: &str) -> Option<String> {
+        let (username, generation) = identity.rsplit_once(':')?;
+        if self.session_generation(username).await? == generation {
+            Some(username.into())
+        } else {
+            None
+        }
+    }
+
+    /// delete user account and all files uploaded by the user
+    pub async fn delete_user(&self, username: &str) -> ServiceResult<()> {
+        self.db.delete_user(username).await?;
+        self.db.delete_dir(username, "").await?;
+        self.db.delete_releases(username).await?;
+        let path = self.settings.files.get_path(username, "");
+        if path.exists() {
+            tokio::fs::remove_dir_all(&path).await?;
+        }
+        crate::compression::remove_sidecars(&self.settings.files, &path).await;
+        Ok(())
+    }
+
+    /// users configured in settings are administrators
+    pub fn is_admin(&self, username: &str) -> bool {
+        self.settings
+            .files
+            .creds
+            .iter()
+            .any(|c| c.username == username)
+    }
 }
 
 pub type ArcCtx = Arc<Ctx>;
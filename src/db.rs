@@ -0,0 +1,613 @@
+/*
+ * Copyright (C) 2022  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Persistent storage: user accounts, the index of stored files and releases. Works with both SQLite and
+//! PostgreSQL through sqlx's `Any` driver, so queries in this module must stick to SQL that both
+//! databases understand.
+use futures_util::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::any::{AnyPool, AnyPoolOptions};
+use sqlx::types::time::OffsetDateTime;
+use sqlx::Row;
+
+use crate::errors::*;
+use crate::settings::{DBType, Settings};
+
+#[derive(Clone)]
+pub struct Db {
+    pub pool: AnyPool,
+    pub database_type: DBType,
+}
+
+/// User account stored in the database
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct User {
+    pub name: String,
+    pub email: Option<String>,
+    /// password hash
+    #[serde(skip)]
+    pub password: String,
+    pub disabled: bool,
+    /// UNIX timestamp of account creation
+    pub created: i64,
+}
+
+/// Metadata of a stored file
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct FileInfo {
+    /// user whose tree contains the file
+    pub owner: String,
+    /// `/` separated path relative to owner's tree
+    pub path: String,
+    pub size: i64,
+    /// hex encoded SHA-256 digest
+    pub digest: String,
+    pub content_type: String,
+    /// UNIX timestamp of last modification on disk
+    pub modified: i64,
+    /// UNIX timestamp of upload
+    pub uploaded: i64,
+    pub uploader: String,
+}
+
+/// Column to sort search results by
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortBy {
+    Path,
+    Size,
+    Modified,
+    Uploaded,
+}
+
+/// Filters applied by the database when searching the index
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FileFilter {
+    pub owner: Option<String>,
+    /// case-insensitive substring of path
+    pub contains: Option<String>,
+    /// path prefix, "docs/" matches files in directory `docs`
+    pub path_prefix: Option<String>,
+    /// content type prefix, "image/" matches all images
+    pub content_type: Option<String>,
+    pub min_size: Option<i64>,
+    pub max_size: Option<i64>,
+    pub modified_after: Option<i64>,
+    pub modified_before: Option<i64>,
+    pub sort: Option<SortBy>,
+    pub descending: bool,
+    /// leave out files with a dot-prefixed path component, which the public file service
+    /// doesn't serve
+    pub exclude_hidden: bool,
+}
+
+/// most rows [Db::search_files] checks with its `matches` filter
+pub const SEARCH_MAX_SCANNED: usize = 10_000;
+
+/// Release channel. Channels are ordered from most to least stable.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    Stable,
+    Beta,
+    Nightly,
+}
+
+impl Channel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Channel::Stable => "stable",
+            Channel::Beta => "beta",
+            Channel::Nightly => "nightly",
+        }
+    }
+
+    pub fn parse(channel: &str) -> Option<Self> {
+        match channel {
+            "stable" => Some(Channel::Stable),
+            "beta" => Some(Channel::Beta),
+            "nightly" => Some(Channel::Nightly),
+            _ => None,
+        }
+    }
+}
+
+/// Published release of a project. Artifacts are stored at `<owner>/<project>/<version>/`.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Release {
+    pub owner: String,
+    pub project: String,
+    /// semver version
+    pub version: String,
+    pub channel: Channel,
+    /// UNIX timestamp of publication
+    pub published: i64,
+    pub publisher: String,
+}
+
+/// Data required to create a new user account
+pub struct AddUser<'a> {
+    pub name: &'a str,
+    pub email: Option<&'a str>,
+    /// password hash
+    pub password: &'a str,
+}
+
+pub fn now_unix() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
+
+impl Db {
+    #[cfg(not(tarpaulin_include))]
+    pub async fn new(s: &Settings) -> Self {
+        let pool = AnyPoolOptions::new()
+            .max_connections(s.database.pool)
+            .connect(&s.database.url)
+            .await
+            .expect("Unable to connect to database");
+        let db = Self {
+            pool,
+            database_type: s.database.database_type.clone(),
+        };
+        db.migrate().await;
+        db
+    }
+
+    #[cfg(not(tarpaulin_include))]
+    pub async fn migrate(&self) {
+        sqlx::migrate!("./migrations/")
+            .run(&self.pool)
+            .await
+            .expect("Unable to run database migrations");
+    }
+
+    /// check database connectivity
+    pub async fn ping(&self) -> bool {
+        sqlx::query("SELECT 1").execute(&self.pool).await.is_ok()
+    }
+
+    /// create new user account
+    pub async fn add_user(&self, p: &AddUser<'_>) -> ServiceResult<()> {
+        sqlx::query(
+            "INSERT INTO dumbserve_users (name, email, password, disabled, created)
+            VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(p.name)
+        .bind(p.email)
+        .bind(p.password)
+        .bind(false)
+        .bind(now_unix())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// check if username is taken
+    pub async fn username_exists(&self, name: &str) -> ServiceResult<bool> {
+        let res = sqlx::query("SELECT name FROM dumbserve_users WHERE name = $1")
+            .bind(name)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(res.is_some())
+    }
+
+    /// check if email is taken
+    pub async fn email_exists(&self, email: &str) -> ServiceResult<bool> {
+        let res = sqlx::query("SELECT name FROM dumbserve_users WHERE email = $1")
+            .bind(email)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(res.is_some())
+    }
+
+    /// get user account
+    pub async fn get_user(&self, name: &str) -> ServiceResult<User> {
+        let row = sqlx::query(
+            "SELECT name, email, password, disabled, created FROM dumbserve_users WHERE name = $1",
+        )
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .await?;
+        row.as_ref()
+            .map(Self::user_from_row)
+            .ok_or(ServiceError::AccountNotFound)
+    }
+
+    /// list all user accounts
+    pub async fn list_users(&self) -> ServiceResult<Vec<User>> {
+        let rows = sqlx::query(
+            "SELECT name, email, password, disabled, created FROM dumbserve_users ORDER BY name",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.iter().map(Self::user_from_row).collect())
+    }
+
+    /// enable or disable user account
+    pub async fn set_user_disabled(&self, name: &str, disabled: bool) -> ServiceResult<()> {
+        let res = sqlx::query("UPDATE dumbserve_users SET disabled = $1 WHERE name = $2")
+            .bind(disabled)
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Self::expect_user(res.rows_affected())
+    }
+
+    /// set password hash of user account
+    pub async fn update_password(&self, name: &str, password: &str) -> ServiceResult<()> {
+        let res = sqlx::query("UPDATE dumbserve_users SET password = $1 WHERE name = $2")
+            .bind(password)
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Self::expect_user(res.rows_affected())
+    }
+
+    /// delete user account
+    pub async fn delete_user(&self, name: &str) -> ServiceResult<()> {
+        let res = sqlx::query("DELETE FROM dumbserve_users WHERE name = $1")
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Self::expect_user(res.rows_affected())
+    }
+
+    /// add file to index or update its metadata
+    pub async fn upsert_file(&self, f: &FileInfo) -> ServiceResult<()> {
+        sqlx::query(
+            "INSERT INTO dumbserve_files
+                (owner, path, size, digest, content_type, modified, uploaded, uploader)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT (owner, path) DO UPDATE SET
+                size = excluded.size,
+                digest = excluded.digest,
+                content_type = excluded.content_type,
+                modified = excluded.modified,
+                uploaded = excluded.uploaded,
+                uploader = excluded.uploader",
+        )
+        .bind(&f.owner)
+        .bind(&f.path)
+        .bind(f.size)
+        .bind(&f.digest)
+        .bind(&f.content_type)
+        .bind(f.modified)
+        .bind(f.uploaded)
+        .bind(&f.uploader)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// get metadata of file
+    pub async fn get_file(&self, owner: &str, path: &str) -> ServiceResult<Option<FileInfo>> {
+        let row = sqlx::query(
+            "SELECT owner, path, size, digest, content_type, modified, uploaded, uploader
+            FROM dumbserve_files WHERE owner = $1 AND path = $2",
+        )
+        .bind(owner)
+        .bind(path)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.as_ref().map(Self::file_from_row))
+    }
+
+    /// list all indexed files of an owner
+    pub async fn list_files(&self, owner: &str) -> ServiceResult<Vec<FileInfo>> {
+        let rows = sqlx::query(
+            "SELECT owner, path, size, digest, content_type, modified, uploaded, uploader
+            FROM dumbserve_files WHERE owner = $1 ORDER BY path",
+        )
+        .bind(owner)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.iter().map(Self::file_from_row).collect())
+    }
+
+    /// list indexed files under `dir` in `owner`'s tree, the whole tree when `dir` is empty
+    pub async fn list_dir(&self, owner: &str, dir: &str) -> ServiceResult<Vec<FileInfo>> {
+        let prefix = if dir.is_empty() {
+            String::new()
+        } else {
+            format!("{dir}/")
+        };
+        let rows = sqlx::query(
+            "SELECT owner, path, size, digest, content_type, modified, uploaded, uploader
+            FROM dumbserve_files WHERE owner = $1 AND substr(path, 1, $2) = $3 ORDER BY path",
+        )
+        .bind(owner)
+        .bind(prefix.chars().count() as i32)
+        .bind(&prefix)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.iter().map(Self::file_from_row).collect())
+    }
+
+    /// list indexed files directly in `dir` of `owner`'s tree, without files of subdirectories
+    pub async fn list_children(&self, owner: &str, dir: &str) -> ServiceResult<Vec<FileInfo>> {
+        let prefix = if dir.is_empty() {
+            String::new()
+        } else {
+            format!("{dir}/")
+        };
+        let rows = sqlx::query(
+            "SELECT owner, path, size, digest, content_type, modified, uploaded, uploader
+            FROM dumbserve_files WHERE owner = $1 AND substr(path, 1, $2) = $3
+            AND substr(path, $2 + 1) NOT LIKE '%/%' ORDER BY path",
+        )
+        .bind(owner)
+        .bind(prefix.chars().count() as i32)
+        .bind(&prefix)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.iter().map(Self::file_from_row).collect())
+    }
+
+    /// list owners that have indexed files
+    pub async fn list_file_owners(&self) -> ServiceResult<Vec<String>> {
+        let rows = sqlx::query("SELECT DISTINCT owner FROM dumbserve_files")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.iter().map(|r| r.get("owner")).collect())
+    }
+
+    /// search index. `matches` is applied to results of `filter` to implement checks that can't
+    /// be done portably in SQL; `offset` and `limit` are applied after it. Only the first
+    /// [SEARCH_MAX_SCANNED] results of `filter` are checked, so results of `matches` that rule
+    /// out most files can be incomplete.
+    pub async fn search_files<F>(
+        &self,
+        filter: &FileFilter,
+        matches: F,
+        offset: usize,
+        limit: usize,
+    ) -> ServiceResult<Vec<FileInfo>>
+    where
+        F: Fn(&FileInfo) -> bool,
+    {
+        let mut conditions = Vec::new();
+        let mut binds = Vec::new();
+        let mut condition = |sql: &str, value: String| {
+            binds.push(value);
+            conditions.push(sql.replace('?', &format!("${}", binds.len())));
+        };
+
+        if let Some(owner) = &filter.owner {
+            condition("owner = ?", owner.clone());
+        }
+        if let Some(contains) = &filter.contains {
+            let escaped = contains
+                .to_lowercase()
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            condition("lower(path) LIKE ? ESCAPE '\\'", format!("%{escaped}%"));
+        }
+        if let Some(path_prefix) = &filter.path_prefix {
+            condition("substr(path, 1, length(?)) = ?", path_prefix.clone());
+        }
+        if let Some(content_type) = &filter.content_type {
+            condition(
+                "substr(content_type, 1, length(?)) = ?",
+                content_type.clone(),
+            );
+        }
+        let mut sql = String::from(
+            "SELECT owner, path, size, digest, content_type, modified, uploaded, uploader
+            FROM dumbserve_files WHERE 1 = 1",
+        );
+        for c in conditions.iter() {
+            sql.push_str(" AND ");
+            sql.push_str(c);
+        }
+        if filter.exclude_hidden {
+            sql.push_str(" AND path NOT LIKE '.%' AND path NOT LIKE '%/.%'");
+        }
+
+        // integer filters are inlined, they can't be used for injection
+        for (column, op, value) in [
+            ("size", ">=", filter.min_size),
+            ("size", "<=", filter.max_size),
+            ("modified", ">", filter.modified_after),
+            ("modified", "<", filter.modified_before),
+        ] {
+            if let Some(value) = value {
+                sql.push_str(&format!(" AND {column} {op} {value}"));
+            }
+        }
+
+        let column = match filter.sort.unwrap_or(SortBy::Path) {
+            SortBy::Path => "owner, path",
+            SortBy::Size => "size",
+            SortBy::Modified => "modified",
+            SortBy::Uploaded => "uploaded",
+        };
+        let order = if filter.descending { "DESC" } else { "ASC" };
+        sql.push_str(&format!(" ORDER BY {column} {order}"));
+
+        let mut query = sqlx::query(&sql);
+        for value in binds.iter() {
+            query = query.bind(value);
+        }
+
+        let mut rows = query.fetch(&self.pool);
+        let mut files = Vec::new();
+        let mut skipped = 0;
+        let mut scanned = 0;
+        while let Some(row) = rows.try_next().await? {
+            scanned += 1;
+            if scanned > SEARCH_MAX_SCANNED {
+                break;
+            }
+            let file = Self::file_from_row(&row);
+            if !matches(&file) {
+                continue;
+            }
+            if skipped < offset {
+                skipped += 1;
+                continue;
+            }
+            files.push(file);
+            if files.len() >= limit {
+                break;
+            }
+        }
+        Ok(files)
+    }
+
+    /// remove file from index
+    pub async fn delete_file(&self, owner: &str, path: &str) -> ServiceResult<()> {
+        sqlx::query("DELETE FROM dumbserve_files WHERE owner = $1 AND path = $2")
+            .bind(owner)
+            .bind(path)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// remove directory and everything under it from index. Empty `dir` removes all files of
+    /// `owner`.
+    pub async fn delete_dir(&self, owner: &str, dir: &str) -> ServiceResult<()> {
+        if dir.is_empty() {
+            sqlx::query("DELETE FROM dumbserve_files WHERE owner = $1")
+                .bind(owner)
+                .execute(&self.pool)
+                .await?;
+        } else {
+            let prefix = format!("{dir}/");
+            sqlx::query(
+                "DELETE FROM dumbserve_files
+                WHERE owner = $1 AND substr(path, 1, $2) = $3",
+            )
+            .bind(owner)
+            .bind(prefix.chars().count() as i32)
+            .bind(&prefix)
+            .execute(&self.pool)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// add release or update its metadata when republished
+    pub async fn upsert_release(&self, r: &Release) -> ServiceResult<()> {
+        sqlx::query(
+            "INSERT INTO dumbserve_releases
+                (owner, project, version, channel, published, publisher)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (owner, project, version) DO UPDATE SET
+                channel = excluded.channel,
+                published = excluded.published,
+                publisher = excluded.publisher",
+        )
+        .bind(&r.owner)
+        .bind(&r.project)
+        .bind(&r.version)
+        .bind(r.channel.as_str())
+        .bind(r.published)
+        .bind(&r.publisher)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// list releases of a project. Versions are sorted as strings, callers that care about
+    /// precedence must sort them by semver.
+    pub async fn list_releases(&self, owner: &str, project: &str) -> ServiceResult<Vec<Release>> {
+        let rows = sqlx::query(
+            "SELECT owner, project, version, channel, published, publisher
+            FROM dumbserve_releases WHERE owner = $1 AND project = $2 ORDER BY version",
+        )
+        .bind(owner)
+        .bind(project)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.iter().map(Self::release_from_row).collect())
+    }
+
+    /// delete release
+    pub async fn delete_release(
+        &self,
+        owner: &str,
+        project: &str,
+        version: &str,
+    ) -> ServiceResult<()> {
+        let res = sqlx::query(
+            "DELETE FROM dumbserve_releases WHERE owner = $1 AND project = $2 AND version = $3",
+        )
+        .bind(owner)
+        .bind(project)
+        .bind(version)
+        .execute(&self.pool)
+        .await?;
+        if res.rows_affected() == 0 {
+            Err(ServiceError::ReleaseNotFound)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// delete all releases of an owner
+    pub async fn delete_releases(&self, owner: &str) -> ServiceResult<()> {
+        sqlx::query("DELETE FROM dumbserve_releases WHERE owner = $1")
+            .bind(owner)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    fn release_from_row(row: &sqlx::any::AnyRow) -> Release {
+        let channel: String = row.get("channel");
+        Release {
+            owner: row.get("owner"),
+            project: row.get("project"),
+            version: row.get("version"),
+            channel: Channel::parse(&channel).unwrap_or(Channel::Nightly),
+            published: row.get("published"),
+            publisher: row.get("publisher"),
+        }
+    }
+
+    fn file_from_row(row: &sqlx::any::AnyRow) -> FileInfo {
+        FileInfo {
+            owner: row.get("owner"),
+            path: row.get("path"),
+            size: row.get("size"),
+            digest: row.get("digest"),
+            content_type: row.get("content_type"),
+            modified: row.get("modified"),
+            uploaded: row.get("uploaded"),
+            uploader: row.get("uploader"),
+        }
+    }
+
+    fn expect_user(rows_affected: u64) -> ServiceResult<()> {
+        if rows_affected == 0 {
+            Err(ServiceError::AccountNotFound)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn user_from_row(row: &sqlx::any::AnyRow) -> User {
+        User {
+            name: row.get("name"),
+            email: row.get("email"),
+            password: row.get("password"),
+            disabled: row.get("disabled"),
+            created: row.get("created"),
+        }
+    }
+}
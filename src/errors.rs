@@ -44,6 +44,9 @@ use url::ParseError;
 pub enum ServiceError {
     #[display(fmt = "unauthorized")]
     Unauthorized,
+    /// signed in, but not allowed to do this
+    #[display(fmt = "forbidden")]
+    Forbidden,
 
     #[display(fmt = "internal server error")]
     InternalServerError,
@@ -81,6 +84,10 @@ pub enum ServiceError {
     #[display(fmt = "Passwords don't match")]
     PasswordsDontMatch,
 
+    /// username can't name a directory in `files.path`
+    #[display(fmt = "Usernames may only contain lowercase letters, digits, '_' and '-'")]
+    InvalidUsername,
+
     /// when the a username is already taken
     #[display(fmt = "Username not available")]
     UsernameTaken,
@@ -88,6 +95,52 @@ pub enum ServiceError {
     /// email is already taken
     #[display(fmt = "Email not available")]
     EmailTaken,
+
+    /// user account doesn't exist
+    #[display(fmt = "Account not found")]
+    AccountNotFound,
+
+    #[display(fmt = "Invalid glob pattern")]
+    InvalidGlob,
+
+    /// version isn't valid semver
+    #[display(fmt = "Invalid version, versions must follow semver")]
+    InvalidVersion,
+    #[display(fmt = "Invalid project name")]
+    InvalidProjectName,
+    #[display(fmt = "Release not found")]
+    ReleaseNotFound,
+    #[display(fmt = "Release doesn't contain artifact")]
+    ArtifactNotFound,
+    /// artifact doesn't have a valid signature from a trusted key
+    #[display(fmt = "Upload contains artifacts without a valid signature from a trusted key")]
+    UnsignedArtifact,
+    #[display(fmt = "Invalid path")]
+    InvalidPath,
+    #[display(fmt = "Publish session not found")]
+    SessionNotFound,
+    #[display(fmt = "Server-side signing is disabled")]
+    SigningDisabled,
+    /// target isn't `<os>-<arch>` or a target triple
+    #[display(fmt = "Invalid target")]
+    InvalidTarget,
+    #[display(fmt = "Filename is not present")]
+    FilenameNotPresent,
+    /// upload isn't a `.tar`, `.tar.gz`, `.tar.zst` or `.zip` archive
+    #[display(fmt = "Unsupported archive format")]
+    UnsupportedArchive,
+    #[display(fmt = "Invalid archive")]
+    InvalidArchive,
+    /// archive contains entries that would be extracted outside of the target directory
+    #[display(fmt = "Archive contains unsafe paths or links")]
+    UnsafeArchive,
+    #[display(fmt = "Archive exceeds extraction limits")]
+    ArchiveTooLarge,
+    #[display(fmt = "Directory exceeds download size limit")]
+    DirectoryTooLarge,
+    /// every archive stream of `files.download.max_streams` is in use
+    #[display(fmt = "Too many directory downloads in progress, try again later")]
+    TooManyDownloads,
     //    #[display(fmt = "{}", _0)]
     //    DBError(DBErrorWrapper),
 }
@@ -124,6 +177,7 @@ impl ResponseError for ServiceError {
             ServiceError::NotAnEmail => StatusCode::BAD_REQUEST,
             ServiceError::WrongPassword => StatusCode::UNAUTHORIZED,
             ServiceError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ServiceError::Forbidden => StatusCode::FORBIDDEN,
 
             ServiceError::ProfainityError => StatusCode::BAD_REQUEST,
             ServiceError::BlacklistError => StatusCode::BAD_REQUEST,
@@ -133,8 +187,27 @@ impl ResponseError for ServiceError {
             ServiceError::PasswordTooLong => StatusCode::BAD_REQUEST,
             ServiceError::PasswordsDontMatch => StatusCode::BAD_REQUEST,
 
+            ServiceError::InvalidUsername => StatusCode::BAD_REQUEST,
             ServiceError::UsernameTaken => StatusCode::BAD_REQUEST,
             ServiceError::EmailTaken => StatusCode::BAD_REQUEST,
+            ServiceError::AccountNotFound => StatusCode::NOT_FOUND,
+            ServiceError::InvalidGlob => StatusCode::BAD_REQUEST,
+            ServiceError::InvalidVersion => StatusCode::BAD_REQUEST,
+            ServiceError::InvalidProjectName => StatusCode::BAD_REQUEST,
+            ServiceError::ReleaseNotFound => StatusCode::NOT_FOUND,
+            ServiceError::ArtifactNotFound => StatusCode::NOT_FOUND,
+            ServiceError::InvalidTarget => StatusCode::BAD_REQUEST,
+            ServiceError::UnsignedArtifact => StatusCode::BAD_REQUEST,
+            ServiceError::SigningDisabled => StatusCode::NOT_FOUND,
+            ServiceError::InvalidPath => StatusCode::BAD_REQUEST,
+            ServiceError::SessionNotFound => StatusCode::NOT_FOUND,
+            ServiceError::FilenameNotPresent => StatusCode::BAD_REQUEST,
+            ServiceError::UnsupportedArchive => StatusCode::BAD_REQUEST,
+            ServiceError::InvalidArchive => StatusCode::BAD_REQUEST,
+            ServiceError::UnsafeArchive => StatusCode::BAD_REQUEST,
+            ServiceError::ArchiveTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ServiceError::DirectoryTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ServiceError::TooManyDownloads => StatusCode::SERVICE_UNAVAILABLE,
             //            ServiceError::DBError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -169,6 +242,14 @@ impl From<CredsError> for ServiceError {
 //    }
 //}
 
+impl From<sqlx::Error> for ServiceError {
+    #[cfg(not(tarpaulin_include))]
+    fn from(e: sqlx::Error) -> ServiceError {
+        log::error!("{:?}", e);
+        ServiceError::InternalServerError
+    }
+}
+
 impl From<ParseError> for ServiceError {
     #[cfg(not(tarpaulin_include))]
     fn from(_: ParseError) -> ServiceError {
@@ -185,5 +266,14 @@ impl From<RecvError> for ServiceError {
     }
 }
 
+#[cfg(not(tarpaulin_include))]
+impl From<std::io::Error> for ServiceError {
+    #[cfg(not(tarpaulin_include))]
+    fn from(e: std::io::Error) -> Self {
+        log::error!("{:?}", e);
+        ServiceError::InternalServerError
+    }
+}
+
 #[cfg(not(tarpaulin_include))]
 pub type ServiceResult<V> = std::result::Result<V, ServiceError>;
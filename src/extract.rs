@@ -0,0 +1,475 @@
+/*
+ * Copyright (C) 2022  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Safe extraction of uploaded archives. Entries can't escape the destination: absolute paths,
+//! `..` components and hard links are rejected, symbolic links may only point below the
+//! directory they are in and later entries can't be extracted through them. Sizes are counted while extracting, so archives lying about entry
+//! sizes can't get around [ExtractLimits].
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+
+use crate::errors::*;
+use crate::settings::ExtractLimits;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    Tar,
+    TarGz,
+    TarZst,
+    Zip,
+}
+
+impl Format {
+    /// archive format of file `name`
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.to_lowercase();
+        if name.ends_with(".tar") {
+            Some(Self::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Self::TarGz)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Some(Self::TarZst)
+        } else if name.ends_with(".zip") {
+            Some(Self::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+/// extracted file or symbolic link
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Entry {
+    /// `/` separated path relative to destination
+    pub path: String,
+    pub is_symlink: bool,
+}
+
+/// what the archives of one upload extracted so far. [ExtractLimits] apply to the upload as a
+/// whole, not to each of its archives.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Usage {
+    /// size of the archives, for compression ratio checks
+    pub archive_size: u64,
+    /// size of extracted files
+    pub written: u64,
+    /// extracted files, links and directories
+    pub entries: usize,
+}
+
+struct Extractor<'a> {
+    dest: &'a Path,
+    limits: &'a ExtractLimits,
+    usage: &'a mut Usage,
+    entries: Vec<Entry>,
+}
+
+/// `/` separated relative path of `path` if it only has normal components
+fn safe_path(path: &Path) -> ServiceResult<String> {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(c) => parts.push(c.to_str().ok_or(ServiceError::UnsafeArchive)?),
+            Component::CurDir => (),
+            _ => return Err(ServiceError::UnsafeArchive),
+        }
+    }
+    Ok(parts.join("/"))
+}
+
+/// reject directory `dir`, relative to `dest`, if it or one of its parents is a link from an
+/// earlier entry. Entries written through links wouldn't end up at their paths.
+fn check_links(dest: &Path, dir: &str) -> ServiceResult<()> {
+    let mut current = dest.to_path_buf();
+    for part in dir.split('/').filter(|p| !p.is_empty()) {
+        current.push(part);
+        match fs::symlink_metadata(&current) {
+            Ok(m) if m.file_type().is_symlink() => return Err(ServiceError::UnsafeArchive),
+            Ok(_) => (),
+            Err(_) => break,
+        }
+    }
+    Ok(())
+}
+
+/// location of entry `path` in `dest`. Creates its parents and removes files or links
+/// already there: writing through links from earlier entries would record the wrong entry
+/// type.
+fn replace(dest: &Path, path: &str) -> ServiceResult<PathBuf> {
+    if let Some((parent, _)) = path.rsplit_once('/') {
+        check_links(dest, parent)?;
+    }
+    let target = dest.join(path);
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::symlink_metadata(&target) {
+        Ok(m) if m.is_dir() => Err(ServiceError::InvalidArchive),
+        Ok(_) => {
+            fs::remove_file(&target)?;
+            Ok(target)
+        }
+        Err(_) => Ok(target),
+    }
+}
+
+impl<'a> Extractor<'a> {
+    fn count_entry(&mut self) -> ServiceResult<()> {
+        if self.usage.entries >= self.limits.max_files {
+            Err(ServiceError::ArchiveTooLarge)
+        } else {
+            self.usage.entries += 1;
+            Ok(())
+        }
+    }
+
+    fn dir(&mut self, path: &Path) -> ServiceResult<()> {
+        self.count_entry()?;
+        let path = safe_path(path)?;
+        check_links(self.dest, &path)?;
+        fs::create_dir_all(self.dest.join(path))?;
+        Ok(())
+    }
+
+    fn file(&mut self, path: &Path, reader: &mut dyn Read) -> ServiceResult<()> {
+        self.count_entry()?;
+        let path = safe_path(path)?;
+        if path.is_empty() {
+            return Err(ServiceError::UnsafeArchive);
+        }
+        let target = replace(self.dest, &path)?;
+
+        let max_size = self.limits.max_size.min(
+            self.usage
+                .archive_size
+                .saturating_mul(self.limits.max_ratio),
+        );
+        let remaining = max_size.saturating_sub(self.usage.written);
+        let mut f = File::create(&target)?;
+        let written = io::copy(&mut reader.take(remaining + 1), &mut f)?;
+        self.usage.written += written;
+        if written > remaining {
+            return Err(ServiceError::ArchiveTooLarge);
+        }
+
+        self.entries.push(Entry {
+            path,
+            is_symlink: false,
+        });
+        Ok(())
+    }
+
+    /// links may only point below the directory they are in, so chains of links can't
+    /// escape the destination either
+    #[cfg(unix)]
+    fn symlink(&mut self, path: &Path, target: &Path) -> ServiceResult<()> {
+        self.count_entry()?;
+        let path = safe_path(path)?;
+        let link_target = safe_path(target)?;
+        if path.is_empty() || link_target.is_empty() {
+            return Err(ServiceError::UnsafeArchive);
+        }
+        let link = replace(self.dest, &path)?;
+        std::os::unix::fs::symlink(link_target, link)?;
+        self.entries.push(Entry {
+            path,
+            is_symlink: true,
+        });
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn symlink(&mut self, _path: &Path, _target: &Path) -> ServiceResult<()> {
+        Err(ServiceError::UnsafeArchive)
+    }
+
+    fn tar(&mut self, reader: impl Read) -> ServiceResult<()> {
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive
+            .entries()
+            .map_err(|_| ServiceError::InvalidArchive)?
+        {
+            let mut entry = entry.map_err(|_| ServiceError::InvalidArchive)?;
+            let path: PathBuf = entry
+                .path()
+                .map_err(|_| ServiceError::InvalidArchive)?
+                .into();
+            match entry.header().entry_type() {
+                tar::EntryType::Directory => self.dir(&path)?,
+                tar::EntryType::Regular | tar::EntryType::Continuous => {
+                    self.file(&path, &mut entry)?
+                }
+                tar::EntryType::Symlink => {
+                    let target: PathBuf = entry
+                        .link_name()
+                        .map_err(|_| ServiceError::InvalidArchive)?
+                        .ok_or(ServiceError::InvalidArchive)?
+                        .into();
+                    self.symlink(&path, &target)?
+                }
+                // PAX and GNU extension headers are consumed by `tar`
+                tar::EntryType::XGlobalHeader => (),
+                _ => return Err(ServiceError::UnsafeArchive),
+            }
+        }
+        Ok(())
+    }
+
+    fn zip(&mut self, file: File) -> ServiceResult<()> {
+        const S_IFMT: u32 = 0o170000;
+        const S_IFLNK: u32 = 0o120000;
+
+        let mut archive =
+            zip::ZipArchive::new(BufReader::new(file)).map_err(|_| ServiceError::InvalidArchive)?;
+        for i in 0..archive.len() {
+            let mut entry = archive
+                .by_index(i)
+                .map_err(|_| ServiceError::InvalidArchive)?;
+            let path: PathBuf = entry
+                .enclosed_name()
+                .ok_or(ServiceError::UnsafeArchive)?
+                .into();
+            if entry.is_dir() {
+                self.dir(&path)?;
+            } else if entry.unix_mode().map(|m| m & S_IFMT) == Some(S_IFLNK) {
+                let mut target = String::new();
+                entry
+                    .by_ref()
+                    .take(4096)
+                    .read_to_string(&mut target)
+                    .map_err(|_| ServiceError::InvalidArchive)?;
+                self.symlink(&path, Path::new(&target))?;
+            } else {
+                self.file(&path, &mut entry)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// extract `archive` into `dest`, adding to `usage` of earlier archives of the same upload.
+/// Blocking, run it on a thread pool.
+pub fn extract(
+    archive: &Path,
+    format: Format,
+    dest: &Path,
+    limits: &ExtractLimits,
+    usage: &mut Usage,
+) -> ServiceResult<Vec<Entry>> {
+    let file = File::open(archive)?;
+    usage.archive_size += file.metadata()?.len();
+    let mut extractor = Extractor {
+        dest,
+        limits,
+        usage,
+        entries: Vec::new(),
+    };
+    let reader = BufReader::new(file);
+    match format {
+        Format::Tar => extractor.tar(reader)?,
+        Format::TarGz => extractor.tar(flate2::read::GzDecoder::new(reader))?,
+        Format::TarZst => extractor.tar(zstd::Decoder::with_buffer(reader)?)?,
+        Format::Zip => extractor.zip(reader.into_inner())?,
+    }
+    Ok(extractor.entries)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    const LIMITS: ExtractLimits = ExtractLimits {
+        max_size: 1024 * 1024,
+        max_files: 10,
+        max_ratio: 100,
+    };
+
+    pub fn tar_archive(build: impl FnOnce(&mut tar::Builder<Vec<u8>>)) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        build(&mut builder);
+        builder.into_inner().unwrap()
+    }
+
+    pub fn append(builder: &mut tar::Builder<Vec<u8>>, path: &str, data: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_entry_type(tar::EntryType::Regular);
+        // `set_path` refuses `..`, write the name directly to test extraction
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        header.set_cksum();
+        builder.append(&header, data).unwrap();
+    }
+
+    fn append_symlink(builder: &mut tar::Builder<Vec<u8>>, path: &str, target: &str) {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(0);
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        header.as_old_mut().linkname[..target.len()].copy_from_slice(target.as_bytes());
+        header.set_cksum();
+        builder.append(&header, io::empty()).unwrap();
+    }
+
+    fn run(name: &str, archive: &[u8], format: Format) -> (PathBuf, ServiceResult<Vec<Entry>>) {
+        let root = std::env::temp_dir().join(format!("dumbserve-extract-{name}"));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("dest")).unwrap();
+        let path = root.join("archive");
+        fs::write(&path, archive).unwrap();
+        let res = extract(
+            &path,
+            format,
+            &root.join("dest"),
+            &LIMITS,
+            &mut Usage::default(),
+        );
+        (root, res)
+    }
+
+    #[test]
+    fn extract_works() {
+        assert_eq!(Format::from_name("site.TAR.GZ"), Some(Format::TarGz));
+        assert_eq!(Format::from_name("site.tar.zst"), Some(Format::TarZst));
+        assert_eq!(Format::from_name("site.txt"), None);
+
+        let archive = tar_archive(|b| {
+            append(b, "docs/index.html", b"foo");
+            append_symlink(b, "docs/latest", "index.html");
+        });
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&archive).unwrap();
+        let (root, res) = run("tar", &gz.finish().unwrap(), Format::TarGz);
+        let entries = res.unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].path, "docs/index.html");
+        assert!(entries[1].is_symlink);
+        assert_eq!(
+            fs::read(root.join("dest/docs/latest")).unwrap(),
+            b"foo".to_vec()
+        );
+        fs::remove_dir_all(root).unwrap();
+
+        let zstd = zstd::encode_all(&archive[..], 0).unwrap();
+        let (root, res) = run("zst", &zstd, Format::TarZst);
+        assert_eq!(res.unwrap().len(), 2);
+        fs::remove_dir_all(root).unwrap();
+
+        let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        zip.start_file("docs/index.html", Default::default())
+            .unwrap();
+        zip.write_all(b"foo").unwrap();
+        let zip = zip.finish().unwrap().into_inner();
+        let (root, res) = run("zip", &zip, Format::Zip);
+        assert_eq!(res.unwrap()[0].path, "docs/index.html");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn unsafe_archives_are_rejected() {
+        let archives = [
+            ("traversal", tar_archive(|b| append(b, "../foo", b"foo"))),
+            ("absolute", tar_archive(|b| append(b, "/tmp/foo", b"foo"))),
+            (
+                "symlink",
+                tar_archive(|b| append_symlink(b, "docs/link", "../../etc/passwd")),
+            ),
+            (
+                "absolute-symlink",
+                tar_archive(|b| append_symlink(b, "link", "/etc/passwd")),
+            ),
+            (
+                "through-symlink",
+                tar_archive(|b| {
+                    append_symlink(b, "a", "b");
+                    append(b, "a/x", b"foo");
+                }),
+            ),
+            (
+                "dir-through-symlink",
+                tar_archive(|b| {
+                    append_symlink(b, "a", "b");
+                    let mut header = tar::Header::new_gnu();
+                    header.set_size(0);
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_path("a/sub").unwrap();
+                    header.set_cksum();
+                    b.append(&header, io::empty()).unwrap();
+                }),
+            ),
+        ];
+        for (name, archive) in archives.iter() {
+            let (root, res) = run(name, archive, Format::Tar);
+            assert_eq!(res, Err(ServiceError::UnsafeArchive), "{name}");
+            assert!(!root.join("foo").exists());
+            fs::remove_dir_all(root).unwrap();
+        }
+
+        // highly compressible data exceeds the compression ratio limit
+        let archive = tar_archive(|b| append(b, "zeros", &vec![0; 1024 * 1024]));
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        gz.write_all(&archive).unwrap();
+        let (root, res) = run("bomb", &gz.finish().unwrap(), Format::TarGz);
+        assert_eq!(res, Err(ServiceError::ArchiveTooLarge));
+        fs::remove_dir_all(root).unwrap();
+
+        let archive = tar_archive(|b| {
+            for i in 0..11 {
+                append(b, &format!("{i}"), b"foo");
+            }
+        });
+        let (root, res) = run("files", &archive, Format::Tar);
+        assert_eq!(res, Err(ServiceError::ArchiveTooLarge));
+        fs::remove_dir_all(root).unwrap();
+
+        // directories count as entries
+        let archive = tar_archive(|b| {
+            for i in 0..11 {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(0);
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_path(format!("{i}")).unwrap();
+                header.set_cksum();
+                b.append(&header, io::empty()).unwrap();
+            }
+        });
+        let (root, res) = run("dirs", &archive, Format::Tar);
+        assert_eq!(res, Err(ServiceError::ArchiveTooLarge));
+        fs::remove_dir_all(root).unwrap();
+
+        // limits apply to all archives of an upload together
+        let archive = tar_archive(|b| {
+            for i in 0..6 {
+                append(b, &format!("{i}"), b"foo");
+            }
+        });
+        let (root, res) = run("upload", &archive, Format::Tar);
+        assert_eq!(res.unwrap().len(), 6);
+        let mut usage = Usage::default();
+        let path = root.join("archive");
+        let dest = root.join("dest");
+        assert!(extract(&path, Format::Tar, &dest, &LIMITS, &mut usage).is_ok());
+        assert_eq!(usage.entries, 6);
+        assert_eq!(
+            extract(&path, Format::Tar, &dest, &LIMITS, &mut usage),
+            Err(ServiceError::ArchiveTooLarge)
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,371 @@
+/*
+ * Copyright (C) 2022  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Response headers from `server.headers` rules: arbitrary headers, CORS and forced downloads.
+//! Applied by the [ResponseHeaders] middleware to every response.
+use std::future::{ready, Ready};
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::http::Method;
+use actix_web::{Error, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+
+use crate::settings::HeaderRule;
+use crate::AppCtx;
+
+/// how long browsers may cache preflight responses, in seconds
+const PREFLIGHT_MAX_AGE: &str = "86400";
+
+/// compiled `server.headers` rules
+pub struct HeaderRules {
+    rules: Vec<(HeaderRule, Vec<(HeaderName, HeaderValue)>)>,
+}
+
+fn matches_prefix(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+impl HeaderRules {
+    pub fn new(rules: &[HeaderRule]) -> Result<Self, String> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let headers = rule
+                    .headers
+                    .iter()
+                    .map(|(name, value)| {
+                        let name = HeaderName::try_from(name.as_str())
+                            .map_err(|_| format!("Invalid header name {name}"))?;
+                        let value = HeaderValue::try_from(value.as_str())
+                            .map_err(|_| format!("Invalid value of header {name}"))?;
+                        Ok((name, value))
+                    })
+                    .collect::<Result<_, String>>()?;
+                Ok((rule.clone(), headers))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self { rules })
+    }
+
+    fn matching<'a>(&'a self, path: &'a str) -> impl Iterator<Item = &'a HeaderRule> + 'a {
+        self.rules
+            .iter()
+            .map(|(rule, _)| rule)
+            .filter(move |rule| matches_prefix(path, &rule.prefix))
+    }
+
+    /// value of `Access-Control-Allow-Origin` for `origin` at `path`
+    fn allowed_origin(&self, path: &str, origin: &HeaderValue) -> Option<HeaderValue> {
+        let origin_str = origin.to_str().ok()?;
+        let mut allowed = None;
+        for rule in self.matching(path) {
+            if rule.cors_origins.iter().any(|o| o == "*") {
+                allowed = Some(HeaderValue::from_static("*"));
+            } else if rule.cors_origins.iter().any(|o| o == origin_str) {
+                allowed = Some(origin.clone());
+            }
+        }
+        allowed
+    }
+
+    /// response to CORS preflight request, if `path` allows it
+    pub fn preflight(&self, path: &str, headers: &HeaderMap) -> Option<HttpResponse> {
+        let origin = headers.get(header::ORIGIN)?;
+        let method = headers
+            .get(header::ACCESS_CONTROL_REQUEST_METHOD)?
+            .to_str()
+            .ok()?;
+        let allowed_origin = self.allowed_origin(path, origin)?;
+        let mut methods: Vec<&str> = self
+            .matching(path)
+            .flat_map(|rule| rule.cors_methods.iter().map(String::as_str))
+            .collect();
+        if methods.is_empty() {
+            methods = vec!["GET", "HEAD"];
+        }
+        if !methods.iter().any(|m| m.eq_ignore_ascii_case(method)) {
+            return None;
+        }
+
+        let mut resp = HttpResponse::NoContent();
+        resp.insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, allowed_origin))
+            .insert_header((header::ACCESS_CONTROL_ALLOW_METHODS, methods.join(", ")))
+            .insert_header((header::ACCESS_CONTROL_MAX_AGE, PREFLIGHT_MAX_AGE))
+            .insert_header((header::VARY, "origin"));
+        if let Some(requested) = headers.get(header::ACCESS_CONTROL_REQUEST_HEADERS) {
+            resp.insert_header((header::ACCESS_CONTROL_ALLOW_HEADERS, requested.clone()));
+        }
+        Some(resp.finish())
+    }
+
+    /// add headers of rules matching `path` to `headers` of a response to a request from
+    /// `origin`
+    pub fn apply(&self, path: &str, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+        let mut attachment = false;
+        // responses differ by origin unless every origin is allowed
+        let mut varies = false;
+        for (rule, rule_headers) in self.rules.iter() {
+            if !matches_prefix(path, &rule.prefix) {
+                continue;
+            }
+            for (name, value) in rule_headers.iter() {
+                headers.insert(name.clone(), value.clone());
+            }
+            attachment |= rule.attachment;
+            varies |= !rule.cors_origins.is_empty() && !rule.cors_origins.iter().any(|o| o == "*");
+        }
+
+        if varies {
+            headers.append(header::VARY, HeaderValue::from_static("origin"));
+        }
+        if let Some(allowed) = origin.and_then(|origin| self.allowed_origin(path, origin)) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allowed);
+        }
+
+        if attachment {
+            // keep file names from `inline; filename=...`
+            let value = match headers.get(header::CONTENT_DISPOSITION) {
+                Some(value) => match value.to_str() {
+                    Ok(value) if value.starts_with("inline") => {
+                        format!("attachment{}", &value["inline".len()..])
+                    }
+                    Ok(value) if value.starts_with("attachment") => value.to_owned(),
+                    _ => "attachment".into(),
+                },
+                None => "attachment".into(),
+            };
+            if let Ok(value) = HeaderValue::try_from(value) {
+                headers.insert(header::CONTENT_DISPOSITION, value);
+            }
+        }
+    }
+}
+
+/// middleware applying [HeaderRules] of the app's [crate::ctx::Ctx]
+pub struct ResponseHeaders;
+
+impl<S, B> Transform<S, ServiceRequest> for ResponseHeaders
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = ResponseHeadersMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ResponseHeadersMiddleware { service }))
+    }
+}
+
+pub struct ResponseHeadersMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for ResponseHeadersMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let ctx = req.app_data::<AppCtx>().cloned();
+        let ctx = match ctx {
+            Some(ctx) => ctx,
+            None => {
+                let fut = self.service.call(req);
+                return Box::pin(async move { Ok(fut.await?.map_into_left_body()) });
+            }
+        };
+
+        let path = req.path().to_owned();
+        if req.method() == Method::OPTIONS {
+            if let Some(resp) = ctx.header_rules.preflight(&path, req.headers()) {
+                return Box::pin(async move { Ok(req.into_response(resp).map_into_right_body()) });
+            }
+        }
+
+        let origin = req.headers().get(header::ORIGIN).cloned();
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            ctx.header_rules
+                .apply(&path, origin.as_ref(), res.headers_mut());
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::collections::BTreeMap;
+
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App};
+
+    use super::*;
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn headers_work() {
+        let rule = |prefix: &str| HeaderRule {
+            prefix: prefix.into(),
+            headers: BTreeMap::new(),
+            cors_origins: Vec::new(),
+            cors_methods: Vec::new(),
+            attachment: false,
+        };
+        let mut settings = Settings::new().unwrap();
+        settings.server.headers = vec![
+            HeaderRule {
+                headers: [("X-Content-Type-Options".into(), "nosniff".into())].into(),
+                ..rule("/")
+            },
+            HeaderRule {
+                cors_origins: vec!["https://example.org".into()],
+                ..rule("/api/v1/meta")
+            },
+            HeaderRule {
+                attachment: true,
+                ..rule("/downloads")
+            },
+        ];
+        assert!(matches_prefix("/api/v1/meta/build", "/api/v1/meta"));
+        assert!(!matches_prefix("/api/v1/metadata", "/api/v1/meta"));
+
+        let ctx = AppCtx::new(crate::ctx::Ctx::new(&settings).await);
+        let app = test::init_service(
+            App::new()
+                .app_data(ctx.clone())
+                .wrap(ResponseHeaders)
+                .route(
+                    "/downloads/app",
+                    web::get().to(|| async {
+                        HttpResponse::Ok()
+                            .insert_header((
+                                header::CONTENT_DISPOSITION,
+                                "inline; filename=\"app\"",
+                            ))
+                            .finish()
+                    }),
+                )
+                .configure(crate::routes::services),
+        )
+        .await;
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .insert_header((header::ORIGIN, "https://example.org"))
+                .uri(API_V1_ROUTES.meta.build_details)
+                .to_request(),
+        )
+        .await;
+        let headers = resp.headers();
+        assert_eq!(headers.get("x-content-type-options").unwrap(), "nosniff");
+        assert_eq!(
+            headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+            "https://example.org"
+        );
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::default()
+                .method(Method::OPTIONS)
+                .insert_header((header::ORIGIN, "https://example.org"))
+                .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "GET"))
+                .uri(API_V1_ROUTES.meta.build_details)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            resp.headers()
+                .get(header::ACCESS_CONTROL_ALLOW_METHODS)
+                .unwrap(),
+            "GET, HEAD"
+        );
+
+        // other origins and methods aren't allowed
+        for (origin, method) in [
+            ("https://evil.example", "GET"),
+            ("https://example.org", "DELETE"),
+        ] {
+            let resp = test::call_service(
+                &app,
+                test::TestRequest::default()
+                    .method(Method::OPTIONS)
+                    .insert_header((header::ORIGIN, origin))
+                    .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, method))
+                    .uri(API_V1_ROUTES.meta.build_details)
+                    .to_request(),
+            )
+            .await;
+            assert_ne!(resp.status(), StatusCode::NO_CONTENT);
+            assert!(resp
+                .headers()
+                .get(header::ACCESS_CONTROL_ALLOW_METHODS)
+                .is_none());
+        }
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .insert_header((header::ORIGIN, "https://evil.example"))
+                .uri(API_V1_ROUTES.meta.build_details)
+                .to_request(),
+        )
+        .await;
+        assert!(resp
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+        // caches keep responses to other origins apart
+        assert_eq!(resp.headers().get(header::VARY).unwrap(), "origin");
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(API_V1_ROUTES.meta.build_details)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.headers().get(header::VARY).unwrap(), "origin");
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get().uri("/downloads/app").to_request(),
+        )
+        .await;
+        assert_eq!(
+            resp.headers().get(header::CONTENT_DISPOSITION).unwrap(),
+            "attachment; filename=\"app\""
+        );
+    }
+}
@@ -0,0 +1,243 @@
+/*
+ * Copyright (C) 2022  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Metadata index of stored files. The file APIs keep it in sync and [reconcile] catches up
+//! with changes made to `files.path` behind dumbserve's back.
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::io;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tokio::io::AsyncReadExt;
+
+use crate::ctx::Ctx;
+use crate::db::{now_unix, FileInfo};
+use crate::errors::*;
+
+/// guess content type from file name
+pub fn content_type(path: &str) -> String {
+    mime_guess::from_path(path)
+        .first_or_octet_stream()
+        .to_string()
+}
+
+/// last modification time as UNIX timestamp
+pub fn modified(metadata: &Metadata) -> i64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// hex encoded SHA-256 digest of file
+pub async fn digest(path: &Path) -> io::Result<String> {
+    let mut f = fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = f.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// record freshly uploaded file `path` in `owner`'s tree
+pub async fn index_file(
+    ctx: &Ctx,
+    owner: &str,
+    path: &str,
+    uploader: &str,
+    digest: String,
+) -> ServiceResult<FileInfo> {
+    let metadata = fs::metadata(ctx.settings.files.get_path(owner, path)).await?;
+    let file = FileInfo {
+        owner: owner.into(),
+        path: path.into(),
+        size: metadata.len() as i64,
+        digest,
+        content_type: content_type(path),
+        modified: modified(&metadata),
+        uploaded: now_unix(),
+        uploader: uploader.into(),
+    };
+    ctx.db.upsert_file(&file).await?;
+    crate::compression::spawn_precompress(
+        &ctx.settings.files,
+        &ctx.precompress_jobs,
+        ctx.settings.files.get_path(owner, path),
+        &file.content_type,
+        metadata.len(),
+    );
+    Ok(file)
+}
+
+/// bring index in sync with the contents of `files.path`
+pub async fn reconcile(ctx: &Ctx) -> ServiceResult<()> {
+    let mut owners = Vec::new();
+    let mut read_dir = fs::read_dir(&ctx.settings.files.path).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        if entry.file_type().await?.is_dir() {
+            match entry.file_name().into_string() {
+                // staging directory
+                Ok(owner) if owner.starts_with('.') => (),
+                Ok(owner) => owners.push(owner),
+                Err(_) => (),
+            }
+        }
+    }
+
+    for owner in ctx.db.list_file_owners().await? {
+        if !owners.contains(&owner) {
+            ctx.db.delete_dir(&owner, "").await?;
+        }
+    }
+
+    for owner in owners.iter() {
+        reconcile_owner(ctx, owner).await?;
+    }
+    Ok(())
+}
+
+/// bring index in sync with the contents of `owner`'s tree. Files whose size and
+/// modification time match the index aren't hashed again.
+pub async fn reconcile_owner(ctx: &Ctx, owner: &str) -> ServiceResult<()> {
+    let mut indexed: HashMap<String, FileInfo> = ctx
+        .db
+        .list_files(owner)
+        .await?
+        .into_iter()
+        .map(|f| (f.path.clone(), f))
+        .collect();
+
+    for (path, metadata) in walk(&ctx.settings.files.get_path(owner, "")).await {
+        let size = metadata.len() as i64;
+        let modified = modified(&metadata);
+        let existing = match indexed.remove(&path) {
+            Some(f) if f.size == size && f.modified == modified => continue,
+            existing => existing,
+        };
+
+        let digest = match digest(&ctx.settings.files.get_path(owner, &path)).await {
+            Ok(digest) => digest,
+            Err(e) => {
+                log::warn!("Unable to index {owner}/{path}: {e}");
+                continue;
+            }
+        };
+        let (uploaded, uploader) = existing
+            .map(|f| (f.uploaded, f.uploader))
+            .unwrap_or_else(|| (modified, owner.to_string()));
+        let file = FileInfo {
+            owner: owner.into(),
+            content_type: content_type(&path),
+            path,
+            size,
+            digest,
+            modified,
+            uploaded,
+            uploader,
+        };
+        ctx.db.upsert_file(&file).await?;
+    }
+
+    for path in indexed.keys() {
+        ctx.db.delete_file(owner, path).await?;
+    }
+    Ok(())
+}
+
+/// regular files under `root` with `/` separated paths relative to it. Entries that can't be
+/// read, symlinks and paths that aren't valid UTF-8 are skipped.
+pub async fn walk(root: &Path) -> Vec<(String, Metadata)> {
+    let mut files = Vec::new();
+    let mut dirs = vec![(root.to_path_buf(), String::new())];
+    while let Some((dir, prefix)) = dirs.pop() {
+        let mut read_dir = match fs::read_dir(&dir).await {
+            Ok(read_dir) => read_dir,
+            Err(e) => {
+                log::warn!("Unable to read {:?}: {e}", dir);
+                continue;
+            }
+        };
+        while let Ok(Some(entry)) = read_dir.next_entry().await {
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            let path = if prefix.is_empty() {
+                name
+            } else {
+                format!("{prefix}/{name}")
+            };
+            match entry.metadata().await {
+                Ok(m) if m.is_dir() => dirs.push((entry.path(), path)),
+                Ok(m) if m.is_file() => files.push((path, m)),
+                _ => (),
+            }
+        }
+    }
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn reconcile_works() {
+        const OWNER: &str = "test-reconcile_works";
+        const FILE: &str = "foo/bar.txt";
+
+        let settings = Settings::new().unwrap();
+        let ctx = crate::ctx::Ctx::new(&settings).await;
+        let root = settings.files.get_path(OWNER, "");
+        let _ = fs::remove_dir_all(&root).await;
+
+        let file = settings.files.get_path(OWNER, FILE);
+        fs::create_dir_all(file.parent().unwrap()).await.unwrap();
+        fs::write(&file, b"foo").await.unwrap();
+
+        reconcile_owner(&ctx, OWNER).await.unwrap();
+        let indexed = ctx.db.get_file(OWNER, FILE).await.unwrap().unwrap();
+        assert_eq!(indexed.size, 3);
+        assert_eq!(
+            indexed.digest,
+            "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae"
+        );
+        assert_eq!(indexed.content_type, "text/plain");
+        assert_eq!(indexed.uploader, OWNER);
+
+        fs::remove_file(&file).await.unwrap();
+        reconcile_owner(&ctx, OWNER).await.unwrap();
+        assert!(ctx.db.get_file(OWNER, FILE).await.unwrap().is_none());
+
+        fs::write(&file, b"foo").await.unwrap();
+        reconcile_owner(&ctx, OWNER).await.unwrap();
+        assert_eq!(ctx.db.list_files(OWNER).await.unwrap().len(), 1);
+        fs::remove_dir_all(&root).await.unwrap();
+        reconcile_owner(&ctx, OWNER).await.unwrap();
+        assert!(ctx.db.list_files(OWNER).await.unwrap().is_empty());
+    }
+}
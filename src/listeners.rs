@@ -0,0 +1,495 @@
+/*
+ * Copyright (C) 2022  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Sockets from `server.listeners`: TCP addresses, Unix domain sockets and sockets inherited
+//! through systemd socket activation. Listeners can be restricted to public files or to the API
+//! and web interface, connections carry the [ListenerScope] of the listener that accepted them.
+use std::any::Any;
+use std::future::{ready, Ready};
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpListener};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+
+use actix_tls::accept::rustls::TlsStream;
+use actix_web::body::EitherBody;
+use actix_web::dev::{
+    forward_ready, Extensions, Service, ServiceRequest, ServiceResponse, Transform,
+};
+use actix_web::rt::net::TcpStream;
+use actix_web::{Error, HttpRequest, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+
+use crate::settings::{Listener, ListenerScope, Server};
+use crate::{AppCtx, API_V1_ROUTES, PAGES};
+
+/// first file descriptor passed by systemd socket activation
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+pub enum Socket {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// where a listening socket accepts connections
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Local {
+    Tcp(SocketAddr),
+    Unix(Option<PathBuf>),
+}
+
+impl Local {
+    /// whether a connection to `self` was accepted by a listener bound to `listener`
+    fn accepted_by(&self, listener: &Local) -> bool {
+        match (self, listener) {
+            (Local::Tcp(conn), Local::Tcp(listener)) => {
+                let ip = match conn.ip() {
+                    IpAddr::V6(ip) => ip.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(conn.ip()),
+                    ip => ip,
+                };
+                conn.port() == listener.port()
+                    && (listener.ip().is_unspecified()
+                        || listener.ip() == ip
+                        || listener.ip() == conn.ip())
+            }
+            (Local::Unix(conn), Local::Unix(listener)) => conn == listener,
+            _ => false,
+        }
+    }
+}
+
+/// listening socket from `server.listeners`
+pub struct Bound {
+    pub socket: Socket,
+    pub listener: Listener,
+    local: Local,
+}
+
+impl Bound {
+    /// URL of the listener for log messages
+    pub fn url(&self) -> String {
+        match &self.local {
+            Local::Tcp(addr) if self.listener.tls => format!("https://{addr}"),
+            Local::Tcp(addr) => format!("http://{addr}"),
+            Local::Unix(Some(path)) => format!("unix:{}", path.display()),
+            Local::Unix(None) => "unix socket".into(),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &str, mode: Option<&str>) -> io::Result<UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    // socket of a previous run
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        let mode = u32::from_str_radix(mode, 8).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid mode {mode} of {path}"),
+            )
+        })?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
+
+/// `n`-th socket passed by systemd socket activation
+#[cfg(unix)]
+fn inherited(n: &str) -> io::Result<Socket> {
+    use std::os::unix::io::FromRawFd;
+
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    let n: i32 = n
+        .parse()
+        .map_err(|_| invalid(format!("Invalid socket number {n}")))?;
+    let for_us =
+        matches!(std::env::var("LISTEN_PID"), Ok(pid) if pid == std::process::id().to_string());
+    let count: i32 = match std::env::var("LISTEN_FDS") {
+        Ok(count) if for_us => count.parse().unwrap_or_default(),
+        _ => 0,
+    };
+    if n < 0 || n >= count {
+        return Err(invalid(format!("systemd didn't pass socket {n}")));
+    }
+
+    let fd = SD_LISTEN_FDS_START + n;
+    // SAFETY: all zeroes is a valid `sockaddr_storage`
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    // SAFETY: `addr` is large enough for any address and outlives the call
+    let res = unsafe {
+        libc::getsockname(
+            fd,
+            &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: systemd passes listening sockets to this process, which owns them from now on.
+    // Sockets are only taken once: listener addresses are unique.
+    unsafe {
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        match addr.ss_family as i32 {
+            libc::AF_UNIX => Ok(Socket::Unix(UnixListener::from_raw_fd(fd))),
+            libc::AF_INET | libc::AF_INET6 => Ok(Socket::Tcp(TcpListener::from_raw_fd(fd))),
+            _ => Err(invalid(format!("Unsupported socket {n} from systemd"))),
+        }
+    }
+}
+
+fn bind_listener(listener: &Listener) -> io::Result<Bound> {
+    let unsupported = |msg: &str| io::Error::new(io::ErrorKind::Unsupported, msg.to_string());
+    let socket = if let Some(path) = listener.address.strip_prefix("unix:") {
+        #[cfg(unix)]
+        {
+            Socket::Unix(bind_unix(path, listener.mode.as_deref())?)
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = path;
+            return Err(unsupported(
+                "Unix domain sockets aren't supported on this platform",
+            ));
+        }
+    } else if let Some(n) = listener.address.strip_prefix("systemd:") {
+        #[cfg(unix)]
+        {
+            inherited(n)?
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = n;
+            return Err(unsupported(
+                "Socket activation isn't supported on this platform",
+            ));
+        }
+    } else {
+        Socket::Tcp(TcpListener::bind(&listener.address)?)
+    };
+
+    let local = match &socket {
+        Socket::Tcp(socket) => Local::Tcp(socket.local_addr()?),
+        #[cfg(unix)]
+        Socket::Unix(socket) => {
+            if listener.tls {
+                return Err(unsupported("TLS isn't supported on Unix domain sockets"));
+            }
+            Local::Unix(socket.local_addr()?.as_pathname().map(Into::into))
+        }
+    };
+    Ok(Bound {
+        socket,
+        listener: listener.clone(),
+        local,
+    })
+}
+
+/// bind sockets of `server.listeners`
+pub fn bind(server: &Server) -> io::Result<Vec<Bound>> {
+    let listeners = server.get_listeners();
+    // sockets inherited from systemd must not be owned twice
+    let key = |address: &str| match address.strip_prefix("systemd:").map(str::parse::<i32>) {
+        Some(Ok(n)) => format!("systemd:{n}"),
+        _ => address.to_owned(),
+    };
+    for (i, listener) in listeners.iter().enumerate() {
+        if listeners[..i]
+            .iter()
+            .any(|l| key(&l.address) == key(&listener.address))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Listener {} is configured twice", listener.address),
+            ));
+        }
+    }
+    if server.tls.is_none() {
+        if let Some(listener) = listeners.iter().find(|l| l.tls) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Unable to listen on {}: TLS listeners need [server.tls]",
+                    listener.address
+                ),
+            ));
+        }
+    }
+    listeners
+        .iter()
+        .map(|listener| {
+            bind_listener(listener).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("Unable to listen on {}: {e}", listener.address),
+                )
+            })
+        })
+        .collect()
+}
+
+/// scopes of bound listeners, stored in connections by [Scopes::on_connect]
+pub struct Scopes(Vec<(Local, ListenerScope)>);
+
+impl Scopes {
+    pub fn new(bound: &[Bound]) -> Self {
+        Self(
+            bound
+                .iter()
+                .map(|b| (b.local.clone(), b.listener.scope))
+                .collect(),
+        )
+    }
+
+    /// scope of the listener that accepted connections to `local`. Public when it's unknown.
+    fn find(&self, local: &Local) -> ListenerScope {
+        self.0
+            .iter()
+            .find(|(listener, _)| local.accepted_by(listener))
+            .map(|(_, scope)| *scope)
+            .unwrap_or(ListenerScope::Public)
+    }
+
+    /// [actix_web::HttpServer::on_connect] handler storing the [ListenerScope] and, see
+    /// [crate::tls::on_connect], client certificate of connections
+    pub fn on_connect(&self, conn: &dyn Any, ext: &mut Extensions) {
+        crate::tls::on_connect(conn, ext);
+
+        let local = if let Some(stream) = conn.downcast_ref::<TcpStream>() {
+            stream.local_addr().ok().map(Local::Tcp)
+        } else if let Some(stream) = conn.downcast_ref::<TlsStream<TcpStream>>() {
+            stream.get_ref().0.local_addr().ok().map(Local::Tcp)
+        } else {
+            #[cfg(unix)]
+            {
+                conn.downcast_ref::<actix_web::rt::net::UnixStream>()
+                    .and_then(|stream| stream.local_addr().ok())
+                    .map(|addr| Local::Unix(addr.as_pathname().map(Into::into)))
+            }
+            #[cfg(not(unix))]
+            None
+        };
+        let scope = match local {
+            Some(local) => self.find(&local),
+            None => ListenerScope::Public,
+        };
+        ext.insert(scope);
+    }
+}
+
+/// scope of the listener that accepted the connection of `req`. Connections without one,
+/// which aren't accepted by [Scopes::on_connect], are public unless all listeners serve
+/// everything.
+pub fn scope(req: &HttpRequest) -> ListenerScope {
+    if let Some(scope) = req.conn_data::<ListenerScope>() {
+        return *scope;
+    }
+    match req.app_data::<AppCtx>() {
+        Some(ctx)
+            if ctx
+                .settings
+                .server
+                .get_listeners()
+                .iter()
+                .all(|l| l.scope == ListenerScope::All) =>
+        {
+            ListenerScope::All
+        }
+        _ => ListenerScope::Public,
+    }
+}
+
+/// whether listeners of `scope` serve `path`. Authenticated API routes are turned away by
+/// [crate::api::v1::httpauth] on public listeners.
+fn serves(scope: ListenerScope, path: &str) -> bool {
+    match scope {
+        ListenerScope::All => true,
+        ListenerScope::Public => ![
+            PAGES.login,
+            PAGES.logout,
+            PAGES.files,
+            API_V1_ROUTES.account.register,
+        ]
+        .contains(&path),
+        ListenerScope::Admin => path.starts_with("/api/") || path.starts_with("/web/"),
+    }
+}
+
+/// middleware answering requests outside of the scope of their listener with 404
+pub struct ScopeGuard;
+
+impl<S, B> Transform<S, ServiceRequest> for ScopeGuard
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = ScopeGuardMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ScopeGuardMiddleware { service }))
+    }
+}
+
+pub struct ScopeGuardMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for ScopeGuardMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if !serves(scope(req.request()), req.path()) {
+            let resp = HttpResponse::NotFound().finish();
+            return Box::pin(async move { Ok(req.into_response(resp).map_into_right_body()) });
+        }
+        let fut = self.service.call(req);
+        Box::pin(async move { Ok(fut.await?.map_into_left_body()) })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use actix_web::test;
+
+    use super::*;
+    use crate::ctx::Ctx;
+    use crate::Settings;
+
+    #[actix_rt::test]
+    async fn listeners_work() {
+        let listener = |address: &str, scope| Listener {
+            address: address.into(),
+            mode: Some("600".into()),
+            tls: false,
+            scope,
+        };
+        let dir = std::env::temp_dir().join("dumbserve-listeners_works");
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("dumbserve.sock");
+        let unix = format!("unix:{}", socket.to_str().unwrap());
+
+        let mut bound = vec![
+            bind_listener(&listener("127.0.0.1:0", ListenerScope::Public)).unwrap(),
+            bind_listener(&listener(&unix, ListenerScope::Admin)).unwrap(),
+        ];
+        // stale sockets are replaced
+        bound.pop();
+        bound.push(bind_listener(&listener(&unix, ListenerScope::Admin)).unwrap());
+        assert!(bind_listener(&listener("systemd:0", ListenerScope::All)).is_err());
+        assert!(bind_listener(&listener("localhost", ListenerScope::All)).is_err());
+        let mut tls = listener(&unix, ListenerScope::All);
+        tls.tls = true;
+        assert!(bind_listener(&tls).is_err());
+        // TLS listeners need a certificate
+        let mut server = Settings::new().unwrap().server;
+        server.tls = None;
+        server.listeners = vec![listener("127.0.0.1:0", ListenerScope::All)];
+        assert!(bind(&server).is_ok());
+        server.listeners[0].tls = true;
+        assert!(bind(&server).is_err());
+        // listeners are bound once
+        server.listeners = vec![
+            listener("systemd:0", ListenerScope::All),
+            listener("systemd:00", ListenerScope::Public),
+        ];
+        let e = bind(&server).err().unwrap();
+        assert!(e.to_string().contains("configured twice"));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&socket).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let scopes = Scopes::new(&bound);
+        let tcp = match bound[0].local {
+            Local::Tcp(addr) => addr,
+            _ => unreachable!(),
+        };
+        assert_eq!(scopes.find(&Local::Tcp(tcp)), ListenerScope::Public);
+        assert_eq!(
+            scopes.find(&Local::Unix(Some(socket.clone()))),
+            ListenerScope::Admin
+        );
+        let other = SocketAddr::new(tcp.ip(), tcp.port().wrapping_add(1));
+        assert_eq!(scopes.find(&Local::Tcp(other)), ListenerScope::Public);
+
+        // connections of unknown listeners are public when listeners are scoped
+        let mut settings = Settings::new().unwrap();
+        settings.server.listeners.clear();
+        let ctx = AppCtx::new(Ctx::new(&settings).await);
+        let req = test::TestRequest::get().app_data(ctx).to_http_request();
+        assert_eq!(scope(&req), ListenerScope::All);
+        settings.server.listeners = vec![listener("[::]:7000", ListenerScope::Admin)];
+        let ctx = AppCtx::new(Ctx::new(&settings).await);
+        let req = test::TestRequest::get().app_data(ctx).to_http_request();
+        assert_eq!(scope(&req), ListenerScope::Public);
+        let req = test::TestRequest::get().to_http_request();
+        assert_eq!(scope(&req), ListenerScope::Public);
+
+        let any = Local::Tcp("[::]:7000".parse().unwrap());
+        assert!(Local::Tcp("127.0.0.1:7000".parse().unwrap()).accepted_by(&any));
+        assert!(Local::Tcp("[::ffff:10.0.0.1]:7000".parse().unwrap())
+            .accepted_by(&Local::Tcp("10.0.0.1:7000".parse().unwrap())));
+        assert!(!Local::Tcp("127.0.0.1:7001".parse().unwrap()).accepted_by(&any));
+
+        assert!(serves(ListenerScope::Public, "/owner/releases/app.tar.gz"));
+        assert!(serves(
+            ListenerScope::Public,
+            API_V1_ROUTES.releases.manifest
+        ));
+        assert!(!serves(ListenerScope::Public, PAGES.login));
+        assert!(!serves(
+            ListenerScope::Public,
+            API_V1_ROUTES.account.register
+        ));
+        assert!(serves(
+            ListenerScope::Admin,
+            API_V1_ROUTES.files.upload_file
+        ));
+        assert!(serves(ListenerScope::Admin, PAGES.files));
+        assert!(!serves(ListenerScope::Admin, "/owner/releases/app.tar.gz"));
+
+        drop(bound);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
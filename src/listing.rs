@@ -0,0 +1,357 @@
+/*
+ * Copyright (C) 2022  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Directory listings of the public file service, rendered with the `listing.html` template.
+//! Digests come from the file index, so they are only shown for indexed files.
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::path::Path;
+
+use actix_web::http::StatusCode;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::index::modified;
+use crate::manifest::SIGNATURE_EXTENSIONS;
+use crate::markdown;
+use crate::pages::files::{encode_path, format_time, human_size};
+use crate::pages::render_with;
+use crate::AppCtx;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortBy {
+    Name,
+    Size,
+    Modified,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub struct ListingQuery {
+    pub sort: Option<SortBy>,
+    #[serde(default)]
+    pub desc: bool,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Link {
+    name: String,
+    link: String,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+struct Entry {
+    name: String,
+    link: String,
+    is_dir: bool,
+    #[serde(skip)]
+    bytes: u64,
+    size: String,
+    #[serde(skip)]
+    modified_unix: i64,
+    modified: String,
+    digest: Option<String>,
+    digest_short: Option<String>,
+    signatures: Vec<Link>,
+}
+
+/// digests of indexed files directly in `tree_path`, which is relative to `files.path`
+async fn digests(ctx: &AppCtx, tree_path: &str) -> HashMap<String, String> {
+    let (owner, dir) = match tree_path.split_once('/') {
+        Some((owner, dir)) => (owner, dir),
+        None => (tree_path, ""),
+    };
+    if owner.is_empty() {
+        return HashMap::new();
+    }
+    let prefix = if dir.is_empty() {
+        String::new()
+    } else {
+        format!("{dir}/")
+    };
+    match ctx.db.list_children(owner, dir).await {
+        Ok(files) => files
+            .into_iter()
+            .filter_map(|f| Some((f.path.strip_prefix(&prefix)?.to_owned(), f.digest)))
+            .collect(),
+        Err(e) => {
+            log::warn!("Unable to read index of {tree_path}: {e}");
+            HashMap::new()
+        }
+    }
+}
+
+/// links to `path`, served at URL path `path` under `prefix`, and its parents
+pub fn breadcrumbs(prefix: &str, path: &str) -> Vec<Link> {
+    let mut breadcrumbs = vec![Link {
+        name: "/".into(),
+        link: format!("{prefix}/"),
+    }];
+    let mut crumb_path = String::new();
+    for name in path.split('/').filter(|s| !s.is_empty()) {
+        if !crumb_path.is_empty() {
+            crumb_path.push('/');
+        }
+        crumb_path.push_str(name);
+        breadcrumbs.push(Link {
+            name: name.into(),
+            link: format!("{prefix}/{}", encode_path(&crumb_path)),
+        });
+    }
+    breadcrumbs
+}
+
+fn sort_link(query: &ListingQuery, sort: SortBy) -> String {
+    let name = match sort {
+        SortBy::Name => "name",
+        SortBy::Size => "size",
+        SortBy::Modified => "modified",
+    };
+    if query.sort.unwrap_or(SortBy::Name) == sort && !query.desc {
+        format!("?sort={name}&desc=true")
+    } else {
+        format!("?sort={name}")
+    }
+}
+
+/// render listing of `dir`, served at URL path `path`. `tree_path` is the location of `dir`
+/// relative to `files.path`.
+pub async fn render(
+    req: &HttpRequest,
+    ctx: &AppCtx,
+    dir: &Path,
+    path: &str,
+    tree_path: &str,
+) -> Result<HttpResponse, Error> {
+    let query = web::Query::<ListingQuery>::from_query(req.query_string())
+        .map(|q| q.into_inner())
+        .unwrap_or_default();
+    let digests = digests(ctx, tree_path).await;
+    let prefix = ctx.settings.server.prefix();
+    let link = |name: &str| {
+        if path.is_empty() {
+            format!("{prefix}/{}", encode_path(name))
+        } else {
+            format!("{prefix}/{}/{}", encode_path(path), encode_path(name))
+        }
+    };
+
+    let mut names = Vec::new();
+    let mut read_dir = fs::read_dir(dir).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        match entry.file_name().into_string() {
+            Ok(name) if !name.starts_with('.') => names.push(name),
+            _ => continue,
+        }
+    }
+
+    let mut entries = Vec::with_capacity(names.len());
+    for name in names.iter() {
+        // signatures are listed next to the files they sign
+        let signs = SIGNATURE_EXTENSIONS
+            .iter()
+            .filter_map(|ext| name.strip_suffix(ext))
+            .any(|signed| names.iter().any(|n| n == signed));
+        if signs {
+            continue;
+        }
+        // follows links, like serving does
+        let metadata = match fs::metadata(dir.join(name)).await {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        let digest = digests.get(name).cloned();
+        let signatures = SIGNATURE_EXTENSIONS
+            .iter()
+            .map(|ext| format!("{name}{ext}"))
+            .filter(|signature| names.contains(signature))
+            .map(|signature| Link {
+                link: link(&signature),
+                name: signature,
+            })
+            .collect();
+        entries.push(Entry {
+            name: name.clone(),
+            link: link(name),
+            is_dir: metadata.is_dir(),
+            bytes: metadata.len(),
+            size: human_size(metadata.len()),
+            modified_unix: modified(&metadata),
+            modified: format_time(modified(&metadata)),
+            digest_short: digest.as_ref().map(|d| d[..16.min(d.len())].to_owned()),
+            digest,
+            signatures,
+        });
+    }
+
+    let sort = query.sort.unwrap_or(SortBy::Name);
+    entries.sort_by(|a, b| {
+        let order = match sort {
+            SortBy::Name => Ordering::Equal,
+            SortBy::Size => a.bytes.cmp(&b.bytes),
+            SortBy::Modified => a.modified_unix.cmp(&b.modified_unix),
+        }
+        .then_with(|| a.name.cmp(&b.name));
+        let order = if query.desc { order.reverse() } else { order };
+        b.is_dir.cmp(&a.is_dir).then(order)
+    });
+
+    let readme = dir.join("README.md");
+    let readme = match fs::metadata(&readme).await {
+        Ok(m) if m.is_file() && m.len() <= markdown::MAX_RENDER_SIZE => fs::read_to_string(readme)
+            .await
+            .ok()
+            .map(|readme| markdown::render(&readme)),
+        _ => None,
+    };
+
+    let mut sort_links = HashMap::new();
+    sort_links.insert("name", sort_link(&query, SortBy::Name));
+    sort_links.insert("size", sort_link(&query, SortBy::Size));
+    sort_links.insert("modified", sort_link(&query, SortBy::Modified));
+
+    let mut context = crate::pages::context(&ctx.settings);
+    context.insert("title", &format!("Index of /{path}"));
+    context.insert("path", path);
+    context.insert("breadcrumbs", &breadcrumbs(prefix, path));
+    context.insert("entries", &entries);
+    context.insert("sort_links", &sort_links);
+    context.insert("readme", &readme);
+    Ok(render_with(
+        &ctx.templates,
+        "listing.html",
+        &mut context,
+        StatusCode::OK,
+        "text/html; charset=utf-8",
+    ))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use actix_web::{test, App};
+    use tera::Context;
+
+    use super::*;
+    use crate::db::FileInfo;
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn listing_works() {
+        const OWNER: &str = "test-listing_works";
+
+        let settings = Settings::new().unwrap();
+        let ctx = AppCtx::new(crate::ctx::Ctx::new(&settings).await);
+        let files = [
+            ("releases/app.tar.gz", "app"),
+            ("releases/app.tar.gz.minisig", "signature"),
+            ("releases/notes.txt", "release notes, longest file"),
+            ("releases/README.md", "# Releases & <script>notes</script>"),
+            ("releases/1.0.0/app.tar.gz", "app"),
+        ];
+        for (path, contents) in files {
+            let path = settings.files.get_path(OWNER, path);
+            fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+            fs::write(path, contents).await.unwrap();
+        }
+        ctx.db.delete_dir(OWNER, "").await.unwrap();
+        let file = FileInfo {
+            owner: OWNER.into(),
+            path: "releases/app.tar.gz".into(),
+            size: 3,
+            digest: "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae".into(),
+            content_type: crate::index::content_type("app.tar.gz"),
+            modified: 0,
+            uploaded: 0,
+            uploader: OWNER.into(),
+        };
+        ctx.db.upsert_file(&file).await.unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(ctx.clone())
+                .configure(crate::routes::services),
+        )
+        .await;
+        let list = |query: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/{OWNER}/releases{query}"))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, list("")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains("<h1>Releases &amp; </h1>"));
+        assert!(body.contains("2c26b46b68ffc68f"));
+        // links are escaped, `/` included
+        assert!(body.contains(&format!(
+            "href=\"&#x2F;{OWNER}&#x2F;releases&#x2F;app.tar.gz.minisig\">app.tar.gz.minisig</a>)"
+        )));
+        let position = |name: &str| body.find(&format!(">{name}")).unwrap();
+        assert!(position("1.0.0/") < position("app.tar.gz<"));
+        assert!(position("README.md") < position("app.tar.gz<"));
+        assert!(position("notes.txt") < body.find("<h1>").unwrap());
+        assert!(position("app.tar.gz<") < position("notes.txt"));
+
+        let resp = test::call_service(&app, list("?sort=size&desc=true")).await;
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        let position = |name: &str| body.find(&format!(">{name}")).unwrap();
+        assert!(position("notes.txt") < position("app.tar.gz<"));
+        assert!(body.contains("href=\"?sort=size\""));
+
+        // markdown files are rendered up to the size of READMEs and served as is above it
+        let large = format!("# Large\n{}", "text ".repeat(20 * 1024));
+        fs::write(settings.files.get_path(OWNER, "releases/large.md"), &large)
+            .await
+            .unwrap();
+        for (name, rendered) in [("README.md", true), ("large.md", false)] {
+            let resp = test::call_service(
+                &app,
+                test::TestRequest::get()
+                    .uri(&format!("/{OWNER}/releases/{name}"))
+                    .insert_header((actix_web::http::header::ACCEPT, "text/html"))
+                    .to_request(),
+            )
+            .await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let body = test::read_body(resp).await;
+            assert_eq!(body.starts_with(b"# "), !rendered, "{name}");
+        }
+
+        // templates can be overridden
+        let templates = std::env::temp_dir().join("dumbserve-listing_works");
+        fs::create_dir_all(&templates).await.unwrap();
+        fs::write(templates.join("listing.html"), "custom {{ title }}")
+            .await
+            .unwrap();
+        let mut settings = settings.clone();
+        settings.templates = Some(templates.to_str().unwrap().into());
+        let tera = crate::pages::load_templates(&settings).unwrap();
+        let mut context = Context::new();
+        context.insert("title", "listing");
+        assert_eq!(
+            tera.render("listing.html", &context).unwrap(),
+            "custom listing"
+        );
+        fs::remove_dir_all(templates).await.unwrap();
+
+        ctx.db.delete_dir(OWNER, "").await.unwrap();
+        fs::remove_dir_all(settings.files.get_path(OWNER, ""))
+            .await
+            .unwrap();
+    }
+}
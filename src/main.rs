@@ -15,8 +15,9 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 use std::env;
+use std::sync::Arc;
 
-use actix_files::Files;
+use actix_web::dev::Service;
 use actix_web::http::StatusCode;
 use actix_web::web::JsonConfig;
 use actix_web::{error::InternalError, middleware, App, HttpServer};
@@ -25,26 +26,39 @@ use log::info;
 use lazy_static::lazy_static;
 
 mod api;
+mod archive;
+mod caching;
+mod compression;
 mod ctx;
-//mod db;
+mod db;
 //mod docs;
 #[cfg(not(tarpaulin_include))]
 mod errors;
-//#[macro_use]
-//mod pages;
+mod extract;
+mod headers;
+mod index;
+mod listeners;
+mod listing;
+mod manifest;
+mod markdown;
+mod pages;
+mod proxy;
 //#[macro_use]
 mod routes;
+mod serve;
 mod settings;
+mod signatures;
+mod signing;
+mod tls;
 //mod static_assets;
-//#[cfg(test)]
-//#[macro_use]
-//mod tests;
+#[cfg(test)]
+mod tests;
 //
 pub use crate::ctx::Ctx;
 //pub use crate::static_assets::static_files::assets::*;
 pub use api::v1::API_V1_ROUTES;
 //pub use docs::DOCS;
-//pub use pages::routes::ROUTES as PAGES;
+pub use pages::ROUTES as PAGES;
 pub use settings::Settings;
 //use static_assets::FileMap;
 
@@ -88,28 +102,64 @@ async fn main() -> std::io::Result<()> {
     let ctx = Ctx::new(&settings).await;
     let ctx = actix_web::web::Data::new(ctx);
 
-    let ip = settings.server.get_ip();
-    let upload_path = settings.files.path;
-    println!("Starting server on: http://{ip}");
+    api::v1::publish::spawn_cleaner(ctx.clone());
+    {
+        let ctx = ctx.clone();
+        actix_web::rt::spawn(async move {
+            info!("Reconciling file index with {}", ctx.settings.files.path);
+            match index::reconcile(&ctx).await {
+                Ok(_) => info!("File index reconciled"),
+                Err(e) => log::error!("Unable to reconcile file index: {e}"),
+            }
+        });
+    }
+
+    let tls = settings.server.tls.as_ref().map(|tls| {
+        let resolver = Arc::new(tls::CertResolver::new(tls).unwrap_or_else(|e| panic!("{e}")));
+        tls::spawn_reloader(resolver.clone());
+        tls::server_config(tls, resolver).unwrap_or_else(|e| panic!("{e}"))
+    });
+    let bound = listeners::bind(&settings.server)?;
+    let scopes = listeners::Scopes::new(&bound);
 
-    HttpServer::new(move || {
+    let mut server = HttpServer::new(move || {
         App::new()
             .wrap(middleware::Logger::default())
             .wrap(
                 middleware::DefaultHeaders::new().add(("Permissions-Policy", "interest-cohort=()")),
             )
             .wrap(middleware::Compress::default())
+            .wrap(headers::ResponseHeaders)
             .app_data(ctx.clone())
+            .wrap(pages::get_identity_service(&settings))
+            .wrap(listeners::ScopeGuard)
+            .wrap(routes::UrlPrefix)
             .wrap(middleware::NormalizePath::new(
                 middleware::TrailingSlash::Trim,
             ))
+            .wrap_fn(|req, srv| {
+                serve::mark_trailing_slash(&req);
+                srv.call(req)
+            })
+            .wrap(proxy::Proxy)
             .app_data(get_json_err())
             .configure(routes::services)
-            .service(Files::new("/", &upload_path).show_files_listing())
     })
-    .bind(ip)?
-    .run()
-    .await
+    .on_connect(move |conn, ext| scopes.on_connect(conn, ext));
+    for b in bound {
+        println!("Starting server on: {}", b.url());
+        server = match b.socket {
+            listeners::Socket::Tcp(socket) if b.listener.tls => {
+                // rejected by listeners::bind without [server.tls]
+                let config = tls.clone().ok_or(std::io::ErrorKind::InvalidInput)?;
+                server.listen_rustls(socket, config)?
+            }
+            listeners::Socket::Tcp(socket) => server.listen(socket)?,
+            #[cfg(unix)]
+            listeners::Socket::Unix(socket) => server.listen_uds(socket)?,
+        };
+    }
+    server.run().await
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -0,0 +1,312 @@
+/*
+ * Copyright (C) 2022  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Release manifests, generated from the file index. Platforms of artifacts are parsed from
+//! names like `dumbserve-0.1.0-linux-amd64.tar.gz`, as produced by `scripts/publish.sh`, and
+//! signatures and checksums are attached to the artifact they were named after.
+use std::collections::HashSet;
+
+use semver::Version;
+use serde::{Deserialize, Serialize};
+
+use crate::ctx::Ctx;
+use crate::db::{Channel, FileInfo, Release};
+use crate::errors::*;
+use crate::pages::files::file_url;
+
+/// longest match first, `.tar.gz` must be tried before `.gz`
+const ARCHIVE_EXTENSIONS: [&str; 12] = [
+    ".tar.gz", ".tar.xz", ".tar.bz2", ".tar.zst", ".tgz", ".zip", ".gz", ".xz", ".exe", ".deb",
+    ".rpm", ".apk",
+];
+pub const SIGNATURE_EXTENSIONS: [&str; 3] = [".asc", ".sig", ".minisig"];
+pub const CHECKSUM_EXTENSIONS: [&str; 2] = [".sha256", ".sha512"];
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Artifact {
+    /// path relative to release directory
+    pub name: String,
+    pub url: String,
+    pub size: i64,
+    /// hex encoded SHA-256 digest
+    pub digest: String,
+    pub content_type: String,
+    pub os: Option<String>,
+    pub arch: Option<String>,
+    /// URLs of detached signatures
+    pub signatures: Vec<String>,
+    /// URLs of checksum files
+    pub checksums: Vec<String>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct ReleaseManifest {
+    pub version: String,
+    pub channel: Channel,
+    /// UNIX timestamp of publication
+    pub published: i64,
+    pub artifacts: Vec<Artifact>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Manifest {
+    pub owner: String,
+    pub project: String,
+    /// newest first
+    pub releases: Vec<ReleaseManifest>,
+}
+
+/// use the names Go and Debian use for common architectures, so `x86_64` in a Rust target
+/// matches `amd64` in artifact names
+pub fn normalize_arch(arch: &str) -> String {
+    let arch = arch.to_lowercase();
+    match arch.as_str() {
+        "x86_64" | "x64" => "amd64".into(),
+        "aarch64" => "arm64".into(),
+        "i386" | "i686" | "x86" => "386".into(),
+        _ => arch,
+    }
+}
+
+/// `(os, arch)` of `target`. Accepts `<os>-<arch>`, as used in artifact names, and Rust
+/// target triples like `x86_64-unknown-linux-gnu`.
+pub fn parse_target(target: &str) -> Option<(String, String)> {
+    const OSES: [&str; 7] = [
+        "linux", "darwin", "windows", "freebsd", "netbsd", "openbsd", "android",
+    ];
+    let parts: Vec<&str> = target.split('-').collect();
+    match parts.as_slice() {
+        [os, arch] if !os.is_empty() && !arch.is_empty() => {
+            Some((os.to_lowercase(), normalize_arch(arch)))
+        }
+        [arch, rest @ ..] if rest.len() >= 2 => rest
+            .iter()
+            .find(|p| OSES.contains(p))
+            .map(|os| (os.to_string(), normalize_arch(arch))),
+        _ => None,
+    }
+}
+
+/// platform of artifact `name` of release `version`, from names like
+/// `<project>-<version>-<os>-<arch>.<extension>`
+pub fn artifact_target(name: &str, version: &str) -> Option<(String, String)> {
+    let name = name.rsplit('/').next().unwrap();
+    let name = ARCHIVE_EXTENSIONS
+        .iter()
+        .find_map(|ext| name.strip_suffix(ext))
+        .unwrap_or(name);
+    [format!("-{version}-"), format!("-v{version}-")]
+        .iter()
+        .find_map(|v| name.find(v.as_str()).map(|i| &name[i + v.len()..]))
+        .and_then(parse_target)
+}
+
+/// manifest of `release` from `files` stored in its directory, linking to files under URL path
+/// `prefix`
+pub fn build_release_manifest(
+    prefix: &str,
+    release: &Release,
+    files: &[FileInfo],
+) -> ReleaseManifest {
+    let dir = format!("{}/{}/", release.project, release.version);
+    let names: HashSet<&str> = files
+        .iter()
+        .filter_map(|f| f.path.strip_prefix(&dir))
+        .collect();
+    // `None` when file is an artifact, name of artifact when it's a signature or a checksum
+    let attached_to = |name: &str, extensions: &[&str]| {
+        extensions
+            .iter()
+            .filter_map(|ext| name.strip_suffix(ext))
+            .find(|artifact| names.contains(artifact))
+            .map(|artifact| artifact.to_string())
+    };
+
+    let mut artifacts: Vec<Artifact> = Vec::new();
+    let mut signatures = Vec::new();
+    let mut checksums = Vec::new();
+    for file in files.iter() {
+        let name = match file.path.strip_prefix(&dir) {
+            Some(name) => name,
+            None => continue,
+        };
+        let url = file_url(prefix, &file.owner, &file.path);
+        if let Some(artifact) = attached_to(name, &SIGNATURE_EXTENSIONS) {
+            signatures.push((artifact, url));
+        } else if let Some(artifact) = attached_to(name, &CHECKSUM_EXTENSIONS) {
+            checksums.push((artifact, url));
+        } else {
+            let (os, arch) = match artifact_target(name, &release.version) {
+                Some((os, arch)) => (Some(os), Some(arch)),
+                None => (None, None),
+            };
+            artifacts.push(Artifact {
+                name: name.into(),
+                url,
+                size: file.size,
+                digest: file.digest.clone(),
+                content_type: file.content_type.clone(),
+                os,
+                arch,
+                signatures: Vec::new(),
+                checksums: Vec::new(),
+            });
+        }
+    }
+
+    for artifact in artifacts.iter_mut() {
+        for (name, url) in signatures.iter() {
+            if name == &artifact.name {
+                artifact.signatures.push(url.clone());
+            }
+        }
+        for (name, url) in checksums.iter() {
+            if name == &artifact.name {
+                artifact.checksums.push(url.clone());
+            }
+        }
+    }
+
+    ReleaseManifest {
+        version: release.version.clone(),
+        channel: release.channel,
+        published: release.published,
+        artifacts,
+    }
+}
+
+/// manifest of `release` from the file index
+pub async fn release_manifest(ctx: &Ctx, release: &Release) -> ServiceResult<ReleaseManifest> {
+    let dir = format!("{}/{}", release.project, release.version);
+    let files = ctx.db.list_dir(&release.owner, &dir).await?;
+    Ok(build_release_manifest(
+        ctx.settings.server.prefix(),
+        release,
+        &files,
+    ))
+}
+
+/// sort releases by semver precedence, newest first
+pub fn sort_releases(releases: &mut [Release]) {
+    releases.sort_by_cached_key(|r| std::cmp::Reverse(Version::parse(&r.version).ok()));
+}
+
+/// newest release in `manifests` that is newer than `current` and has an artifact for
+/// `(os, arch)`. `manifests` must be sorted newest first.
+pub fn find_update<'a>(
+    manifests: &'a [ReleaseManifest],
+    current: &Version,
+    os: &str,
+    arch: &str,
+) -> Option<(&'a ReleaseManifest, &'a Artifact)> {
+    manifests
+        .iter()
+        .filter(|m| matches!(Version::parse(&m.version), Ok(v) if &v > current))
+        .find_map(|m| {
+            m.artifacts
+                .iter()
+                .find(|a| a.os.as_deref() == Some(os) && a.arch.as_deref() == Some(arch))
+                .map(|a| (m, a))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str) -> FileInfo {
+        FileInfo {
+            owner: "owner".into(),
+            path: path.into(),
+            size: 3,
+            digest: "digest".into(),
+            content_type: crate::index::content_type(path),
+            modified: 0,
+            uploaded: 0,
+            uploader: "owner".into(),
+        }
+    }
+
+    #[test]
+    fn targets_work() {
+        let target = |os: &str, arch: &str| Some((os.to_string(), arch.to_string()));
+        assert_eq!(parse_target("linux-amd64"), target("linux", "amd64"));
+        assert_eq!(
+            parse_target("x86_64-unknown-linux-gnu"),
+            target("linux", "amd64")
+        );
+        assert_eq!(
+            parse_target("aarch64-apple-darwin"),
+            target("darwin", "arm64")
+        );
+        assert_eq!(parse_target("linux"), None);
+
+        assert_eq!(
+            artifact_target("dumbserve-1.0.0-rc.1-linux-amd64.tar.gz", "1.0.0-rc.1"),
+            target("linux", "amd64")
+        );
+        assert_eq!(
+            artifact_target("dumbserve-v1.0.0-windows-x86_64.zip", "1.0.0"),
+            target("windows", "amd64")
+        );
+        assert_eq!(artifact_target("README.md", "1.0.0"), None);
+    }
+
+    #[test]
+    fn manifests_work() {
+        let release = |version: &str| Release {
+            owner: "owner".into(),
+            project: "dumbserve".into(),
+            version: version.into(),
+            channel: Channel::Stable,
+            published: 0,
+            publisher: "owner".into(),
+        };
+        let files = [
+            file("dumbserve/1.0.0/dumbserve-1.0.0-linux-amd64.tar.gz"),
+            file("dumbserve/1.0.0/dumbserve-1.0.0-linux-amd64.tar.gz.asc"),
+            file("dumbserve/1.0.0/dumbserve-1.0.0-linux-amd64.tar.gz.sha256"),
+            file("dumbserve/1.0.0/dumbserve-1.0.0-linux-arm64.tar.gz"),
+            file("dumbserve/1.0.0/orphan.sig"),
+        ];
+        let manifest = build_release_manifest("", &release("1.0.0"), &files);
+        assert_eq!(manifest.artifacts.len(), 3);
+        let artifact = &manifest.artifacts[0];
+        assert_eq!(artifact.name, "dumbserve-1.0.0-linux-amd64.tar.gz");
+        assert_eq!(artifact.os.as_deref(), Some("linux"));
+        assert_eq!(artifact.arch.as_deref(), Some("amd64"));
+        assert_eq!(
+            artifact.signatures,
+            ["/owner/dumbserve/1.0.0/dumbserve-1.0.0-linux-amd64.tar.gz.asc"]
+        );
+        assert_eq!(artifact.checksums.len(), 1);
+        assert_eq!(manifest.artifacts[2].name, "orphan.sig");
+
+        let older = build_release_manifest(
+            "",
+            &release("0.9.0"),
+            &[file("dumbserve/0.9.0/dumbserve-0.9.0-linux-amd64.tar.gz")],
+        );
+        let manifests = [manifest, older];
+        let current = Version::parse("0.9.0").unwrap();
+        let (m, a) = find_update(&manifests, &current, "linux", "arm64").unwrap();
+        assert_eq!(m.version, "1.0.0");
+        assert_eq!(a.arch.as_deref(), Some("arm64"));
+        assert!(find_update(&manifests, &current, "darwin", "arm64").is_none());
+        let current = Version::parse("1.0.0").unwrap();
+        assert!(find_update(&manifests, &current, "linux", "amd64").is_none());
+    }
+}
@@ -0,0 +1,129 @@
+/*
+ * Copyright (C) 2022  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Markdown files rendered to sanitized HTML. Browsers get rendered pages, other clients and
+//! requests with `?raw=1` get the file as is.
+use std::path::Path;
+
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use pulldown_cmark::{html, Options, Parser};
+use serde::Deserialize;
+use tokio::fs;
+
+use crate::listing::breadcrumbs;
+use crate::pages::render_with;
+use crate::AppCtx;
+
+/// largest markdown file rendered, READMEs shown above listings included. Larger files are
+/// served as is.
+pub const MAX_RENDER_SIZE: u64 = 64 * 1024;
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+pub struct RenderQuery {
+    pub render: Option<String>,
+    pub raw: Option<String>,
+}
+
+/// query flags are set unless they are `0` or `false`
+fn is_set(flag: &Option<String>) -> bool {
+    matches!(flag.as_deref(), Some(value) if value != "0" && value != "false")
+}
+
+pub fn is_markdown(name: &str) -> bool {
+    let name = name.to_lowercase();
+    name.ends_with(".md") || name.ends_with(".markdown")
+}
+
+/// `req` asked for a rendered page, with `?render=1` or by accepting HTML
+pub fn wants_html(req: &HttpRequest) -> bool {
+    let query = web::Query::<RenderQuery>::from_query(req.query_string())
+        .map(|q| q.into_inner())
+        .unwrap_or_default();
+    if is_set(&query.raw) {
+        return false;
+    }
+    is_set(&query.render)
+        || matches!(
+            req.headers().get(header::ACCEPT).map(|accept| accept.to_str()),
+            Some(Ok(accept)) if accept.contains("text/html")
+        )
+}
+
+/// render `markdown` to HTML with scripts, event handlers and other unsafe markup removed
+pub fn render(markdown: &str) -> String {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS;
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, Parser::new_ext(markdown, options));
+    ammonia::clean(&unsafe_html)
+}
+
+/// page with markdown file `file`, served at URL path `path`, rendered with the
+/// `markdown.html` template
+pub async fn render_page(ctx: &AppCtx, file: &Path, path: &str) -> Result<HttpResponse, Error> {
+    let markdown = fs::read(file).await?;
+    let mut context = crate::pages::context(&ctx.settings);
+    context.insert("title", path.rsplit('/').next().unwrap_or_default());
+    context.insert("path", path);
+    context.insert(
+        "breadcrumbs",
+        &breadcrumbs(ctx.settings.server.prefix(), path),
+    );
+    context.insert("html", &render(&String::from_utf8_lossy(&markdown)));
+    let mut resp = render_with(
+        &ctx.templates,
+        "markdown.html",
+        &mut context,
+        StatusCode::OK,
+        "text/html; charset=utf-8",
+    );
+    resp.headers_mut()
+        .insert(header::VARY, header::HeaderValue::from_static("accept"));
+    Ok(resp)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use actix_web::test;
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn markdown_works() {
+        let html = render("# Title\n\n<script>alert(1)</script>\n\n[link](javascript:alert(1))");
+        assert!(html.contains("<h1>Title</h1>"));
+        assert!(!html.contains("script"));
+        assert!(!html.contains("javascript"));
+
+        assert!(is_markdown("README.MD"));
+        assert!(!is_markdown("app.tar.gz"));
+
+        let req = |uri: &str, accept: &str| {
+            test::TestRequest::get()
+                .uri(uri)
+                .insert_header((header::ACCEPT, accept))
+                .to_http_request()
+        };
+        assert!(wants_html(&req("/README.md", "text/html,*/*")));
+        assert!(wants_html(&req("/README.md?render=1", "*/*")));
+        assert!(!wants_html(&req("/README.md", "*/*")));
+        assert!(!wants_html(&req("/README.md?raw=1", "text/html")));
+        assert!(!wants_html(&req("/README.md?render=0", "*/*")));
+    }
+}
@@ -0,0 +1,69 @@
+/*
+ * Copyright (C) 2022  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use super::{context, redirect, render, ROUTES};
+use crate::AppCtx;
+use actix_identity::Identity;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+pub fn services(cfg: &mut web::ServiceConfig) {
+    cfg.service(login);
+    cfg.service(login_submit);
+    cfg.service(logout);
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Login {
+    pub username: String,
+    pub password: String,
+}
+
+#[actix_web_codegen_const_routes::get(path = "ROUTES.login")]
+async fn login(id: Identity, ctx: AppCtx) -> HttpResponse {
+    let session_user = match id.identity() {
+        Some(identity) => ctx.session_user(&identity).await,
+        None => None,
+    };
+    if session_user.is_some() {
+        return redirect(&ctx.settings.server.url_path(ROUTES.files));
+    }
+    render("login.html", &mut context(&ctx.settings), StatusCode::OK)
+}
+
+#[actix_web_codegen_const_routes::post(path = "ROUTES.login")]
+async fn login_submit(id: Identity, ctx: AppCtx, payload: web::Form<Login>) -> HttpResponse {
+    let identity = if ctx.authenticate(&payload.username, &payload.password).await {
+        ctx.session_identity(&payload.username).await
+    } else {
+        None
+    };
+    if let Some(identity) = identity {
+        id.remember(identity);
+        redirect(&ctx.settings.server.url_path(ROUTES.files))
+    } else {
+        let mut context = context(&ctx.settings);
+        context.insert("error", "Wrong username or password");
+        render("login.html", &mut context, StatusCode::UNAUTHORIZED)
+    }
+}
+
+#[actix_web_codegen_const_routes::post(path = "ROUTES.logout")]
+async fn logout(id: Identity, ctx: AppCtx) -> HttpResponse {
+    id.forget();
+    redirect(&ctx.settings.server.url_path(ROUTES.login))
+}
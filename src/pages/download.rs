@@ -0,0 +1,177 @@
+/*
+ * Copyright (C) 2022  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Download directories as `.zip` or `.tar.gz` archives streamed on the fly. Serves what the
+//! public file mount serves: hidden files and symbolic links are left out.
+use std::path::Path;
+
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::{web, HttpResponse};
+
+use super::ROUTES;
+use crate::archive::{stream, Format};
+use crate::errors::*;
+use crate::index::walk;
+use crate::pages::files::encode_path;
+use crate::serve::is_hidden;
+use crate::settings::clean_path;
+use crate::AppCtx;
+
+pub fn services(cfg: &mut web::ServiceConfig) {
+    cfg.service(download);
+}
+
+/// link to download `path`, which is `<owner>` or `<owner>/<dir>`, as `format`, under URL
+/// path `prefix`
+pub fn download_link(prefix: &str, format: Format, path: &str) -> String {
+    let route = ROUTES
+        .download
+        .replace("{format}", format.extension())
+        .replace("{path:.*}", &encode_path(path));
+    format!("{prefix}{route}")
+}
+
+#[actix_web_codegen_const_routes::get(path = "ROUTES.download")]
+async fn download(ctx: AppCtx, path: web::Path<(String, String)>) -> ServiceResult<HttpResponse> {
+    let (format, path) = path.into_inner();
+    let format = match format.as_str() {
+        "zip" => Format::Zip,
+        "tar.gz" => Format::TarGz,
+        _ => return Ok(HttpResponse::NotFound().body("format not found".to_string())),
+    };
+    let path = clean_path(&path);
+    let root = Path::new(&ctx.settings.files.path).join(&path);
+    if path.is_empty() || is_hidden(&path) || !root.is_dir() {
+        return Ok(HttpResponse::NotFound().body("dir not found".to_string()));
+    }
+
+    let mut files = walk(&root).await;
+    files.retain(|(name, _)| !is_hidden(name));
+    files.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let size: u64 = files.iter().map(|(_, m)| m.len()).sum();
+    let max_size = ctx.settings.files.download.max_size.min(format.max_size());
+    if size > max_size || files.len() > format.max_files() {
+        return Err(ServiceError::DirectoryTooLarge);
+    }
+
+    let permit = ctx
+        .archive_streams
+        .clone()
+        .try_acquire_owned()
+        .map_err(|_| ServiceError::TooManyDownloads)?;
+
+    let name = path.rsplit('/').next().unwrap_or_default();
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format!(
+                "{name}.{}",
+                format.extension()
+            ))],
+        })
+        .streaming(stream(root, files, format, permit)))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::io::{Cursor, Read};
+
+    use actix_web::http::{header, StatusCode};
+    use actix_web::{test, App};
+
+    use super::*;
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn download_works() {
+        const OWNER: &str = "test-download_works";
+
+        let mut settings = Settings::new().unwrap();
+        settings.files.download.max_size = 1024;
+        let dir = settings.files.get_path(OWNER, "releases/1.0.0");
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        tokio::fs::write(dir.join("app.tar.gz"), "foo")
+            .await
+            .unwrap();
+        tokio::fs::write(dir.join(".secret"), "foo").await.unwrap();
+
+        let ctx = AppCtx::new(crate::ctx::Ctx::new(&settings).await);
+        let app = test::init_service(
+            App::new()
+                .app_data(ctx.clone())
+                .configure(crate::routes::services),
+        )
+        .await;
+        let get = |uri: String| test::TestRequest::get().uri(&uri).to_request();
+
+        let resp = test::call_service(
+            &app,
+            get(download_link("", Format::Zip, &format!("{OWNER}/releases"))),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_DISPOSITION).unwrap(),
+            "attachment; filename=\"releases.zip\""
+        );
+        let body = test::read_body(resp).await;
+        let mut zip = zip::ZipArchive::new(Cursor::new(body.to_vec())).unwrap();
+        assert_eq!(zip.len(), 1);
+        let mut contents = String::new();
+        zip.by_name("1.0.0/app.tar.gz")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "foo");
+
+        let resp = test::call_service(&app, get(download_link("", Format::TarGz, OWNER))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/gzip"
+        );
+
+        for path in [".staging".to_string(), format!("{OWNER}/nonexistent")] {
+            let resp = test::call_service(&app, get(download_link("", Format::Zip, &path))).await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        }
+
+        tokio::fs::write(dir.join("large"), vec![0; 1024])
+            .await
+            .unwrap();
+        let resp = test::call_service(&app, get(download_link("", Format::Zip, OWNER))).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        tokio::fs::remove_file(dir.join("large")).await.unwrap();
+
+        // streams in progress hold permits until their archive is complete
+        let permits = ctx
+            .archive_streams
+            .clone()
+            .acquire_many_owned(settings.files.download.max_streams as u32)
+            .await
+            .unwrap();
+        let resp = test::call_service(&app, get(download_link("", Format::Zip, OWNER))).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        drop(permits);
+        let resp = test::call_service(&app, get(download_link("", Format::Zip, OWNER))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        tokio::fs::remove_dir_all(settings.files.get_path(OWNER, ""))
+            .await
+            .unwrap();
+    }
+}
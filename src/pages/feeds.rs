@@ -0,0 +1,247 @@
+/*
+ * Copyright (C) 2022  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Atom and RSS feeds of new uploads in a user's tree or one of its directories, generated
+//! from the file index
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+use sqlx::types::time::OffsetDateTime;
+use tera::Context;
+
+use super::{render_as, ROUTES};
+use crate::db::{FileFilter, SortBy};
+use crate::errors::*;
+use crate::pages::files::{encode_path, file_url, human_size};
+use crate::serve::is_hidden;
+use crate::settings::clean_path;
+use crate::AppCtx;
+
+/// number of uploads listed in a feed
+pub const FEED_ENTRIES: usize = 50;
+
+pub fn services(cfg: &mut web::ServiceConfig) {
+    cfg.service(atom);
+    cfg.service(rss);
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+struct FeedEntry {
+    name: String,
+    id: String,
+    url: String,
+    size: String,
+    digest: String,
+    uploader: String,
+    updated: String,
+    updated_rfc2822: String,
+}
+
+/// link to feed `route` of `path`, which is `<owner>` or `<owner>/<dir>`, under URL path
+/// `prefix`
+pub fn feed_link(prefix: &str, route: &str, path: &str) -> String {
+    format!("{prefix}{}", route.replace("{path:.*}", &encode_path(path)))
+}
+
+fn rfc3339(unix: i64) -> String {
+    OffsetDateTime::from_unix_timestamp(unix).format("%Y-%m-%dT%H:%M:%SZ")
+}
+
+fn rfc2822(unix: i64) -> String {
+    OffsetDateTime::from_unix_timestamp(unix).format("%a, %d %b %Y %H:%M:%S +0000")
+}
+
+/// template context shared by both feed formats
+async fn feed_context(ctx: &AppCtx, path: &str, route: &str) -> ServiceResult<Option<Context>> {
+    let path = clean_path(path);
+    let (owner, dir) = match path.split_once('/') {
+        Some((owner, dir)) => (owner, dir),
+        None => (path.as_str(), ""),
+    };
+    if owner.is_empty() || is_hidden(&path) || !ctx.settings.files.get_path(owner, dir).is_dir() {
+        return Ok(None);
+    }
+
+    let prefix = format!("{dir}/");
+    // hidden files aren't served, so they aren't announced either
+    let filter = FileFilter {
+        owner: Some(owner.into()),
+        path_prefix: Some(prefix.clone()).filter(|_| !dir.is_empty()),
+        sort: Some(SortBy::Uploaded),
+        descending: true,
+        exclude_hidden: true,
+        ..Default::default()
+    };
+    let files = ctx
+        .db
+        .search_files(&filter, |_| true, 0, FEED_ENTRIES)
+        .await?;
+
+    let base = ctx.settings.server.get_url();
+    let url_prefix = ctx.settings.server.prefix();
+    let entries: Vec<FeedEntry> = files
+        .iter()
+        .map(|f| {
+            let url = format!("{base}{}", file_url(url_prefix, &f.owner, &f.path));
+            FeedEntry {
+                name: f.path.strip_prefix(&prefix).unwrap_or(&f.path).into(),
+                id: format!("{url}#{}", f.digest),
+                url,
+                size: human_size(f.size as u64),
+                digest: f.digest.clone(),
+                uploader: f.uploader.clone(),
+                updated: rfc3339(f.uploaded),
+                updated_rfc2822: rfc2822(f.uploaded),
+            }
+        })
+        .collect();
+    let updated = files
+        .first()
+        .map(|f| f.uploaded)
+        .unwrap_or_else(crate::db::now_unix);
+
+    let mut context = Context::new();
+    context.insert("title", &format!("New uploads in {path}"));
+    context.insert("path", &path);
+    context.insert(
+        "link",
+        &format!("{base}{url_prefix}/{}", encode_path(&path)),
+    );
+    context.insert(
+        "self_link",
+        &format!("{base}{}", feed_link(url_prefix, route, &path)),
+    );
+    context.insert("updated", &rfc3339(updated));
+    context.insert("updated_rfc2822", &rfc2822(updated));
+    context.insert("entries", &entries);
+    Ok(Some(context))
+}
+
+#[actix_web_codegen_const_routes::get(path = "ROUTES.atom")]
+async fn atom(ctx: AppCtx, path: web::Path<String>) -> ServiceResult<HttpResponse> {
+    match feed_context(&ctx, &path, ROUTES.atom).await? {
+        Some(mut context) => Ok(render_as(
+            "atom.xml",
+            &mut context,
+            StatusCode::OK,
+            "application/atom+xml; charset=utf-8",
+        )),
+        None => Ok(HttpResponse::NotFound().body("dir not found".to_string())),
+    }
+}
+
+#[actix_web_codegen_const_routes::get(path = "ROUTES.rss")]
+async fn rss(ctx: AppCtx, path: web::Path<String>) -> ServiceResult<HttpResponse> {
+    match feed_context(&ctx, &path, ROUTES.rss).await? {
+        Some(mut context) => Ok(render_as(
+            "rss.xml",
+            &mut context,
+            StatusCode::OK,
+            "application/rss+xml; charset=utf-8",
+        )),
+        None => Ok(HttpResponse::NotFound().body("dir not found".to_string())),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+
+    use super::*;
+    use crate::db::FileInfo;
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn feeds_work() {
+        const OWNER: &str = "test-feeds_work";
+
+        let settings = Settings::new().unwrap();
+        let ctx = AppCtx::new(crate::ctx::Ctx::new(&settings).await);
+        ctx.db.delete_dir(OWNER, "").await.unwrap();
+        tokio::fs::create_dir_all(settings.files.get_path(OWNER, "releases"))
+            .await
+            .unwrap();
+        for (path, uploaded) in [
+            ("releases/app<1>.tar.gz", 2),
+            ("notes.txt", 1),
+            (".env", 3),
+            ("releases/.cache/app.tar.gz", 3),
+        ] {
+            let file = FileInfo {
+                owner: OWNER.into(),
+                path: path.into(),
+                size: 2048,
+                digest: "digest".into(),
+                content_type: crate::index::content_type(path),
+                modified: uploaded,
+                uploaded,
+                uploader: OWNER.into(),
+            };
+            ctx.db.upsert_file(&file).await.unwrap();
+        }
+
+        let app = test::init_service(
+            App::new()
+                .app_data(ctx.clone())
+                .configure(crate::routes::services),
+        )
+        .await;
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&feed_link("", PAGES.atom, OWNER))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains("<title>releases&#x2F;app&lt;1&gt;.tar.gz</title>"));
+        assert!(body.contains("<updated>1970-01-01T00:00:02Z</updated>"));
+        assert!(body.find("app&lt;1&gt;").unwrap() < body.find("notes.txt").unwrap());
+        assert!(!body.contains(".env"));
+        assert!(!body.contains(".cache"));
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&feed_link("", PAGES.rss, &format!("{OWNER}/releases")))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains("<title>app&lt;1&gt;.tar.gz</title>"));
+        assert!(body.contains("<pubDate>Thu, 01 Jan 1970 00:00:02 +0000</pubDate>"));
+        assert!(!body.contains("notes.txt"));
+        assert!(!body.contains(".cache"));
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&feed_link("", PAGES.rss, &format!("{OWNER}/missing")))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        ctx.db.delete_dir(OWNER, "").await.unwrap();
+        tokio::fs::remove_dir_all(settings.files.get_path(OWNER, ""))
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,340 @@
+/*
+ * Copyright (C) 2022  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! File browser for the signed-in user's tree
+use std::collections::HashMap;
+use std::time::UNIX_EPOCH;
+
+use actix_identity::Identity;
+use actix_web::http::StatusCode;
+use actix_web::{web, Error, HttpResponse};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::{Deserialize, Serialize};
+use sqlx::types::time::OffsetDateTime;
+use tokio::fs;
+use url::form_urlencoded;
+
+use super::download::download_link;
+use super::feeds::feed_link;
+use super::{context, redirect, render, ROUTES};
+use crate::archive::Format;
+use crate::settings::clean_path;
+use crate::AppCtx;
+use crate::API_V1_ROUTES;
+
+/// characters that must be escaped in a URL path segment
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+pub fn services(cfg: &mut web::ServiceConfig) {
+    cfg.service(browse);
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Browse {
+    #[serde(default)]
+    pub path: String,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+struct Entry {
+    name: String,
+    /// path relative to user's tree
+    path: String,
+    link: String,
+    is_dir: bool,
+    size: String,
+    modified: String,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+struct Crumb {
+    name: String,
+    link: String,
+}
+
+/// percent-encode every segment of `path`
+pub fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(|s| utf8_percent_encode(s, SEGMENT).to_string())
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+/// public URL of file `path` in `owner`'s tree, under URL path `prefix`
+pub fn file_url(prefix: &str, owner: &str, path: &str) -> String {
+    format!("{prefix}/{}/{}", encode_path(owner), encode_path(path))
+}
+
+pub fn human_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", size, UNITS[unit])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+pub fn format_time(unix: i64) -> String {
+    OffsetDateTime::from_unix_timestamp(unix).format("%Y-%m-%d %H:%M")
+}
+
+fn browse_link(prefix: &str, path: &str) -> String {
+    let path: String = form_urlencoded::byte_serialize(path.as_bytes()).collect();
+    format!("{prefix}{}?path={}", ROUTES.files, path)
+}
+
+#[actix_web_codegen_const_routes::get(path = "ROUTES.files")]
+async fn browse(
+    id: Identity,
+    ctx: AppCtx,
+    query: web::Query<Browse>,
+) -> Result<HttpResponse, Error> {
+    let username = match id.identity() {
+        Some(identity) => ctx.session_user(&identity).await,
+        None => None,
+    };
+    let username = match username {
+        Some(username) => username,
+        None => return Ok(redirect(&ctx.settings.server.url_path(ROUTES.login))),
+    };
+    let prefix = ctx.settings.server.prefix();
+
+    let path = clean_path(&query.path);
+    let dir = ctx.settings.files.get_path(&username, &path);
+    if path.is_empty() && !dir.exists() {
+        fs::create_dir_all(&dir).await?;
+    }
+    if !dir.is_dir() {
+        return Ok(HttpResponse::NotFound().body("dir not found".to_string()));
+    }
+
+    let mut entries = Vec::new();
+    let mut read_dir = fs::read_dir(&dir).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let metadata = entry.metadata().await?;
+        let entry_path = if path.is_empty() {
+            name.clone()
+        } else {
+            format!("{path}/{name}")
+        };
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+        let link = if metadata.is_dir() {
+            browse_link(prefix, &entry_path)
+        } else {
+            file_url(prefix, &username, &entry_path)
+        };
+        entries.push(Entry {
+            name,
+            link,
+            path: entry_path,
+            is_dir: metadata.is_dir(),
+            size: human_size(metadata.len()),
+            modified: format_time(modified),
+        });
+    }
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+    let mut breadcrumbs = vec![Crumb {
+        name: username.clone(),
+        link: browse_link(prefix, ""),
+    }];
+    let mut crumb_path = String::new();
+    for name in path.split('/').filter(|s| !s.is_empty()) {
+        if !crumb_path.is_empty() {
+            crumb_path.push('/');
+        }
+        crumb_path.push_str(name);
+        breadcrumbs.push(Crumb {
+            name: name.into(),
+            link: browse_link(prefix, &crumb_path),
+        });
+    }
+
+    let mut context = context(&ctx.settings);
+    context.insert("username", &username);
+    context.insert("path", &path);
+    context.insert("entries", &entries);
+    context.insert("breadcrumbs", &breadcrumbs);
+    context.insert("api", &API_V1_ROUTES.files);
+    let feed_path = if path.is_empty() {
+        username.clone()
+    } else {
+        format!("{username}/{path}")
+    };
+    let mut feeds = HashMap::new();
+    feeds.insert("atom", feed_link(prefix, ROUTES.atom, &feed_path));
+    feeds.insert("rss", feed_link(prefix, ROUTES.rss, &feed_path));
+    context.insert("feeds", &feeds);
+    let mut downloads = HashMap::new();
+    downloads.insert("zip", download_link(prefix, Format::Zip, &feed_path));
+    downloads.insert("tar_gz", download_link(prefix, Format::TarGz, &feed_path));
+    context.insert("downloads", &downloads);
+    Ok(render("files.html", &mut context, StatusCode::OK))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use actix_web::cookie::Cookie;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::{test, App};
+
+    use super::*;
+    use crate::pages::auth::Login;
+    use crate::pages::get_identity_service;
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn utils_work() {
+        assert_eq!(
+            crate::settings::clean_path("/foo/../bar/./baz/"),
+            "foo/bar/baz"
+        );
+        assert_eq!(encode_path("foo bar/baz?"), "foo%20bar/baz%3F");
+        assert_eq!(human_size(100), "100 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+    }
+
+    #[actix_rt::test]
+    async fn web_ui_works() {
+        let settings = Settings::new().unwrap();
+        let creds = settings.files.creds.first().unwrap().clone();
+        let ctx = AppCtx::new(crate::ctx::Ctx::new(&settings).await);
+        let app = test::init_service(
+            App::new()
+                .wrap(get_identity_service(&settings))
+                .app_data(ctx.clone())
+                .configure(crate::routes::services),
+        )
+        .await;
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get().uri(ROUTES.files).to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        assert_eq!(resp.headers().get(header::LOCATION).unwrap(), ROUTES.login);
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get().uri(ROUTES.login).to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let mut payload = Login {
+            username: creds.username.clone(),
+            password: "wrongpassword".into(),
+        };
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .set_form(&payload)
+                .uri(ROUTES.login)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        payload.password = creds.password.clone();
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .set_form(&payload)
+                .uri(ROUTES.login)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        let cookie: Cookie = resp
+            .response()
+            .cookies()
+            .find(|c| c.name() == "dumbserve-auth")
+            .unwrap()
+            .into_owned();
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .cookie(cookie.clone())
+                .uri(ROUTES.files)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // session cookie authenticates API requests
+        const TEST_DIR_NAME: &str = "test-web_ui_works";
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .cookie(cookie.clone())
+                .set_json(&crate::api::v1::files::Dir {
+                    path: TEST_DIR_NAME.into(),
+                })
+                .uri(API_V1_ROUTES.files.mkdir)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let test_dir = settings.files.get_path(&creds.username, TEST_DIR_NAME);
+        assert!(test_dir.is_dir());
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .cookie(cookie.clone())
+                .uri(&browse_link("", TEST_DIR_NAME))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        tokio::fs::remove_dir_all(test_dir).await.unwrap();
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .cookie(cookie)
+                .uri(ROUTES.logout)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        assert_eq!(resp.headers().get(header::LOCATION).unwrap(), ROUTES.login);
+    }
+}
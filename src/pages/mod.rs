@@ -0,0 +1,171 @@
+/*
+ * Copyright (C) 2022  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Web interface
+use actix_identity::{CookieIdentityPolicy, IdentityService};
+use actix_web::cookie::SameSite;
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, HttpResponse};
+use lazy_static::lazy_static;
+use tera::{Context, Tera};
+
+use crate::settings::Settings;
+
+pub mod auth;
+pub mod download;
+pub mod feeds;
+pub mod files;
+
+pub const ROUTES: routes::Routes = routes::Routes::new();
+
+lazy_static! {
+    pub static ref TEMPLATES: Tera = {
+        let mut tera = Tera::default();
+        tera.add_raw_templates(vec![
+            ("base.html", include_str!("../../templates/base.html")),
+            ("login.html", include_str!("../../templates/login.html")),
+            ("files.html", include_str!("../../templates/files.html")),
+            ("atom.xml", include_str!("../../templates/atom.xml")),
+            ("rss.xml", include_str!("../../templates/rss.xml")),
+            ("listing.html", include_str!("../../templates/listing.html")),
+            (
+                "markdown.html",
+                include_str!("../../templates/markdown.html"),
+            ),
+        ])
+        .unwrap();
+        tera
+    };
+}
+
+pub mod routes {
+    use serde::Serialize;
+
+    #[derive(Debug, Eq, PartialEq, Serialize)]
+    pub struct Routes {
+        pub login: &'static str,
+        pub logout: &'static str,
+        pub files: &'static str,
+        pub atom: &'static str,
+        pub rss: &'static str,
+        pub download: &'static str,
+    }
+
+    impl Routes {
+        pub const fn new() -> Self {
+            Self {
+                login: "/web/login",
+                logout: "/web/logout",
+                files: "/web/files",
+                atom: "/web/feeds/atom/{path:.*}",
+                rss: "/web/feeds/rss/{path:.*}",
+                download: "/web/download/{format}/{path:.*}",
+            }
+        }
+    }
+}
+
+pub fn services(cfg: &mut web::ServiceConfig) {
+    auth::services(cfg);
+    download::services(cfg);
+    feeds::services(cfg);
+    files::services(cfg);
+}
+
+/// session cookie issued by the login page, signed with `server.cookie_secret`
+pub fn get_identity_service(settings: &Settings) -> IdentityService<CookieIdentityPolicy> {
+    let cookie_secret = &settings.server.cookie_secret;
+    IdentityService::new(
+        CookieIdentityPolicy::new(cookie_secret.as_bytes())
+            .path(settings.server.url_path("/"))
+            .name("dumbserve-auth")
+            .max_age_secs(60 * 60 * 24 * 7)
+            .same_site(SameSite::Strict)
+            .secure(settings.server.has_tls()),
+    )
+}
+
+/// template context with `prefix` of links to routes, see [crate::settings::Server::prefix]
+pub fn context(settings: &Settings) -> Context {
+    let mut ctx = Context::new();
+    ctx.insert("prefix", settings.server.prefix());
+    ctx
+}
+
+/// render template with routes available to it
+pub fn render(template: &str, ctx: &mut Context, status: StatusCode) -> HttpResponse {
+    render_as(template, ctx, status, "text/html; charset=utf-8")
+}
+
+/// built-in templates with overrides from `settings.templates`. Overrides replace built-in
+/// templates with the same file name.
+pub fn load_templates(settings: &Settings) -> Result<Tera, String> {
+    let mut tera = TEMPLATES.clone();
+    let dir = match &settings.templates {
+        Some(dir) => dir,
+        None => return Ok(tera),
+    };
+    let read_dir = std::fs::read_dir(dir).map_err(|e| format!("Unable to read {dir}: {e}"))?;
+    let mut templates = Vec::new();
+    for entry in read_dir.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !entry.path().is_file() || name.starts_with('.') {
+            continue;
+        }
+        let template = std::fs::read_to_string(entry.path())
+            .map_err(|e| format!("Unable to read template {name}: {e}"))?;
+        templates.push((name, template));
+    }
+    tera.add_raw_templates(templates)
+        .map_err(|e| format!("Invalid template in {dir}: {:?}", e))?;
+    Ok(tera)
+}
+
+/// render template with routes available to it and serve it as `content_type`
+pub fn render_as(
+    template: &str,
+    ctx: &mut Context,
+    status: StatusCode,
+    content_type: &str,
+) -> HttpResponse {
+    render_with(&TEMPLATES, template, ctx, status, content_type)
+}
+
+/// [render_as] with `templates` instead of the built-in templates
+pub fn render_with(
+    templates: &Tera,
+    template: &str,
+    ctx: &mut Context,
+    status: StatusCode,
+    content_type: &str,
+) -> HttpResponse {
+    ctx.insert("routes", &ROUTES);
+    match templates.render(template, ctx) {
+        Ok(body) => HttpResponse::build(status)
+            .content_type(content_type)
+            .body(body),
+        Err(e) => {
+            log::error!("Unable to render {template}: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub fn redirect(location: &str) -> HttpResponse {
+    HttpResponse::Found()
+        .insert_header((header::LOCATION, location))
+        .finish()
+}
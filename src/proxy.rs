@@ -0,0 +1,305 @@
+/*
+ * Copyright (C) 2022  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Deployment behind reverse proxies: `X-Forwarded-*` headers are only honored in requests from
+//! `server.trusted_proxies`, and with `server.proxy_has_tls`, plain HTTP requests are
+//! redirected to HTTPS and responses get HSTS headers.
+//!
+//! Everything reading the client address, scheme or host through
+//! [actix_web::dev::ConnectionInfo](logging, virtual hosts, link building) gets values the
+//! proxies vouch for.
+use std::future::{ready, Ready};
+use std::net::IpAddr;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::http::Method;
+use actix_web::{Error, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+
+use crate::AppCtx;
+
+/// `Strict-Transport-Security` of responses when `server.proxy_has_tls` is set
+pub const HSTS: &str = "max-age=31536000";
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// network of a trusted proxy, `10.0.0.0/8` or a single address
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Network {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Network {
+    fn parse(network: &str) -> Option<Self> {
+        let (addr, prefix_len) = match network.split_once('/') {
+            Some((addr, len)) => (addr.parse().ok()?, Some(len.parse().ok()?)),
+            None => (network.parse().ok()?, None),
+        };
+        let max_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix_len = prefix_len.unwrap_or(max_len);
+        if prefix_len > max_len {
+            return None;
+        }
+        Some(Self { addr, prefix_len })
+    }
+
+    fn contains(&self, addr: IpAddr) -> bool {
+        let (net, addr, bits) = match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                (u32::from(net) as u128, u32::from(addr) as u128, 32)
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => (u128::from(net), u128::from(addr), 128),
+            (IpAddr::V4(net), IpAddr::V6(addr)) => match addr.to_ipv4_mapped() {
+                Some(addr) => (u32::from(net) as u128, u32::from(addr) as u128, 32),
+                None => return false,
+            },
+            (IpAddr::V6(_), IpAddr::V4(_)) => return false,
+        };
+        let host_bits = bits - self.prefix_len as u32;
+        host_bits >= bits || (net >> host_bits) == (addr >> host_bits)
+    }
+}
+
+/// compiled `server.trusted_proxies`
+pub struct TrustedProxies {
+    networks: Vec<Network>,
+    /// trust connections over Unix domain sockets
+    unix: bool,
+}
+
+impl TrustedProxies {
+    pub fn new(proxies: &[String]) -> Result<Self, String> {
+        let networks = proxies
+            .iter()
+            .filter(|p| *p != "unix")
+            .map(|p| Network::parse(p).ok_or_else(|| format!("Invalid trusted proxy {p}")))
+            .collect::<Result<_, String>>()?;
+        let unix = proxies.iter().any(|p| p == "unix");
+        Ok(Self { networks, unix })
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        self.networks.iter().any(|n| n.contains(addr))
+    }
+
+    /// drop forwarding headers of requests from `peer`, `None` for Unix domain sockets, unless
+    /// it's a trusted proxy. Of the
+    /// addresses in `X-Forwarded-For`, only the last one not belonging to a trusted proxy is
+    /// kept: clients can put anything in front of it.
+    pub fn sanitize(&self, peer: Option<IpAddr>, headers: &mut HeaderMap) {
+        // only the `X-Forwarded-*` family is supported
+        headers.remove(header::FORWARDED);
+        let trusted = match peer {
+            Some(peer) => self.contains(peer),
+            None => self.unix,
+        };
+        if !trusted {
+            headers.remove(X_FORWARDED_FOR);
+            headers.remove(X_FORWARDED_PROTO);
+            headers.remove(X_FORWARDED_HOST);
+            return;
+        }
+
+        let forwarded_for: Vec<String> = headers
+            .get_all(X_FORWARDED_FOR)
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|v| v.trim().to_owned())
+            .collect();
+        let client = forwarded_for
+            .iter()
+            .rev()
+            .find(|addr| !matches!(addr.parse(), Ok(addr) if self.contains(addr)))
+            .or_else(|| forwarded_for.first())
+            .and_then(|addr| HeaderValue::try_from(addr.as_str()).ok());
+        headers.remove(X_FORWARDED_FOR);
+        if let Some(client) = client {
+            headers.insert(X_FORWARDED_FOR, client);
+        }
+    }
+}
+
+/// middleware applying `server.trusted_proxies` and `server.proxy_has_tls`. Must wrap all
+/// other middleware, their view of the connection is cached in the request.
+pub struct Proxy;
+
+impl<S, B> Transform<S, ServiceRequest> for Proxy
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = ProxyMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ProxyMiddleware { service }))
+    }
+}
+
+pub struct ProxyMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for ProxyMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let ctx = match req.app_data::<AppCtx>() {
+            Some(ctx) => ctx.clone(),
+            None => {
+                let fut = self.service.call(req);
+                return Box::pin(async move { Ok(fut.await?.map_into_left_body()) });
+            }
+        };
+        let peer = req.peer_addr().map(|addr| addr.ip());
+        ctx.trusted_proxies.sanitize(peer, req.headers_mut());
+
+        if !ctx.settings.server.proxy_has_tls {
+            let fut = self.service.call(req);
+            return Box::pin(async move { Ok(fut.await?.map_into_left_body()) });
+        }
+
+        // without a scheme from a trusted proxy, requests can't be told apart
+        if req.headers().contains_key(X_FORWARDED_PROTO) && req.connection_info().scheme() == "http"
+        {
+            let location = format!(
+                "https://{}{}",
+                req.connection_info().host(),
+                req.uri()
+                    .path_and_query()
+                    .map(|p| p.as_str())
+                    .unwrap_or("/")
+            );
+            let mut resp = if matches!(*req.method(), Method::GET | Method::HEAD) {
+                HttpResponse::MovedPermanently()
+            } else {
+                HttpResponse::PermanentRedirect()
+            };
+            let resp = resp.insert_header((header::LOCATION, location)).finish();
+            return Box::pin(async move { Ok(req.into_response(resp).map_into_right_body()) });
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            res.headers_mut().insert(
+                header::STRICT_TRANSPORT_SECURITY,
+                HeaderValue::from_static(HSTS),
+            );
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App, HttpRequest};
+
+    use super::*;
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn proxy_works() {
+        let proxies = TrustedProxies::new(&["10.0.0.0/8".into(), "::1".into()]).unwrap();
+        assert!(proxies.contains("10.1.2.3".parse().unwrap()));
+        assert!(proxies.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(proxies.contains("::1".parse().unwrap()));
+        assert!(!proxies.contains("192.168.1.1".parse().unwrap()));
+        assert!(!proxies.contains("::2".parse().unwrap()));
+        assert!(TrustedProxies::new(&["10.0.0.0/33".into()]).is_err());
+        assert!(TrustedProxies::new(&["proxy".into()]).is_err());
+        let mut headers = HeaderMap::new();
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static("https"));
+        proxies.sanitize(None, &mut headers);
+        assert!(headers.is_empty());
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static("https"));
+        let proxies = TrustedProxies::new(&["unix".into()]).unwrap();
+        proxies.sanitize(None, &mut headers);
+        assert!(headers.contains_key(X_FORWARDED_PROTO));
+
+        let mut settings = Settings::new().unwrap();
+        settings.server.proxy_has_tls = true;
+        settings.server.trusted_proxies = vec!["10.0.0.0/8".into()];
+        let ctx = AppCtx::new(crate::ctx::Ctx::new(&settings).await);
+        let app = test::init_service(App::new().app_data(ctx.clone()).wrap(Proxy).route(
+            "/",
+            web::get().to(|req: HttpRequest| async move {
+                let info = req.connection_info();
+                format!(
+                    "{} {} {}",
+                    info.realip_remote_addr().unwrap_or_default(),
+                    info.scheme(),
+                    info.host()
+                )
+            }),
+        ))
+        .await;
+        let req = |peer: &str, proto: &str| {
+            test::TestRequest::get()
+                .uri("/")
+                .peer_addr(format!("{peer}:1234").parse().unwrap())
+                .insert_header((header::HOST, "internal"))
+                .insert_header((X_FORWARDED_FOR, "6.6.6.6, 1.2.3.4, 10.0.0.2"))
+                .insert_header((X_FORWARDED_PROTO, proto))
+                .insert_header((X_FORWARDED_HOST, "example.org"))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, req("10.0.0.1", "https")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers()
+                .get(header::STRICT_TRANSPORT_SECURITY)
+                .unwrap(),
+            HSTS
+        );
+        assert_eq!(test::read_body(resp).await, "1.2.3.4 https example.org");
+
+        let resp = test::call_service(&app, req("10.0.0.1", "http")).await;
+        assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            resp.headers().get(header::LOCATION).unwrap(),
+            "https://example.org/"
+        );
+
+        // forwarding headers of clients are ignored
+        let resp = test::call_service(&app, req("192.168.1.1", "http")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(test::read_body(resp).await, "192.168.1.1 http internal");
+    }
+}
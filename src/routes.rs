@@ -14,8 +14,165 @@
  * You should have received a copy of the GNU Affero General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
-use actix_web::web;
+use std::future::{ready, Ready};
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::uri::{PathAndQuery, Uri};
+use actix_web::{web, Error, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+
+use crate::AppCtx;
 
 pub fn services(cfg: &mut web::ServiceConfig) {
     crate::api::v1::services(cfg);
+    crate::pages::services(cfg);
+    crate::serve::services(cfg);
+}
+
+/// path of request to `path` relative to `prefix`, `None` when it's outside of `prefix`
+fn strip_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    match path.strip_prefix(prefix)? {
+        "" => Some("/"),
+        rest if rest.starts_with('/') => Some(rest),
+        _ => None,
+    }
+}
+
+/// middleware routing requests under `server.url_prefix` as if they were made to `/`.
+/// Requests outside of it get 404.
+pub struct UrlPrefix;
+
+impl<S, B> Transform<S, ServiceRequest> for UrlPrefix
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = UrlPrefixMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(UrlPrefixMiddleware { service }))
+    }
+}
+
+pub struct UrlPrefixMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for UrlPrefixMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let prefix = match req.app_data::<AppCtx>() {
+            Some(ctx) if !ctx.settings.server.prefix().is_empty() => {
+                ctx.settings.server.prefix().to_owned()
+            }
+            _ => {
+                let fut = self.service.call(req);
+                return Box::pin(async move { Ok(fut.await?.map_into_left_body()) });
+            }
+        };
+
+        let uri = strip_prefix(req.path(), &prefix).and_then(|path| {
+            let path_and_query = match req.query_string() {
+                "" => path.to_owned(),
+                query => format!("{path}?{query}"),
+            };
+            let mut parts = req.head().uri.clone().into_parts();
+            parts.path_and_query = Some(PathAndQuery::try_from(path_and_query).ok()?);
+            Uri::from_parts(parts).ok()
+        });
+        match uri {
+            Some(uri) => {
+                req.match_info_mut().get_mut().update(&uri);
+                req.head_mut().uri = uri;
+                let fut = self.service.call(req);
+                Box::pin(async move { Ok(fut.await?.map_into_left_body()) })
+            }
+            None => {
+                let resp = HttpResponse::NotFound().finish();
+                Box::pin(async move { Ok(req.into_response(resp).map_into_right_body()) })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use actix_web::http::{header, StatusCode};
+    use actix_web::{test, App};
+
+    use super::*;
+    use crate::pages::get_identity_service;
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn url_prefix_works() {
+        const OWNER: &str = "url_prefix_works";
+        assert_eq!(strip_prefix("/downloads", "/downloads"), Some("/"));
+        assert_eq!(strip_prefix("/downloads/a", "/downloads"), Some("/a"));
+        assert_eq!(strip_prefix("/downloadsa", "/downloads"), None);
+
+        let mut settings = Settings::new().unwrap();
+        settings.server.url_prefix = Some("/downloads".into());
+        let ctx = AppCtx::new(crate::ctx::Ctx::new(&settings).await);
+        let app = test::init_service(
+            App::new()
+                .wrap(get_identity_service(&settings))
+                .wrap(UrlPrefix)
+                .app_data(ctx.clone())
+                .configure(services),
+        )
+        .await;
+        let get = |uri: &str| test::TestRequest::get().uri(uri).to_request();
+
+        let dir = settings.files.get_path(OWNER, "");
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        tokio::fs::write(dir.join("hello.txt"), "hello")
+            .await
+            .unwrap();
+
+        let resp = test::call_service(&app, get("/downloads/api/v1/meta/build")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&app, get(API_V1_ROUTES.meta.build_details)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = test::call_service(&app, get(&format!("/{OWNER}/hello.txt"))).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let resp = test::call_service(&app, get("/downloads/web/files")).await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        assert_eq!(
+            resp.headers().get(header::LOCATION).unwrap(),
+            "/downloads/web/login"
+        );
+        let resp = test::call_service(&app, get("/downloads/web/login")).await;
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains("action=\"&#x2F;downloads&#x2F;web&#x2F;login\""));
+
+        let resp = test::call_service(&app, get(&format!("/downloads/{OWNER}"))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains(&format!(
+            "href=\"&#x2F;downloads&#x2F;{OWNER}&#x2F;hello.txt\""
+        )));
+        let resp = test::call_service(&app, get(&format!("/downloads/{OWNER}/hello.txt"))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(test::read_body(resp).await, "hello");
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
 }
@@ -0,0 +1,597 @@
+/*
+ * Copyright (C) 2022  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Public file service. Directories can opt into static website hosting with a
+//! `.dumbserve.toml` file, which applies to the directory and everything below it:
+//!
+//! ```toml
+//! # serve index.html for directories, 404.html for missing files and page.html at /page
+//! site = true
+//! # serve the site's index.html for missing files, for single page applications
+//! spa = false
+//! # list directories that don't have an index.html
+//! listing = true
+//! ```
+//!
+//! Hidden files, including these configuration files, are never served. Virtual hosts
+//! configured in `server.vhosts` serve a directory at `/` instead of all trees.
+use std::ffi::OsString;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+
+use actix_files::NamedFile;
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::{self, ContentEncoding};
+use actix_web::http::StatusCode;
+use actix_web::{guard, web, Error, HttpMessage, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use tokio::fs;
+
+use crate::caching;
+use crate::compression::{find_sidecar, is_compressible};
+use crate::index::modified;
+use crate::listing;
+use crate::markdown;
+use crate::settings::clean_path;
+use crate::AppCtx;
+
+/// per-directory configuration file
+pub const DIR_CONFIG: &str = ".dumbserve.toml";
+
+pub fn services(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/{path:.*}").route(
+            web::route()
+                .guard(guard::Any(guard::Get()).or(guard::Head()))
+                .to(serve),
+        ),
+    );
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+pub struct DirConfig {
+    #[serde(default)]
+    pub site: bool,
+    #[serde(default)]
+    pub spa: bool,
+    #[serde(default = "default_listing")]
+    pub listing: bool,
+}
+
+fn default_listing() -> bool {
+    true
+}
+
+impl Default for DirConfig {
+    fn default() -> Self {
+        Self {
+            site: false,
+            spa: false,
+            listing: default_listing(),
+        }
+    }
+}
+
+/// configuration of `dir` from the closest [DIR_CONFIG] in `dir` or its parents up to `root`.
+/// Returns the directory the configuration was found in, the site root.
+pub async fn dir_config(root: &Path, dir: &Path) -> (PathBuf, DirConfig) {
+    let mut current = Some(dir);
+    while let Some(dir) = current.filter(|dir| dir.starts_with(root)) {
+        if let Ok(config) = fs::read_to_string(dir.join(DIR_CONFIG)).await {
+            let config = toml::from_str(&config).unwrap_or_else(|e| {
+                log::warn!("Invalid configuration in {:?}: {e}", dir);
+                DirConfig::default()
+            });
+            return (dir.to_path_buf(), config);
+        }
+        current = dir.parent();
+    }
+    (root.to_path_buf(), DirConfig::default())
+}
+
+/// request path had a trailing slash before `NormalizePath` trimmed it
+pub struct TrailingSlash;
+
+/// record trailing slashes for [serve], run before `NormalizePath`. Relative links in
+/// `index.html` only work when directories are requested with a trailing slash.
+pub fn mark_trailing_slash(req: &ServiceRequest) {
+    if req.path().len() > 1 && req.path().ends_with('/') {
+        req.extensions_mut().insert(TrailingSlash);
+    }
+}
+
+fn has_trailing_slash(req: &HttpRequest) -> bool {
+    req.path().ends_with('/') || req.extensions().get::<TrailingSlash>().is_some()
+}
+
+/// paths with dot-prefixed components aren't served
+pub fn is_hidden(path: &str) -> bool {
+    path.split('/').any(|c| c.starts_with('.'))
+}
+
+fn not_found() -> HttpResponse {
+    HttpResponse::NotFound().body("file not found".to_string())
+}
+
+/// digest of file `path` from the index, if the index is up to date
+async fn indexed_digest(ctx: &AppCtx, path: &str, metadata: &Metadata) -> Option<String> {
+    let (owner, path) = path.split_once('/')?;
+    match ctx.db.get_file(owner, path).await {
+        Ok(Some(f)) if f.size as u64 == metadata.len() && f.modified == modified(metadata) => {
+            Some(f.digest)
+        }
+        _ => None,
+    }
+}
+
+/// serve file `path`, or its best precompressed sidecar. Already compressed content is
+/// exempt from dynamic compression. Successful responses get caching headers.
+async fn serve_file(
+    req: &HttpRequest,
+    ctx: &AppCtx,
+    path: &Path,
+    status: StatusCode,
+) -> Result<HttpResponse, Error> {
+    let files = &ctx.settings.files;
+    let file = NamedFile::open_async(path).await?;
+    let content_type = file.content_type().to_string();
+    let rel = path
+        .strip_prefix(&files.path)
+        .ok()
+        .and_then(|rel| rel.to_str())
+        .unwrap_or_default();
+    let (etag, cache_control) = if status == StatusCode::OK {
+        (
+            indexed_digest(ctx, rel, file.metadata()).await,
+            ctx.cache_policy.cache_control(rel, &content_type),
+        )
+    } else {
+        (None, None)
+    };
+    let compressible = is_compressible(file.content_type().essence_str());
+    let accept_encoding = req
+        .headers()
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    // ranges of encoded content confuse clients
+    let sidecar = if compressible && !req.headers().contains_key(header::RANGE) {
+        find_sidecar(files, path, accept_encoding)
+    } else {
+        None
+    };
+
+    let encoding = sidecar.as_ref().map(|(_, encoding)| *encoding);
+    let etag = etag.map(|digest| caching::etag(&digest, encoding));
+    let file = match sidecar {
+        Some((sidecar, encoding)) => NamedFile::open_async(sidecar)
+            .await?
+            .set_content_type(file.content_type().clone())
+            .set_content_disposition(file.content_disposition().clone())
+            .set_content_encoding(encoding),
+        None if !compressible => file.set_content_encoding(ContentEncoding::Identity),
+        None => file,
+    };
+
+    let mut resp = match &etag {
+        Some(etag) if caching::is_fresh(req, etag) => HttpResponse::NotModified().finish(),
+        Some(_) => file.use_etag(false).into_response(req),
+        None => file.into_response(req),
+    };
+    let headers = resp.headers_mut();
+    if let Some(etag) = etag {
+        headers.insert(header::ETAG, etag.to_string().parse().unwrap());
+    }
+    if let Some(cache_control) = cache_control {
+        headers.insert(header::CACHE_CONTROL, cache_control.parse().unwrap());
+    }
+    if compressible {
+        headers.append(
+            header::VARY,
+            header::HeaderValue::from_static("accept-encoding"),
+        );
+    }
+    if status != StatusCode::OK {
+        *resp.status_mut() = status;
+    }
+    Ok(resp)
+}
+
+async fn serve(
+    req: HttpRequest,
+    ctx: AppCtx,
+    path: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let path = clean_path(&path);
+    let tree = ctx
+        .settings
+        .server
+        .vhost_path(req.connection_info().host())
+        .unwrap_or_default();
+    if is_hidden(&tree) || is_hidden(&path) {
+        return Ok(not_found());
+    }
+    let root = Path::new(&ctx.settings.files.path);
+    let target = root.join(&tree).join(&path);
+    let is_dir = target.is_dir();
+    let dir = if is_dir {
+        target.as_path()
+    } else {
+        target.parent().unwrap_or(root)
+    };
+    let (site_root, config) = dir_config(root, dir).await;
+
+    if is_dir {
+        let index = target.join("index.html");
+        if config.site && index.is_file() {
+            if !has_trailing_slash(&req) {
+                let mut location = ctx
+                    .settings
+                    .server
+                    .url_path(&format!("{}/", req.path().trim_end_matches('/')));
+                if !req.query_string().is_empty() {
+                    location = format!("{location}?{}", req.query_string());
+                }
+                return Ok(HttpResponse::MovedPermanently()
+                    .insert_header((header::LOCATION, location))
+                    .finish());
+            }
+            return serve_file(&req, &ctx, &index, StatusCode::OK).await;
+        }
+        if config.listing {
+            let tree_path = clean_path(&format!("{tree}/{path}"));
+            return listing::render(&req, &ctx, &target, &path, &tree_path).await;
+        }
+    } else if target.is_file() {
+        if !markdown::is_markdown(&path) {
+            return serve_file(&req, &ctx, &target, StatusCode::OK).await;
+        }
+        let renderable = matches!(
+            fs::metadata(&target).await,
+            Ok(m) if m.len() <= markdown::MAX_RENDER_SIZE
+        );
+        if renderable && markdown::wants_html(&req) {
+            return markdown::render_page(&ctx, &target, &path).await;
+        }
+        let mut resp = serve_file(&req, &ctx, &target, StatusCode::OK).await?;
+        resp.headers_mut()
+            .append(header::VARY, header::HeaderValue::from_static("accept"));
+        return Ok(resp);
+    } else if config.site {
+        // clean URLs: `page.html` at `page`
+        let mut page = OsString::from(target.as_os_str());
+        page.push(".html");
+        let page = PathBuf::from(page);
+        if page.is_file() {
+            return serve_file(&req, &ctx, &page, StatusCode::OK).await;
+        }
+    }
+
+    if config.spa {
+        let index = site_root.join("index.html");
+        if index.is_file() {
+            return serve_file(&req, &ctx, &index, StatusCode::OK).await;
+        }
+    }
+    if config.site {
+        let page = site_root.join("404.html");
+        if page.is_file() {
+            return serve_file(&req, &ctx, &page, StatusCode::NOT_FOUND).await;
+        }
+    }
+    Ok(not_found())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use actix_web::{test, App};
+
+    use super::*;
+    use crate::settings::VirtualHost;
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn site_mode_works() {
+        const OWNER: &str = "test-site_mode_works";
+
+        let settings = Settings::new().unwrap();
+        let files = [
+            ("docs/.dumbserve.toml", "site = true\nlisting = false"),
+            ("docs/index.html", "index"),
+            ("docs/about.html", "about"),
+            ("docs/404.html", "not found"),
+            ("docs/assets/.secret", "secret"),
+            ("app/.dumbserve.toml", "site = true\nspa = true"),
+            ("app/index.html", "app"),
+            ("releases/index.html", "release notes"),
+            ("releases/app.tar.gz", "app"),
+        ];
+        for (path, contents) in files {
+            let path = settings.files.get_path(OWNER, path);
+            fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+            fs::write(path, contents).await.unwrap();
+        }
+
+        let ctx = AppCtx::new(crate::ctx::Ctx::new(&settings).await);
+        let app = test::init_service(
+            App::new()
+                .app_data(ctx.clone())
+                .configure(crate::routes::services),
+        )
+        .await;
+
+        let cases = [
+            ("docs", StatusCode::MOVED_PERMANENTLY, ""),
+            ("docs/", StatusCode::OK, "index"),
+            ("docs/about", StatusCode::OK, "about"),
+            ("docs/about.html", StatusCode::OK, "about"),
+            ("docs/missing", StatusCode::NOT_FOUND, "not found"),
+            ("docs/assets/", StatusCode::NOT_FOUND, "not found"),
+            (
+                "docs/.dumbserve.toml",
+                StatusCode::NOT_FOUND,
+                "file not found",
+            ),
+            (
+                "docs/assets/.secret",
+                StatusCode::NOT_FOUND,
+                "file not found",
+            ),
+            ("app/some/route", StatusCode::OK, "app"),
+            ("releases/app.tar.gz", StatusCode::OK, "app"),
+        ];
+        for (path, status, body) in cases {
+            let resp = test::call_service(
+                &app,
+                test::TestRequest::get()
+                    .uri(&format!("/{OWNER}/{path}"))
+                    .to_request(),
+            )
+            .await;
+            assert_eq!(resp.status(), status, "{path}");
+            if status == StatusCode::MOVED_PERMANENTLY {
+                assert_eq!(
+                    resp.headers().get(header::LOCATION).unwrap(),
+                    &format!("/{OWNER}/{path}/")
+                );
+                continue;
+            }
+            let resp_body = test::read_body(resp).await;
+            assert_eq!(resp_body, body.as_bytes(), "{path}");
+        }
+
+        // directories without site mode are listed, index.html is just a file
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&format!("/{OWNER}/releases/"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains(&format!(
+            "href=\"&#x2F;{OWNER}&#x2F;releases&#x2F;app.tar.gz\""
+        )));
+
+        let resp =
+            test::call_service(&app, test::TestRequest::get().uri("/.staging").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        fs::remove_dir_all(settings.files.get_path(OWNER, ""))
+            .await
+            .unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn vhosts_work() {
+        const OWNER: &str = "test-vhosts_work";
+
+        let mut settings = Settings::new().unwrap();
+        settings.server.vhosts = vec![
+            VirtualHost {
+                host: "docs.example.org".into(),
+                path: format!("{OWNER}/docs"),
+            },
+            VirtualHost {
+                host: "*.example.org".into(),
+                path: "*/site".into(),
+            },
+        ];
+
+        for (path, contents) in [("docs/guide.txt", "guide"), ("site/index.txt", "site")] {
+            let path = settings.files.get_path(OWNER, path);
+            fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+            fs::write(path, contents).await.unwrap();
+        }
+
+        let ctx = AppCtx::new(crate::ctx::Ctx::new(&settings).await);
+        let app = test::init_service(
+            App::new()
+                .app_data(ctx.clone())
+                .configure(crate::routes::services),
+        )
+        .await;
+
+        let cases = [
+            ("docs.example.org", "/guide.txt", StatusCode::OK, "guide"),
+            (
+                &format!("{OWNER}.example.org"),
+                "/index.txt",
+                StatusCode::OK,
+                "site",
+            ),
+            (
+                "localhost",
+                &format!("/{OWNER}/docs/guide.txt"),
+                StatusCode::OK,
+                "guide",
+            ),
+            (
+                "docs.example.org",
+                "/../site/index.txt",
+                StatusCode::NOT_FOUND,
+                "file not found",
+            ),
+        ];
+        for (host, uri, status, body) in cases {
+            let resp = test::call_service(
+                &app,
+                test::TestRequest::get()
+                    .insert_header((header::HOST, host))
+                    .uri(uri)
+                    .to_request(),
+            )
+            .await;
+            assert_eq!(resp.status(), status, "{host}{uri}");
+            assert_eq!(test::read_body(resp).await, body.as_bytes());
+        }
+
+        fs::remove_dir_all(settings.files.get_path(OWNER, ""))
+            .await
+            .unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn precompressed_works() {
+        const OWNER: &str = "test-precompressed_works";
+
+        let settings = Settings::new().unwrap();
+        let files = [
+            ("app.js", "app".repeat(1000)),
+            ("app.js.gz", "gzipped".into()),
+            ("app.tar.gz", "app".into()),
+        ];
+        for (path, contents) in files {
+            let path = settings.files.get_path(OWNER, path);
+            fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+            fs::write(path, contents).await.unwrap();
+        }
+
+        let ctx = AppCtx::new(crate::ctx::Ctx::new(&settings).await);
+        let app = test::init_service(
+            App::new()
+                .app_data(ctx.clone())
+                .configure(crate::routes::services),
+        )
+        .await;
+        let get = |path: &str, accept_encoding: &str| {
+            test::TestRequest::get()
+                .insert_header((header::ACCEPT_ENCODING, accept_encoding))
+                .uri(&format!("/{OWNER}/{path}"))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, get("app.js", "gzip, br")).await;
+        assert_eq!(
+            resp.headers().get(header::CONTENT_ENCODING).unwrap(),
+            "gzip"
+        );
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/javascript; charset=utf-8"
+        );
+        assert_eq!(resp.headers().get(header::VARY).unwrap(), "accept-encoding");
+        assert_eq!(test::read_body(resp).await, "gzipped".as_bytes());
+
+        let resp = test::call_service(&app, get("app.js", "br")).await;
+        assert!(resp.headers().get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(test::read_body(resp).await, "app".repeat(1000).as_bytes());
+
+        let resp = test::call_service(&app, get("app.tar.gz", "gzip")).await;
+        assert_eq!(
+            resp.headers().get(header::CONTENT_ENCODING).unwrap(),
+            "identity"
+        );
+
+        fs::remove_dir_all(settings.files.get_path(OWNER, ""))
+            .await
+            .unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn etags_work() {
+        const OWNER: &str = "test-etags_work";
+
+        let mut settings = Settings::new().unwrap();
+        settings.files.cache.push(crate::settings::CacheRule {
+            glob: Some(format!("{OWNER}/**")),
+            content_type: None,
+            max_age: Some(60),
+            immutable: true,
+            no_cache: false,
+        });
+        let path = settings.files.get_path(OWNER, "app.tar.gz");
+        fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+        fs::write(&path, "foo").await.unwrap();
+
+        let ctx = AppCtx::new(crate::ctx::Ctx::new(&settings).await);
+        let digest = crate::index::digest(&path).await.unwrap();
+        crate::index::index_file(&ctx, OWNER, "app.tar.gz", OWNER, digest.clone())
+            .await
+            .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(ctx.clone())
+                .configure(crate::routes::services),
+        )
+        .await;
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&format!("/{OWNER}/app.tar.gz"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let etag = resp.headers().get(header::ETAG).unwrap().clone();
+        assert_eq!(etag, format!("\"{digest}\"").as_str());
+        assert_eq!(
+            resp.headers().get(header::CACHE_CONTROL).unwrap(),
+            "public, max-age=60, immutable"
+        );
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .insert_header((header::IF_NONE_MATCH, etag))
+                .uri(&format!("/{OWNER}/app.tar.gz"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+        // modified files don't get stale ETags
+        fs::write(&path, "foobar").await.unwrap();
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&format!("/{OWNER}/app.tar.gz"))
+                .to_request(),
+        )
+        .await;
+        assert_ne!(
+            resp.headers().get(header::ETAG).unwrap(),
+            format!("\"{digest}\"").as_str()
+        );
+
+        ctx.db.delete_dir(OWNER, "").await.unwrap();
+        fs::remove_dir_all(settings.files.get_path(OWNER, ""))
+            .await
+            .unwrap();
+    }
+}
@@ -14,12 +14,14 @@
  * You should have received a copy of the GNU Affero General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::path::{Component, Path};
 use std::{env, path::PathBuf};
 
 use config::{Config, ConfigError, Environment, File};
+use derive_more::Display;
 use log::warn;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use url::Url;
 
 #[derive(Debug, Clone, Deserialize)]
@@ -30,40 +32,227 @@ pub struct Server {
     pub ip: String,
     pub url_prefix: Option<String>,
     pub proxy_has_tls: bool,
+    /// addresses and networks of reverse proxies whose `X-Forwarded-*` headers are honored
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    /// native TLS listener, see [crate::tls]
+    pub tls: Option<Tls>,
+    /// sockets to listen on instead of `ip`, `port` and `tls.port`, see [crate::listeners]
+    #[serde(default)]
+    pub listeners: Vec<Listener>,
+    #[serde(default)]
+    pub vhosts: Vec<VirtualHost>,
+    #[serde(default)]
+    pub headers: Vec<HeaderRule>,
+}
+
+/// response headers of requests under a path prefix, see [crate::headers]
+#[derive(Debug, Clone, Deserialize)]
+pub struct HeaderRule {
+    /// path prefix, matched on `/` boundaries
+    pub prefix: String,
+    /// headers added to responses, replacing existing values
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// origins allowed to make cross-origin requests, `*` for any
+    #[serde(default)]
+    pub cors_origins: Vec<String>,
+    /// methods allowed in cross-origin requests, `GET` and `HEAD` when empty
+    #[serde(default)]
+    pub cors_methods: Vec<String>,
+    /// have browsers download responses instead of displaying them
+    #[serde(default)]
+    pub attachment: bool,
+}
+
+/// socket accepting connections
+#[derive(Debug, Clone, Deserialize)]
+pub struct Listener {
+    /// `ip:port`, `[ipv6]:port`, `unix:<path>` or `systemd:<n>` for the n-th socket passed by
+    /// systemd socket activation
+    pub address: String,
+    /// octal permissions of Unix domain sockets, like `660`
+    pub mode: Option<String>,
+    /// serve HTTPS with the certificate of `server.tls`
+    #[serde(default)]
+    pub tls: bool,
+    #[serde(default)]
+    pub scope: ListenerScope,
+}
+
+/// requests served by a listener
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerScope {
+    /// everything
+    #[default]
+    All,
+    /// public files and other routes that don't need authentication
+    Public,
+    /// API and web interface
+    Admin,
+}
+
+/// HTTPS listener next to the plain HTTP one
+#[derive(Debug, Clone, Deserialize)]
+pub struct Tls {
+    /// port of the TLS listener on `server.ip` when `server.listeners` isn't set
+    pub port: Option<u32>,
+    /// PEM encoded certificate chain, leaf certificate first
+    pub cert: String,
+    /// PEM encoded private key
+    pub key: String,
+    #[serde(default)]
+    pub min_version: TlsVersion,
+    /// PEM encoded certificates of CAs client certificates are verified against. Clients
+    /// without certificates are still accepted.
+    pub client_ca: Option<String>,
+    /// users authenticated by client certificates
+    #[serde(default)]
+    pub clients: Vec<ClientUser>,
+}
+
+/// user authenticated by a verified client certificate with either `subject` or the SHA-256
+/// `fingerprint` of its public key
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientUser {
+    pub username: String,
+    /// RFC 4514 subject, like `CN=builder,O=Example`
+    pub subject: Option<String>,
+    /// hex encoded SHA-256 digest of the certificate's DER encoded SubjectPublicKeyInfo
+    pub fingerprint: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+/// domain serving a directory instead of all trees
+#[derive(Debug, Clone, Deserialize)]
+pub struct VirtualHost {
+    /// domain name, `*.example.org` matches every subdomain of `example.org`
+    pub host: String,
+    /// directory in `files.path`. `*` is replaced with the subdomain matched by a wildcard.
+    pub path: String,
 }
 
 impl Server {
     #[cfg(not(tarpaulin_include))]
     pub fn get_ip(&self) -> String {
-        format!("{}:{}", self.ip, self.port)
-    }
-}
-
-//#[derive(Deserialize, Serialize, Display, PartialEq, Clone, Debug)]
-//#[serde(rename_all = "lowercase")]
-//pub enum DBType {
-//    #[display(fmt = "postgres")]
-//    Postgres,
-//    #[display(fmt = "maria")]
-//    Maria,
-//}
-//
-//impl DBType {
-//    fn from_url(url: &Url) -> Result<Self, ConfigError> {
-//        match url.scheme() {
-//            "mysql" => Ok(Self::Maria),
-//            "postgres" => Ok(Self::Postgres),
-//            _ => Err(ConfigError::Message("Unknown database type".into())),
-//        }
-//    }
-//}
-//
-//#[derive(Debug, Clone, Deserialize)]
-//pub struct Database {
-//    pub url: String,
-//    pub pool: u32,
-//    pub database_type: DBType,
-//}
+        if self.ip.contains(':') {
+            format!("[{}]:{}", self.ip, self.port)
+        } else {
+            format!("{}:{}", self.ip, self.port)
+        }
+    }
+
+    /// address of the TLS listener, if enabled
+    pub fn get_tls_ip(&self) -> Option<String> {
+        let port = self.tls.as_ref()?.port?;
+        if self.ip.contains(':') {
+            Some(format!("[{}]:{port}", self.ip))
+        } else {
+            Some(format!("{}:{port}", self.ip))
+        }
+    }
+
+    /// `listeners`, or listeners on `ip` at `port` and `tls.port` when it's empty
+    pub fn get_listeners(&self) -> Vec<Listener> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+        let listener = |address, tls| Listener {
+            address,
+            mode: None,
+            tls,
+            scope: ListenerScope::All,
+        };
+        let mut listeners = vec![listener(self.get_ip(), false)];
+        if let Some(address) = self.get_tls_ip() {
+            listeners.push(listener(address, true));
+        }
+        listeners
+    }
+
+    /// whether clients reach the instance over HTTPS, through a TLS terminating proxy or a
+    /// listener with `tls = true`
+    pub fn has_tls(&self) -> bool {
+        self.proxy_has_tls || self.get_listeners().iter().any(|l| l.tls)
+    }
+
+    /// directory in `files.path` served at `host`, the value of a `Host` header. Exact matches
+    /// win over wildcards.
+    pub fn vhost_path(&self, host: &str) -> Option<String> {
+        let host = match host.rsplit_once(':') {
+            Some((name, port)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => {
+                name
+            }
+            _ => host,
+        };
+        let host = host.trim_end_matches('.').to_lowercase();
+        if let Some(vhost) = self.vhosts.iter().find(|v| v.host.to_lowercase() == host) {
+            return Some(clean_path(&vhost.path));
+        }
+        self.vhosts.iter().find_map(|v| {
+            let suffix = v.host.strip_prefix('*')?.to_lowercase();
+            let subdomain = host.strip_suffix(&suffix)?;
+            if subdomain.is_empty() || subdomain.contains('.') {
+                return None;
+            }
+            Some(clean_path(&v.path.replace('*', subdomain)))
+        })
+    }
+
+    /// path dumbserve is served under, without trailing slash. Empty when served at `/`.
+    pub fn prefix(&self) -> &str {
+        self.url_prefix
+            .as_deref()
+            .unwrap_or_default()
+            .trim_end_matches('/')
+    }
+
+    /// URL path of route or file `path`, which starts with `/`, under [Server::prefix]
+    pub fn url_path(&self, path: &str) -> String {
+        format!("{}{path}", self.prefix())
+    }
+
+    /// public URL of the instance, used where absolute URLs are required
+    pub fn get_url(&self) -> String {
+        let scheme = if self.has_tls() { "https" } else { "http" };
+        format!("{scheme}://{}", self.domain)
+    }
+}
+
+#[derive(Deserialize, Serialize, Display, PartialEq, Eq, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DBType {
+    #[display(fmt = "postgres")]
+    Postgres,
+    #[display(fmt = "sqlite")]
+    Sqlite,
+}
+
+impl DBType {
+    fn from_url(url: &Url) -> Result<Self, ConfigError> {
+        match url.scheme() {
+            "sqlite" => Ok(Self::Sqlite),
+            "postgres" => Ok(Self::Postgres),
+            _ => Err(ConfigError::Message("Unknown database type".into())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Database {
+    pub url: String,
+    pub pool: u32,
+    pub database_type: DBType,
+}
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Creds {
@@ -71,10 +260,95 @@ pub struct Creds {
     pub password: String,
 }
 
+/// public key uploads must be signed with
+#[derive(Debug, Clone, Deserialize)]
+pub struct TrustedKey {
+    pub owner: String,
+    /// directory in owner's tree the key is trusted for, the whole tree when absent
+    pub path: Option<String>,
+    /// minisign public key, OpenSSH public key or ASCII armored OpenPGP public key
+    pub key: String,
+}
+
+/// server-side signing of releases
+#[derive(Debug, Clone, Deserialize)]
+pub struct Signing {
+    /// hex encoded ed25519 secret key, generated when the file doesn't exist
+    pub key_path: String,
+}
+
+/// limits applied when extracting uploaded archives
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExtractLimits {
+    /// total size of extracted files in bytes
+    pub max_size: u64,
+    /// number of extracted files
+    pub max_files: usize,
+    /// total size of extracted files divided by archive size
+    pub max_ratio: u64,
+}
+
+impl Default for ExtractLimits {
+    fn default() -> Self {
+        Self {
+            max_size: 1024 * 1024 * 1024,
+            max_files: 10000,
+            max_ratio: 100,
+        }
+    }
+}
+
+/// limits applied to directory downloads
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DownloadLimits {
+    /// total size of files in a downloaded directory in bytes
+    pub max_size: u64,
+    /// archives streamed at the same time, each one occupies a blocking thread
+    pub max_streams: usize,
+}
+
+impl Default for DownloadLimits {
+    fn default() -> Self {
+        Self {
+            max_size: 1024 * 1024 * 1024,
+            max_streams: 16,
+        }
+    }
+}
+
+/// caching policy of public files, see [crate::caching]
+#[derive(Debug, Clone, Deserialize)]
+pub struct CacheRule {
+    /// glob matched against paths relative to `files.path`, like `*/releases/**`
+    pub glob: Option<String>,
+    /// content type prefix, like `image/`
+    pub content_type: Option<String>,
+    /// `max-age` in seconds, [crate::CACHE_AGE] when absent
+    pub max_age: Option<u32>,
+    /// contents never change
+    #[serde(default)]
+    pub immutable: bool,
+    /// revalidate on every request
+    #[serde(default)]
+    pub no_cache: bool,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Files {
     pub path: String,
     pub creds: Vec<Creds>,
+    #[serde(default)]
+    pub trusted_keys: Vec<TrustedKey>,
+    #[serde(default)]
+    pub extract: ExtractLimits,
+    #[serde(default)]
+    pub download: DownloadLimits,
+    /// generate compressed sidecars of compressible uploads in the background
+    #[serde(default)]
+    pub precompress: bool,
+    #[serde(default)]
+    pub cache: Vec<CacheRule>,
 }
 
 impl Files {
@@ -84,18 +358,49 @@ impl Files {
             .any(|c| c.username == username && c.password == password)
     }
 
+    /// get location of `path` in `username`'s tree. Components that could escape the tree
+    /// (`..`, root, prefixes) are dropped.
     pub fn get_path(&self, username: &str, path: &str) -> PathBuf {
-        Path::new(&self.path).join(username).join(path)
+        Path::new(&self.path).join(username).join(clean_path(path))
+    }
+
+    /// uploads are received here and moved into place once complete. Usernames can't start
+    /// with a dot, see [crate::api::v1::admin::create_user], so this never collides with a
+    /// user's tree.
+    pub fn get_staging_path(&self) -> PathBuf {
+        Path::new(&self.path).join(".staging")
     }
+
+    /// generated sidecars of uploads, see [crate::compression]
+    pub fn get_precompressed_path(&self) -> PathBuf {
+        Path::new(&self.path).join(".precompressed")
+    }
+}
+
+/// `/` separated relative path with components that could escape a tree removed
+pub fn clean_path(path: &str) -> String {
+    Path::new(path)
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(c) => c.to_str(),
+            _ => None,
+        })
+        .collect::<Vec<&str>>()
+        .join("/")
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Settings {
     pub debug: bool,
-    //    pub database: Database,
+    #[serde(default)]
+    pub allow_registration: bool,
+    pub database: Database,
     pub server: Server,
     pub source_code: String,
     pub files: Files,
+    pub signing: Option<Signing>,
+    /// directory with templates overriding the built-in templates of public pages
+    pub templates: Option<String>,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -128,26 +433,22 @@ impl Settings {
             Err(e) => warn!("couldn't interpret PORT: {}", e),
         }
 
-        //        match env::var("DATABASE_URL") {
-        //            Ok(val) => {
-        //                let url = Url::parse(&val).expect("couldn't parse Database URL");
-        //                s.set("database.url", url.to_string()).unwrap();
-        //                let database_type = DBType::from_url(&url).unwrap();
-        //                s.set("database.database_type", database_type.to_string())
-        //                    .unwrap();
-        //            }
-        //            Err(e) => {
-        //                set_database_url(&mut s);
-        //            }
-        //        }
-
-        // setting default values
-        //    #[cfg(test)]
-        //    s.set("database.pool", 2.to_string())
-        //        .expect("Couldn't set database pool count");
+        if let Ok(val) = env::var("DATABASE_URL") {
+            s.set("database.url", val).unwrap();
+        }
+        set_database_defaults(&mut s)?;
+        set_database_type(&mut s)?;
 
         match s.try_into::<Self>() {
-            Ok(val) => {
+            Ok(mut val) => {
+                // `downloads/` and `/downloads` are both served at `/downloads`
+                val.server.url_prefix = val
+                    .server
+                    .url_prefix
+                    .as_deref()
+                    .map(|p| p.trim_matches('/'))
+                    .filter(|p| !p.is_empty())
+                    .map(|p| format!("/{p}"));
                 std::fs::create_dir_all(&val.files.path).unwrap();
                 Ok(val)
             },
@@ -165,6 +466,33 @@ fn check_url(s: &Config) {
     Url::parse(&url).expect("Please enter a URL for source_code in settings");
 }
 
+/// SQLite database next to the stored files in `files.path`, hidden from the public file
+/// service and the index like staging directories
+#[cfg(not(tarpaulin_include))]
+fn set_database_defaults(s: &mut Config) -> Result<(), ConfigError> {
+    let path = s.get::<String>("files.path")?;
+    s.set_default(
+        "database.url",
+        format!(
+            "sqlite://{}/.dumbserve.db?mode=rwc",
+            path.trim_end_matches('/')
+        ),
+    )?;
+    s.set_default("database.pool", 4)?;
+    Ok(())
+}
+
+#[cfg(not(tarpaulin_include))]
+fn set_database_type(s: &mut Config) -> Result<(), ConfigError> {
+    let url = s
+        .get::<String>("database.url")
+        .expect("Couldn't access database url");
+    let url = Url::parse(&url).expect("couldn't parse Database URL");
+    let database_type = DBType::from_url(&url)?;
+    s.set("database.database_type", database_type.to_string())?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -190,25 +518,68 @@ mod tests {
             .files
             .authenticate(&creds.username, &creds.password));
     }
-}
 
-//#[cfg(not(tarpaulin_include))]
-//fn set_database_url(s: &mut Config) {
-//    s.set(
-//        "database.url",
-//        format!(
-//            r"postgres://{}:{}@{}:{}/{}",
-//            s.get::<String>("database.username")
-//                .expect("Couldn't access database username"),
-//            s.get::<String>("database.password")
-//                .expect("Couldn't access database password"),
-//            s.get::<String>("database.hostname")
-//                .expect("Couldn't access database hostname"),
-//            s.get::<String>("database.port")
-//                .expect("Couldn't access database port"),
-//            s.get::<String>("database.name")
-//                .expect("Couldn't access database name")
-//        ),
-//    )
-//    .expect("Couldn't set database url");
-//}
+    #[test]
+    fn has_tls_works() {
+        let mut settings = Settings::new().unwrap();
+        settings.server.proxy_has_tls = false;
+        settings.server.tls = None;
+        settings.server.listeners.clear();
+        assert!(!settings.server.has_tls());
+        assert!(settings.server.get_url().starts_with("http://"));
+        settings.server.listeners.push(Listener {
+            address: "[::]:7443".into(),
+            mode: None,
+            tls: true,
+            scope: ListenerScope::Public,
+        });
+        assert!(settings.server.has_tls());
+        assert!(settings.server.get_url().starts_with("https://"));
+        settings.server.listeners.clear();
+        settings.server.proxy_has_tls = true;
+        assert!(settings.server.has_tls());
+    }
+
+    #[test]
+    fn get_path_stays_in_tree() {
+        let settings = Settings::new().unwrap();
+        let root = Path::new(&settings.files.path).join("foo");
+        assert_eq!(
+            settings.files.get_path("foo", "bar/baz"),
+            root.join("bar/baz")
+        );
+        assert_eq!(settings.files.get_path("foo", "../bar"), root.join("bar"));
+        assert_eq!(
+            settings.files.get_path("foo", "/etc/passwd"),
+            root.join("etc/passwd")
+        );
+        assert_eq!(settings.files.get_path("foo", ""), root);
+    }
+
+    #[test]
+    fn vhost_path_works() {
+        let mut settings = Settings::new().unwrap();
+        settings.server.vhosts = vec![
+            VirtualHost {
+                host: "docs.example.org".into(),
+                path: "foo/docs".into(),
+            },
+            VirtualHost {
+                host: "*.example.org".into(),
+                path: "*/site".into(),
+            },
+        ];
+        let server = &settings.server;
+        assert_eq!(
+            server.vhost_path("Docs.Example.org:7000"),
+            Some("foo/docs".into())
+        );
+        assert_eq!(
+            server.vhost_path("alice.example.org"),
+            Some("alice/site".into())
+        );
+        assert_eq!(server.vhost_path("a.b.example.org"), None);
+        assert_eq!(server.vhost_path("example.org"), None);
+        assert_eq!(server.vhost_path("[::1]"), None);
+    }
+}
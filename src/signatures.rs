@@ -0,0 +1,380 @@
+/*
+ * Copyright (C) 2022  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Verification of detached signatures. Uploads to trees and directories that have trusted
+//! keys configured must carry a signature for every artifact:
+//!
+//! - `<artifact>.minisig`: minisign signature
+//! - `<artifact>.sig`: SSH signature(`ssh-keygen -Y sign -n file`) or binary OpenPGP signature
+//! - `<artifact>.asc`: ASCII armored OpenPGP signature
+//!
+//! Signatures and checksums of artifacts that are part of the same upload don't have to be
+//! signed themselves.
+use std::fs::File;
+use std::io::{self, Read, Seek};
+use std::path::Path;
+use std::sync::Arc;
+
+use actix_web::web;
+use pgp::{Deserializable, SignedPublicKey, StandaloneSignature};
+use sha2::{Digest, Sha256, Sha512};
+use signature::Verifier;
+use ssh_key::{HashAlg, SshSig};
+
+use crate::errors::*;
+use crate::manifest::{CHECKSUM_EXTENSIONS, SIGNATURE_EXTENSIONS};
+use crate::settings::{clean_path, TrustedKey};
+
+/// namespace SSH signatures must be created in
+pub const SSH_NAMESPACE: &str = "file";
+
+const SSH_SIGNATURE_HEADER: &[u8] = b"-----BEGIN SSH SIGNATURE-----";
+
+pub enum PublicKey {
+    Minisign(minisign_verify::PublicKey),
+    Ssh(ssh_key::PublicKey),
+    OpenPgp(Box<SignedPublicKey>),
+}
+
+impl PublicKey {
+    /// parse minisign public key(base64 or contents of a `.pub` file), OpenSSH public key or
+    /// ASCII armored OpenPGP public key
+    pub fn parse(key: &str) -> Result<Self, String> {
+        let key = key.trim();
+        if key.starts_with("-----BEGIN PGP PUBLIC KEY BLOCK-----") {
+            SignedPublicKey::from_string(key)
+                .map(|(key, _)| Self::OpenPgp(Box::new(key)))
+                .map_err(|e| e.to_string())
+        } else if key.starts_with("ssh-") || key.starts_with("ecdsa-") || key.starts_with("sk-") {
+            ssh_key::PublicKey::from_openssh(key)
+                .map(Self::Ssh)
+                .map_err(|e| e.to_string())
+        } else if key.starts_with("untrusted comment:") {
+            minisign_verify::PublicKey::decode(key)
+                .map(Self::Minisign)
+                .map_err(|e| e.to_string())
+        } else {
+            minisign_verify::PublicKey::from_base64(key)
+                .map(Self::Minisign)
+                .map_err(|e| e.to_string())
+        }
+    }
+
+    /// check `signature`, read from file `signature_name`, over `data`, which is read from its
+    /// start. Keys only verify signatures of their own kind, other signatures are rejected.
+    pub fn verify<R: Read + Seek>(
+        &self,
+        signature_name: &str,
+        signature: &[u8],
+        data: &mut R,
+    ) -> bool {
+        let ext = signature_name.rsplit('.').next().unwrap_or_default();
+        let is_ssh = signature.starts_with(SSH_SIGNATURE_HEADER);
+        match self {
+            Self::Minisign(key) if ext == "minisig" => {
+                let signature = match std::str::from_utf8(signature)
+                    .ok()
+                    .and_then(|s| minisign_verify::Signature::decode(s).ok())
+                {
+                    Some(signature) => signature,
+                    None => return false,
+                };
+                match key.verify_stream(&signature) {
+                    Ok(mut verifier) => {
+                        read_chunks(data, |chunk| verifier.update(chunk)).is_ok()
+                            && verifier.finalize().is_ok()
+                    }
+                    Err(_) => false,
+                }
+            }
+            Self::Ssh(key) if ext == "sig" && is_ssh => SshSig::from_pem(signature)
+                .map(|s| verify_ssh(key, &s, data))
+                .unwrap_or(false),
+            Self::OpenPgp(key) if ext == "asc" || (ext == "sig" && !is_ssh) => {
+                let signature = if ext == "asc" {
+                    std::str::from_utf8(signature)
+                        .ok()
+                        .and_then(|s| StandaloneSignature::from_string(s).ok())
+                        .map(|(s, _)| s)
+                } else {
+                    StandaloneSignature::from_bytes(signature).ok()
+                };
+                let s = match signature {
+                    Some(s) => s.signature,
+                    None => return false,
+                };
+                if data.rewind().is_ok() && s.verify(key.as_ref(), &mut *data).is_ok() {
+                    return true;
+                }
+                key.public_subkeys
+                    .iter()
+                    .any(|k| data.rewind().is_ok() && s.verify(k, &mut *data).is_ok())
+            }
+            _ => false,
+        }
+    }
+}
+
+/// feed `data` from its start to `f` in chunks
+fn read_chunks<R: Read + Seek>(data: &mut R, mut f: impl FnMut(&[u8])) -> io::Result<()> {
+    data.rewind()?;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match data.read(&mut buf)? {
+            0 => return Ok(()),
+            n => f(&buf[..n]),
+        }
+    }
+}
+
+/// SSH signatures sign a digest of the data, which is computed while reading it instead of
+/// through [ssh_key::PublicKey::verify]
+fn verify_ssh<R: Read + Seek>(key: &ssh_key::PublicKey, signature: &SshSig, data: &mut R) -> bool {
+    if key.key_data() != signature.public_key() || signature.namespace() != SSH_NAMESPACE {
+        return false;
+    }
+    let digest = match signature.hash_alg() {
+        HashAlg::Sha256 => {
+            let mut hasher = Sha256::new();
+            if read_chunks(data, |chunk| hasher.update(chunk)).is_err() {
+                return false;
+            }
+            hasher.finalize().to_vec()
+        }
+        HashAlg::Sha512 => {
+            let mut hasher = Sha512::new();
+            if read_chunks(data, |chunk| hasher.update(chunk)).is_err() {
+                return false;
+            }
+            hasher.finalize().to_vec()
+        }
+        _ => return false,
+    };
+
+    // signed data blob of PROTOCOL.sshsig
+    let mut signed = b"SSHSIG".to_vec();
+    for field in [
+        SSH_NAMESPACE.as_bytes(),
+        signature.reserved(),
+        signature.hash_alg().as_str().as_bytes(),
+        &digest,
+    ] {
+        signed.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        signed.extend_from_slice(field);
+    }
+    signature
+        .public_key()
+        .verify(&signed, signature.signature())
+        .is_ok()
+}
+
+struct ScopedKey {
+    owner: String,
+    path: String,
+    key: Arc<PublicKey>,
+}
+
+/// trusted keys from settings
+#[derive(Default)]
+pub struct Keyring {
+    keys: Vec<ScopedKey>,
+}
+
+impl Keyring {
+    pub fn new(keys: &[TrustedKey]) -> Result<Self, String> {
+        let mut keyring = Self::default();
+        for k in keys.iter() {
+            let key = PublicKey::parse(&k.key)
+                .map_err(|e| format!("Invalid trusted key for {}: {e}", k.owner))?;
+            keyring.keys.push(ScopedKey {
+                owner: k.owner.clone(),
+                path: clean_path(k.path.as_deref().unwrap_or_default()),
+                key: Arc::new(key),
+            });
+        }
+        Ok(keyring)
+    }
+
+    /// keys trusted for `dir` in `owner`'s tree
+    pub fn keys_for(&self, owner: &str, dir: &str) -> Vec<Arc<PublicKey>> {
+        self.keys
+            .iter()
+            .filter(|k| k.owner == owner)
+            .filter(|k| {
+                k.path.is_empty() || dir == k.path || dir.starts_with(&format!("{}/", k.path))
+            })
+            .map(|k| k.key.clone())
+            .collect()
+    }
+
+    /// check that every artifact in `files`, stored in `root`, has a valid signature in
+    /// `files` from a key trusted for `dir` in `owner`'s tree. Signatures and checksums of
+    /// verified artifacts in `files` are accepted without signatures.
+    pub async fn verify(
+        &self,
+        owner: &str,
+        dir: &str,
+        root: &Path,
+        files: &[String],
+    ) -> ServiceResult<()> {
+        let keys = self.keys_for(owner, dir);
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        let root = root.to_owned();
+        let files = files.to_vec();
+        let unsigned = web::block(move || find_unsigned(&keys, &root, &files))
+            .await
+            .map_err(|_| ServiceError::InternalServerError)??;
+        match unsigned {
+            Some(file) => {
+                log::info!("Rejecting upload of {owner}/{dir}/{file}: no valid signature");
+                Err(ServiceError::UnsignedArtifact)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// signatures are small, larger files aren't read
+const MAX_SIGNATURE_SIZE: u64 = 64 * 1024;
+
+/// first file of `files`, stored in `root`, without a valid signature from one of `keys`
+fn find_unsigned(
+    keys: &[Arc<PublicKey>],
+    root: &Path,
+    files: &[String],
+) -> io::Result<Option<String>> {
+    // artifacts are checked before their signatures and checksums, which are accepted when
+    // the artifact is
+    let mut sorted: Vec<&String> = files.iter().collect();
+    sorted.sort_by_key(|f| f.len());
+    let mut accepted: Vec<&str> = Vec::with_capacity(files.len());
+    for file in sorted {
+        let base = SIGNATURE_EXTENSIONS
+            .iter()
+            .chain(CHECKSUM_EXTENSIONS.iter())
+            .find_map(|ext| file.strip_suffix(ext));
+        if matches!(base, Some(base) if accepted.contains(&base)) {
+            accepted.push(file);
+            continue;
+        }
+
+        let mut data = File::open(root.join(file))?;
+        let mut verified = false;
+        for ext in SIGNATURE_EXTENSIONS.iter() {
+            let signature_name = format!("{file}{ext}");
+            let signature_path = root.join(&signature_name);
+            if !files.contains(&signature_name)
+                || signature_path.metadata()?.len() > MAX_SIGNATURE_SIZE
+            {
+                continue;
+            }
+            let signature = std::fs::read(signature_path)?;
+            if keys
+                .iter()
+                .any(|k| k.verify(&signature_name, &signature, &mut data))
+            {
+                verified = true;
+                break;
+            }
+        }
+        if !verified {
+            return Ok(Some(file.clone()));
+        }
+        accepted.push(file);
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    pub const MINISIGN_KEY: &str = "RWQBI0VniavN7wJGriAGvKncEfFwyd4fqoECmQKpyXsXXImBsFrZ+ZsG";
+    pub const MINISIGN_SIGNATURE: &str = "untrusted comment: signature from minisign secret key
+RUQBI0VniavN7zSOUIsyBgU6SyK2lwDQU1naIphcJ4LeCs9rcAWKsbubohesdGwyUzRJ9Txvvew0SIwPmd3ssveBTYJ1husVxgY=
+trusted comment: timestamp:0\tfile:data
+O3NPMU6vWMX2U5WBNOcMoZmpgG0Zuv99AzbX0w4xBm7rmNHlRl5j3o6ZubloTe38mccxSZb+p+IovNLGDF7uAw==
+";
+    const SSH_KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIJIGkmoaawK88XI7BOjHJOCIKC1hQpL8C/DhrhJE8ASs test";
+    const SSH_SIGNATURE: &str = "-----BEGIN SSH SIGNATURE-----
+U1NIU0lHAAAAAQAAADMAAAALc3NoLWVkMjU1MTkAAAAgkgaSahprArzxcjsE6Mck4IgoLW
+FCkvwL8OGuEkTwBKwAAAAEZmlsZQAAAAAAAAAGc2hhNTEyAAAAUwAAAAtzc2gtZWQyNTUx
+OQAAAEBDsdxN3BHtUiN0+tZqfym3kWlIzowi38acv/9SfpqlGx/L+oRwfg8fWX1+2G2J2m
+zpZdZ5t96h0c15ZRg4KNUK
+-----END SSH SIGNATURE-----
+";
+    const PGP_KEY: &str = "-----BEGIN PGP PUBLIC KEY BLOCK-----
+
+mDMEatXKAhYJKwYBBAHaRw8BAQdAfuk5whmNV4XX6DJfz7YTsOZ7o1FLRjvz6FOW
+nFO6re+0IWR1bWJzZXJ2ZSB0ZXN0IDx0ZXN0QGV4YW1wbGUub3JnPoiQBBMWCAA4
+FiEEQKSvHK5ZUTvuDj3V6TIp297vhVMFAmrVygICGwMFCwkIBwIGFQoJCAsCBBYC
+AwECHgECF4AACgkQ6TIp297vhVPjJgD9F+Ga6j6nseMfoM/siVspdWkwOYqwF4ZW
+32Mf/eerT30BAJSN4ZMDRWuK7CFluwz8XSUz5KcJc2Xf3C5ArZNWU/AP
+=GVJ5
+-----END PGP PUBLIC KEY BLOCK-----
+";
+    const PGP_SIGNATURE: &str = "-----BEGIN PGP SIGNATURE-----
+
+iHUEABYIAB0WIQRApK8crllRO+4OPdXpMinb3u+FUwUCatXKAgAKCRDpMinb3u+F
+U3ycAQDHdgfNauY2dji5kLht8GO7/CdnDNkREMQN1aYoMysoHgD/XXyQTKjLXsT3
+NVtBq1rtSnHcrsNGXyrpZy/MTAWloAk=
+=Y4vm
+-----END PGP SIGNATURE-----
+";
+
+    #[test]
+    fn signatures_work() {
+        let minisign = PublicKey::parse(MINISIGN_KEY).unwrap();
+        let ssh = PublicKey::parse(SSH_KEY).unwrap();
+        let pgp = PublicKey::parse(PGP_KEY).unwrap();
+
+        let signatures = [
+            (&minisign, "data.minisig", MINISIGN_SIGNATURE),
+            (&ssh, "data.sig", SSH_SIGNATURE),
+            (&pgp, "data.asc", PGP_SIGNATURE),
+        ];
+        let mut foo = Cursor::new(b"foo");
+        let mut bar = Cursor::new(b"bar");
+        for (key, name, signature) in signatures.iter() {
+            assert!(key.verify(name, signature.as_bytes(), &mut foo), "{name}");
+            assert!(!key.verify(name, signature.as_bytes(), &mut bar), "{name}");
+        }
+        assert!(!minisign.verify("data.sig", SSH_SIGNATURE.as_bytes(), &mut foo));
+        assert!(!pgp.verify("data.minisig", MINISIGN_SIGNATURE.as_bytes(), &mut foo));
+        assert!(PublicKey::parse("not a key").is_err());
+    }
+
+    #[test]
+    fn keyring_works() {
+        let keys = [TrustedKey {
+            owner: "owner".into(),
+            path: Some("releases".into()),
+            key: MINISIGN_KEY.into(),
+        }];
+        let keyring = Keyring::new(&keys).unwrap();
+        assert_eq!(keyring.keys_for("owner", "releases").len(), 1);
+        assert_eq!(keyring.keys_for("owner", "releases/1.0.0").len(), 1);
+        assert!(keyring.keys_for("owner", "releases-old").is_empty());
+        assert!(keyring.keys_for("owner", "").is_empty());
+        assert!(keyring.keys_for("other", "releases").is_empty());
+    }
+}
@@ -0,0 +1,277 @@
+/*
+ * Copyright (C) 2022  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Server-side signing of releases. Signatures use the minisign format, so they can be checked
+//! with `minisign -V -P <key> -m <file>` using the key served at
+//! `/api/v1/meta/signing-key`.
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use blake2::Blake2b512;
+use ed25519_dalek::{Signer as _, SigningKey};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+use crate::ctx::Ctx;
+use crate::db::now_unix;
+use crate::errors::*;
+use crate::index::{digest, index_file};
+use crate::manifest::{CHECKSUM_EXTENSIONS, SIGNATURE_EXTENSIONS};
+
+/// checksum manifest written to signed release directories, `sha256sum -c` compatible
+pub const CHECKSUMS: &str = "SHA256SUMS";
+
+pub struct Signer {
+    key: SigningKey,
+    key_id: [u8; 8],
+}
+
+impl Signer {
+    /// load secret key from `path`, a new key is generated and saved there when it doesn't
+    /// exist. Keys in `files_path` would be served publicly and are refused.
+    pub fn load_or_generate(path: &Path, files_path: &Path) -> io::Result<Self> {
+        if resolve(path)?.starts_with(files_path.canonicalize()?) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "signing key must be kept outside of files.path",
+            ));
+        }
+
+        let seed = if path.exists() {
+            let seed = hex::decode(std::fs::read_to_string(path)?.trim())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            <[u8; 32]>::try_from(seed.as_slice()).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "signing key must be 32 bytes")
+            })?
+        } else {
+            let mut seed = [0; 32];
+            getrandom::getrandom(&mut seed).map_err(io::Error::other)?;
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            // readable by dumbserve only
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            options
+                .open(path)?
+                .write_all(hex::encode(seed).as_bytes())?;
+            log::info!("Generated signing key at {:?}", path);
+            seed
+        };
+        Ok(Self::new(&seed))
+    }
+
+    pub fn new(seed: &[u8; 32]) -> Self {
+        let key = SigningKey::from_bytes(seed);
+        let hash = Sha256::digest(key.verifying_key().as_bytes());
+        let mut key_id = [0; 8];
+        key_id.copy_from_slice(&hash[..8]);
+        Self { key, key_id }
+    }
+
+    /// public key in minisign's `.pub` file format
+    pub fn public_key(&self) -> String {
+        let mut key = b"Ed".to_vec();
+        key.extend_from_slice(&self.key_id);
+        key.extend_from_slice(self.key.verifying_key().as_bytes());
+        format!(
+            "untrusted comment: dumbserve public key {}\n{}\n",
+            hex::encode_upper(self.key_id),
+            base64::encode(key)
+        )
+    }
+
+    /// prehashed minisign signature of `data`, which is called `name`
+    pub fn sign(&self, name: &str, data: &[u8]) -> String {
+        self.sign_prehashed(name, &Blake2b512::digest(data))
+    }
+
+    /// minisign signature of the data called `name` from its BLAKE2b-512 digest `hash`
+    pub fn sign_prehashed(&self, name: &str, hash: &[u8]) -> String {
+        let signature = self.key.sign(hash).to_bytes();
+        let trusted_comment = format!("timestamp:{}\tfile:{name}\thashed", now_unix());
+        let mut global = signature.to_vec();
+        global.extend_from_slice(trusted_comment.as_bytes());
+        let global_signature = self.key.sign(&global).to_bytes();
+
+        let mut sig = b"ED".to_vec();
+        sig.extend_from_slice(&self.key_id);
+        sig.extend_from_slice(&signature);
+        format!(
+            "untrusted comment: signature from dumbserve\n{}\ntrusted comment: {trusted_comment}\n{}\n",
+            base64::encode(sig),
+            base64::encode(global_signature)
+        )
+    }
+}
+
+/// `path` with symlinks in its longest existing ancestor resolved
+fn resolve(path: &Path) -> io::Result<PathBuf> {
+    let mut existing = path;
+    let mut missing = Vec::new();
+    while !existing.exists() {
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                missing.push(name);
+                existing = parent;
+            }
+            _ => break,
+        }
+    }
+    let mut resolved = if existing.as_os_str().is_empty() {
+        std::env::current_dir()?
+    } else {
+        existing.canonicalize()?
+    };
+    resolved.extend(missing.iter().rev());
+    Ok(resolved)
+}
+
+/// hex encoded SHA-256 and BLAKE2b-512 digests of file `path`, computed in one pass
+async fn digests(path: &Path) -> io::Result<(String, Vec<u8>)> {
+    let mut f = tokio::fs::File::open(path).await?;
+    let mut sha256 = Sha256::new();
+    let mut blake2b = Blake2b512::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = f.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        sha256.update(&buf[..n]);
+        blake2b.update(&buf[..n]);
+    }
+    Ok((hex::encode(sha256.finalize()), blake2b.finalize().to_vec()))
+}
+
+/// sign every artifact of release directory `dir` in `owner`'s tree and write a signed checksum
+/// manifest. Signatures uploaded as `<artifact>.minisig` are replaced by the server's. Does
+/// nothing when server-side signing is disabled.
+pub async fn sign_release(ctx: &Ctx, owner: &str, dir: &str) -> ServiceResult<()> {
+    let signer = match &ctx.signer {
+        Some(signer) => signer,
+        None => return Ok(()),
+    };
+
+    let names: Vec<String> = ctx
+        .db
+        .list_dir(owner, dir)
+        .await?
+        .into_iter()
+        .filter_map(|f| f.path.strip_prefix(&format!("{dir}/")).map(String::from))
+        .collect();
+    let path = ctx.settings.files.get_path(owner, dir);
+    for name in sign_dir(signer, &path, &names).await? {
+        let file = format!("{dir}/{name}");
+        let path = ctx.settings.files.get_path(owner, &file);
+        index_file(ctx, owner, &file, owner, digest(&path).await?).await?;
+    }
+    Ok(())
+}
+
+/// sign artifacts `names` of directory `path` and write a signed checksum manifest next to
+/// them. Only files directly in `path` are signed. Returns names of the written files.
+pub async fn sign_dir(
+    signer: &Signer,
+    path: &Path,
+    names: &[String],
+) -> ServiceResult<Vec<String>> {
+    let mut written = Vec::new();
+    let mut checksums = String::new();
+    for name in names.iter() {
+        let exempt = name.contains('/')
+            || name == CHECKSUMS
+            || SIGNATURE_EXTENSIONS
+                .iter()
+                .chain(CHECKSUM_EXTENSIONS.iter())
+                .any(|ext| name.ends_with(ext));
+        if exempt {
+            continue;
+        }
+
+        let (sha256, blake2b) = digests(&path.join(name)).await?;
+        checksums.push_str(&format!("{sha256}  {name}\n"));
+        let signature = format!("{name}.minisig");
+        tokio::fs::write(path.join(&signature), signer.sign_prehashed(name, &blake2b)).await?;
+        written.push(signature);
+    }
+
+    let signature = signer.sign(CHECKSUMS, checksums.as_bytes());
+    tokio::fs::write(path.join(CHECKSUMS), checksums).await?;
+    written.push(CHECKSUMS.into());
+    let name = format!("{CHECKSUMS}.minisig");
+    tokio::fs::write(path.join(&name), signature).await?;
+    written.push(name);
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::signatures::PublicKey;
+
+    #[test]
+    fn signing_works() {
+        let signer = Signer::new(&[7; 32]);
+        let key = PublicKey::parse(&signer.public_key()).unwrap();
+        let signature = signer.sign("data", b"foo");
+        assert!(key.verify(
+            "data.minisig",
+            signature.as_bytes(),
+            &mut Cursor::new(b"foo")
+        ));
+        assert!(!key.verify(
+            "data.minisig",
+            signature.as_bytes(),
+            &mut Cursor::new(b"bar")
+        ));
+
+        let other = Signer::new(&[8; 32]);
+        let signature = other.sign("data", b"foo");
+        assert!(!key.verify(
+            "data.minisig",
+            signature.as_bytes(),
+            &mut Cursor::new(b"foo")
+        ));
+    }
+
+    #[test]
+    fn key_file_works() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = std::env::temp_dir().join("dumbserve-key_file_works");
+        let _ = std::fs::remove_dir_all(&root);
+        let files = root.join("files");
+        std::fs::create_dir_all(&files).unwrap();
+
+        let public = files.join("keys/signing.key");
+        assert!(Signer::load_or_generate(&public, &files).is_err());
+        assert!(!public.exists());
+
+        let path = root.join("signing.key");
+        let signer = Signer::load_or_generate(&path, &files).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let loaded = Signer::load_or_generate(&path, &files).unwrap();
+        assert_eq!(signer.public_key(), loaded.public_key());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
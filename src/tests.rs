@@ -0,0 +1,42 @@
+/*
+ * Copyright (C) 2022  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Test utilities
+
+/// HTTP Basic authorization header value
+pub fn basic_auth(username: &str, password: &str) -> String {
+    format!(
+        "Basic {}",
+        base64::encode(format!("{}:{}", username, password))
+    )
+}
+
+/// multipart/form-data body with `files` as `(filename, contents)` pairs. Returns value of
+/// content type header and body.
+pub fn multipart(files: &[(&str, &str)]) -> (String, String) {
+    const BOUNDARY: &str = "dumbserveboundary";
+    let mut body = String::new();
+    for (filename, contents) in files {
+        body.push_str(&format!(
+            "--{BOUNDARY}\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"{filename}\"\r\n\
+            Content-Type: application/octet-stream\r\n\r\n\
+            {contents}\r\n"
+        ));
+    }
+    body.push_str(&format!("--{BOUNDARY}--\r\n"));
+    (format!("multipart/form-data; boundary={BOUNDARY}"), body)
+}